@@ -1,17 +1,30 @@
 extern crate daemonize_me;
 
 use std::fs::File;
+use std::thread::sleep;
+use std::time::Duration;
 
-pub use daemonize_me::{Daemon, User, Group};
+use nix::sys::stat::Mode;
+
+pub use daemonize_me::Daemon;
 
 fn main() {
     let stdout = File::create("info.log").unwrap();
     let stderr = File::create("err.log").unwrap();
     let daemon = Daemon::new()
         .pid_file("example.pid", Some(false))
-        .user(User::try_from("daemon").unwrap())
-        .group(Group::try_from("daemon").unwrap())
-        .umask(0o000)
+        .pid_file_relative_to_launch_dir(true)
+        .user_by_name("daemon")
+        .and_then(|daemon| daemon.group_by_name("daemon"));
+    let daemon = match daemon {
+        Ok(daemon) => daemon,
+        Err(e) => {
+            eprintln!("Error, {}", e);
+            return;
+        }
+    };
+    let daemon = daemon
+        .umask_mode(Mode::empty())
         .work_dir(".")
         .stdout(stdout)
         .stderr(stderr)
@@ -24,5 +37,6 @@ fn main() {
 
     loop {
         // You wil have to kill this process yourself
+        sleep(Duration::from_secs(1));
     }
 }
@@ -4,6 +4,8 @@ use std::any::Any;
 use std::fs::File;
 use std::process::exit;
 
+use nix::sys::stat::Mode;
+
 pub use daemonize_me::Daemon;
 
 
@@ -18,12 +20,10 @@ fn post_fork_child(ppid: i32, cpid: i32) {
     println!("Parent pid: {}, Child pid {}", ppid, cpid);
     println!("This hook is called in the child");
     // Child hook must return
-    return
 }
 
 fn after_init(_: Option<&dyn Any>) {
     println!("Initialized the daemon!");
-    return
 }
 
 fn main() {
@@ -31,7 +31,8 @@ fn main() {
     let stderr = File::create("err.log").unwrap();
     let daemon = Daemon::new()
         .pid_file("example.pid", Some(false))
-        .umask(0o000)
+        .pid_file_relative_to_launch_dir(true)
+        .umask_mode(Mode::empty())
         .work_dir(".")
         .stdout(stdout)
         .stderr(stderr)
@@ -0,0 +1,61 @@
+use std::fmt::{self, Display};
+
+/// A Linux capability the daemon keeps once privileges are dropped, see [`crate::Daemon::keep_capabilities`].
+/// The discriminant is the kernel capability number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Capability {
+    Chown = 0,
+    DacOverride = 1,
+    DacReadSearch = 2,
+    Fowner = 3,
+    Kill = 5,
+    Setgid = 6,
+    Setuid = 7,
+    /// Bind ports below 1024
+    NetBindService = 10,
+    NetBroadcast = 11,
+    NetAdmin = 12,
+    NetRaw = 13,
+    IpcLock = 14,
+    SysChroot = 18,
+    SysPtrace = 19,
+    SysAdmin = 21,
+    SysNice = 23,
+    SysResource = 24,
+    SysTime = 25,
+    AuditWrite = 29,
+}
+
+impl Capability {
+    /// The name used by `capabilities(7)`, eg. `CAP_NET_BIND_SERVICE`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Capability::Chown => "CAP_CHOWN",
+            Capability::DacOverride => "CAP_DAC_OVERRIDE",
+            Capability::DacReadSearch => "CAP_DAC_READ_SEARCH",
+            Capability::Fowner => "CAP_FOWNER",
+            Capability::Kill => "CAP_KILL",
+            Capability::Setgid => "CAP_SETGID",
+            Capability::Setuid => "CAP_SETUID",
+            Capability::NetBindService => "CAP_NET_BIND_SERVICE",
+            Capability::NetBroadcast => "CAP_NET_BROADCAST",
+            Capability::NetAdmin => "CAP_NET_ADMIN",
+            Capability::NetRaw => "CAP_NET_RAW",
+            Capability::IpcLock => "CAP_IPC_LOCK",
+            Capability::SysChroot => "CAP_SYS_CHROOT",
+            Capability::SysPtrace => "CAP_SYS_PTRACE",
+            Capability::SysAdmin => "CAP_SYS_ADMIN",
+            Capability::SysNice => "CAP_SYS_NICE",
+            Capability::SysResource => "CAP_SYS_RESOURCE",
+            Capability::SysTime => "CAP_SYS_TIME",
+            Capability::AuditWrite => "CAP_AUDIT_WRITE",
+        }
+    }
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
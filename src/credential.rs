@@ -0,0 +1,70 @@
+use std::ffi::OsString;
+use std::fmt::{self, Debug};
+use std::process::{Command, Stdio};
+
+use crate::{DaemonError, Result};
+
+/// A secret obtained with [`crate::Daemon::credential_command`], handed to
+/// [`crate::Daemon::setup_post_init_credential_hook`] in the daemon.
+/// The bytes are wiped from memory when it is dropped
+#[derive(Clone)]
+pub struct Credential {
+    secret: Vec<u8>,
+}
+
+impl Credential {
+    /// The output of the command without its trailing newline
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.secret
+    }
+}
+
+impl Debug for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Credential(<redacted>)")
+    }
+}
+
+impl Drop for Credential {
+    fn drop(&mut self) {
+        for byte in self.secret.iter_mut() {
+            // volatile so the wipe isn't optimized away as a dead store
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+/// Runs `program` with the terminal still attached, stdin and stderr are inherited so it can prompt,
+/// and captures its stdout as the secret
+pub(crate) fn run_credential_command(program: &OsString, args: &[OsString]) -> Result<Credential> {
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .output();
+    let mut secret = match output {
+        Ok(output) if output.status.success() => output.stdout,
+        _ => return Err(DaemonError::CredentialCommand),
+    };
+    if secret.last() == Some(&b'\n') {
+        secret.pop();
+    }
+    Ok(Credential { secret })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Asserts that the output is captured without the newline and that failures are reported
+    fn test_run_credential_command() {
+        let args = [OsString::from("s3cret\n")];
+        let credential = run_credential_command(&OsString::from("printf"), &args).unwrap();
+        assert_eq!(credential.as_bytes(), b"s3cret");
+        assert_eq!(format!("{:?}", credential), "Credential(<redacted>)");
+        let failed = run_credential_command(&OsString::from("false"), &[]);
+        assert!(matches!(failed, Err(DaemonError::CredentialCommand)));
+    }
+}
@@ -1,27 +1,52 @@
 use std::any::Any;
 use std::convert::TryFrom;
 use std::ffi::{CString, OsStr, OsString};
-use std::fs::File;
-use std::io::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
 use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use nix::sys::stat::{Mode, umask};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::resource::Resource;
+use nix::sys::signal::Signal;
+use nix::sys::socket::{AddressFamily, bind, listen, SockAddr, SockFlag, SockType, socket, UnixAddr};
+use nix::sys::stat::{Mode, stat, umask};
 #[cfg(not(target_os = "macos"))]
 use nix::unistd::{
-    chdir, chown, fork, ForkResult, getpid, Gid, initgroups, Pid, setgid, setsid,
-    setuid, Uid,
+    chdir, chroot, close, fchdir, fork, ForkResult, getegid, geteuid, getgrouplist, getgroups, getpid, getppid, getsid,
+    getuid, Gid, initgroups, Pid, read, setegid, seteuid, setgid, setgroups, setsid, setuid, Uid,
 };
 #[cfg(target_os = "macos")]
 use nix::unistd::{
-    chdir, chown, close, dup2, fork, ForkResult, getpid, Gid, Pid, setgid, setsid, setuid, Uid,
+    chdir, chroot, close, dup2, fchdir, fork, ForkResult, getegid, geteuid, getpid, getppid, getsid, getuid, Gid, Pid,
+    read, setegid, seteuid, setgid, setsid, setuid, Uid,
 };
 
 use crate::{DaemonError, Result};
+use crate::capability::Capability;
+use crate::credential::{Credential, run_credential_command};
 use crate::DaemonError::{InvalidGroup, InvalidUser};
-use crate::ffi::{PasswdRecord, set_proc_name};
+use crate::ffi::{change_apparmor_profile, cloexec_pipe, close_fds_from, mount_private, PasswdRecord, proc_name_cstring, raw_log, restrict_capabilities, set_argv0_basename, set_child_subreaper, set_keep_capabilities, set_login_class, set_no_new_privs, set_proc_name, set_stdio_buffering, thread_count};
 use crate::group::Group;
-use crate::stdio::{redirect_stdio, Stdio};
+#[cfg(feature = "json")]
+use crate::pid_file::write_file_atomically;
+use crate::pid_file::{lock_file, lock_file_path, read_current_pid, start_heartbeat, write_pid_file};
+use crate::plan::{DaemonStep, Limit};
+use crate::privileges::{PrivilegeMode, set_resgid, set_resuid, verify_privilege_drop};
+use crate::profile::SecurityProfile;
+use crate::ring_buffer::{dump_on_signal, flush_on_signal};
+use crate::resolver::{RecordResolver, SystemResolver};
+use crate::rlimit::{apply_rlimits, Rlimit, RlimitPhase};
+use crate::signal::SignalPipe;
+use crate::status::{DaemonStatus, read_status};
+use crate::takeover::{take_over, Takeover};
+use crate::stdio::{BufferMode, install_stderr_panic_hook, LogHandle, redirect_stdio, Stdio};
 use crate::user::User;
 
 /// Basic daemonization consists of:
@@ -31,56 +56,553 @@ use crate::user::User;
 /// Options:
 /// * user [optional], if set will drop privileges to the specified user **NOTE**: This library is strict and makes no assumptions if you provide a user you must provide a group
 /// * group [optional(**see note on user**)], if set will drop privileges to specified group
-/// * umask [optional], umask for the process defaults to 0o027
+/// * umask [optional], umask for the process defaults to 0o027, `umask_group_writable` presets 0o002 for shared setgid directories
 /// * pid_file [optional], if set a pid file will be created default is that no file is created *
+/// * lock_pid_file [optional], if set the pid file itself is locked for the lifetime of the daemon to prevent two instances
+/// * use_lockfile [optional], if set a `{pid_file}.lock` file is locked for the lifetime of the daemon to prevent two instances
+/// * hand_over_pid_file_lock [optional], if set the file holding the lock is returned by `start` instead of being kept open for good
+/// * admin_socket [optional], unix socket bound before forking with its mode and owner set, the listener is returned by `start`
+/// * status_file [optional][json feature], if set a JSON status file is written once the daemon is initialized
+/// * file_creation_umask [optional], umask used only while the pid and ready files are created, defaults to the process umask
+/// * pid_file_append_history [optional], if set each start appends a `{timestamp} {pid}` line to the pid file instead of truncating it
+/// * pid_file_relative_to_launch_dir [optional], if set a relative pid file is resolved against the directory `start` was called from
+/// * pid_file_zero_pad [optional], if set the pid is zero padded to this many digits for fixed width readers
+/// * pid_file_newline [optional], if set the pid is followed by a newline, off by default
+/// * pid_file_after_init [optional], if set the pid file is only written once the after_init hook returned
+/// * pid_file_timing [optional], whether the pid file is first written by the daemon or reserved before the fork, see [`PidFileTiming`]
+/// * pid_file_heartbeat [optional], if set a thread touches the mtime of the pid file at this interval as a liveness signal
+/// * login_class [optional][FreeBSD], if set the resource limits, priority and umask of this login class are applied
+/// * require_valid_account [optional], if set dropping privileges to a locked or nologin account fails
+/// * require_launch_user [optional], if set `start` refuses to run unless launched by this user
+/// * rlimits [optional], resource limits applied either before or after dropping privileges
+/// * deferred_user [optional], a user name resolved in the child after the post fork child hook instead of up front
+/// * privilege_mode [optional], whether privileges are dropped for good or can be regained, defaults to permanently
+/// * resuid [optional], explicit real, effective and saved uids set with `setresuid` instead of following privilege_mode
+/// * resgid [optional], explicit real, effective and saved gids set with `setresgid` instead of following privilege_mode
+/// * supplementary_groups [optional], extra supplementary groups added to those of the user when dropping privileges
+/// * keep_capabilities [optional][Linux], capabilities the daemon keeps when dropping privileges, eg. to bind low ports
+/// * apparmor_profile [optional][Linux], if set the daemon changes to this AppArmor profile once privileges are dropped
+/// * no_new_privs [optional][Linux], if set the daemon and what it executes can never gain privileges again
+/// * verify_groups [optional], if set the supplementary groups are checked to be in place after dropping privileges
+/// * ready_file [optional], if set this file is created once the daemon is fully initialized
 /// * stdio [optional][**recommended**], this determines where standard output will be piped to since daemons have no console it's highly recommended to set this
 /// * stderr [optional][**recommended**], same as above but for standard error
-/// * chdir [optional], default is "/"
+/// * no_stdio_redirect [optional], if set the standard streams are left exactly as inherited and the stdio options are ignored
+/// * keep_fds [optional], file descriptors handed to the daemon open and without close on exec, eg. activated sockets
+/// * close_all_fds [optional], if set every inherited fd above stderr is closed in the daemon except the keep_fds
+/// * clear_env [optional], if set the daemon starts with an empty environment holding only the env variables
+/// * env [optional], environment variables set in the daemon after the fork
+/// * stdio_fallback_to_null [optional], if set a stream that can't be redirected goes to /dev/null instead of failing `start`
+/// * verify_devnull [optional], if set /dev/null is checked to be the null character device before streams are pointed at it
+/// * stdio_buffering [optional], buffering mode of the libc stdout and stderr streams after the redirection
+/// * install_panic_hook [optional], if set panics in the daemon are written with a backtrace straight to the redirected stderr
+/// * ring_buffer_dump_signal [optional], signal that dumps the stdio ring buffers, see [`Stdio::ring_buffer`]
+/// * flush_stdio_on_signal [optional], shutdown signal on which the redirected streams are flushed before dying, see [`crate::flush_stdio`]
+/// * chdir [optional], default is "/", when chroot is set it is interpreted inside the new root
+/// * chroot [optional], if set the daemon changes its root directory to this one before dropping privileges
+/// * tmpfs_mounts [optional][Linux], mounts made in a private mount namespace of the daemon right before the chroot
 /// * name [optional], set the daemon process name eg what shows in `ps` default is to not set a process name
+/// * name_truncate [optional][Linux], if set a name longer than 15 bytes is cut instead of failing `start`
+/// * argv0_basename [optional][Linux], if set argv[0] is cut down to its basename so `ps` shows the short command
+/// * child_subreaper [optional][Linux], if set orphaned descendants are reparented to the daemon instead of init
+/// * skip_if_already_daemonized [optional], if set `start` does nothing when the process already is a daemon
+/// * fork_strategy [optional], how the daemon process is created, defaults to a plain fork
+/// * debug_friendly [optional], if set and a tracer is attached the process stays in the foreground without forking
+/// * auto_detect_supervisor [optional], if set and a service supervisor started the process it stays in the foreground
+/// * parent_behavior [optional], what the parent does once the daemon is forked, defaults to exiting with 0
+/// * wait_for_parent_exit [optional], if set the child waits for the parent to exit before writing the pid file
+/// * credential_command [optional], if set this command is run while the terminal is attached and its output handed to the post init credential hook
+/// * double_fork [optional], if set the daemon forks again after `setsid` so it is never a session leader
 /// * before_fork_hook [optional], called before the fork with the current pid as argument
-/// * after_fork_parent_hook [optional], called after the fork with the parent pid as argument, can be used to continue some work on the parent after the fork (do not return)
+/// * before_fork_payload_hook [optional], called before the fork, its return value is shared with the child through copy on write and handed to the payload hooks
+/// * after_fork_parent_hook [optional], called after the fork with the parent pid as argument, can be used to continue some work on the parent after the fork, the parent exits once it returns
+/// * parent_cleanup [optional], called in the parent right before it exits when no after_fork_parent_hook is set
 /// * after_fork_child_hook [optional], called after the fork with the parent and child pid as arguments
+/// * after_fork_child_payload_hook [optional], like after_fork_child_hook but also given the pre fork payload
+/// * on_fork_hook [optional], called after every fork level in the process that carries on with the level and its pid
+/// * after_drop_hook [optional], called in the daemon right after privileges are dropped with the effective uid and gid
+/// * after_init_payload_hook [optional], like after_init_hook but given the pre fork payload instead of the hook data
+/// * after_init_credential_hook [optional], called after the post init hooks with the output of the credential command
 ///
 /// * See the setter function documentation for more details
 ///
+/// Hooks can be plain functions or closures capturing state from the surrounding scope, eg. a logger handle
+///
+/// A configuration can be cloned to derive variants from it, say one pid file per instance,
+/// clones share the files passed for the standard streams and the hooks along with what they captured.
+///
 /// **Beware there is no escalation back if dropping privileges**
+#[derive(Clone)]
 pub struct Daemon<'a> {
     pub(crate) chdir: PathBuf,
+    pub(crate) chroot: Option<PathBuf>,
+    pub(crate) tmpfs_mounts: Vec<(String, PathBuf, String, libc::c_ulong)>,
     pub(crate) pid_file: Option<PathBuf>,
     pub(crate) chown_pid_file: bool,
+    pub(crate) pid_file_append_history: bool,
+    pub(crate) pid_file_relative_to_launch_dir: bool,
+    pub(crate) pid_file_zero_pad: Option<usize>,
+    pub(crate) pid_file_newline: bool,
+    pub(crate) pid_file_after_init: bool,
+    pub(crate) pid_file_timing: PidFileTiming,
+    pub(crate) pid_file_heartbeat: Option<Duration>,
+    pub(crate) lock_pid_file: bool,
+    pub(crate) use_lockfile: bool,
+    pub(crate) hand_over_pid_file_lock: bool,
+    pub(crate) admin_socket: Option<AdminSocket>,
+    pub(crate) ready_file: Option<PathBuf>,
+    #[cfg(feature = "json")]
+    pub(crate) status_file: Option<PathBuf>,
     pub(crate) user: Option<User>,
+    pub(crate) deferred_user: Option<String>,
     pub(crate) group: Option<Group>,
+    pub(crate) require_valid_account: bool,
+    pub(crate) require_launch_user: Option<String>,
+    pub(crate) supplementary_groups: Vec<Group>,
+    pub(crate) capabilities: Vec<Capability>,
+    pub(crate) apparmor_profile: Option<String>,
+    pub(crate) no_new_privs: bool,
+    pub(crate) verify_groups: bool,
+    pub(crate) privilege_mode: PrivilegeMode,
+    pub(crate) resuid: Option<[u32; 3]>,
+    pub(crate) resgid: Option<[u32; 3]>,
+    pub(crate) rlimits: Vec<Rlimit>,
+    pub(crate) login_class: Option<String>,
     pub(crate) umask: u16,
+    pub(crate) file_creation_umask: Option<u16>,
     // stdin is practically always null
     pub(crate) stdin: Stdio,
     pub(crate) stdout: Stdio,
     pub(crate) stderr: Stdio,
+    pub(crate) no_stdio_redirect: bool,
+    pub(crate) keep_fds: Vec<RawFd>,
+    pub(crate) close_all_fds: bool,
+    pub(crate) clear_env: bool,
+    pub(crate) env: Vec<(OsString, OsString)>,
+    pub(crate) stdio_fallback_to_null: bool,
+    pub(crate) verify_devnull: bool,
+    pub(crate) stdio_buffering: Option<BufferMode>,
+    pub(crate) ring_buffer_dump_signal: Option<Signal>,
+    pub(crate) flush_stdio_on_signal: Option<(Signal, Duration)>,
+    pub(crate) install_panic_hook: bool,
     pub(crate) name: Option<OsString>,
-    pub(crate) before_fork_hook: Option<fn(pid: i32)>,
-    pub(crate) after_fork_parent_hook: Option<fn(parent_pid: i32, child_pid: i32) -> !>,
-    pub(crate) after_fork_child_hook: Option<fn(parent_pid: i32, child_pid: i32) -> ()>,
+    pub(crate) name_truncate: bool,
+    pub(crate) argv0_basename: bool,
+    pub(crate) skip_if_already_daemonized: bool,
+    pub(crate) fork_strategy: ForkStrategy,
+    pub(crate) debug_friendly: bool,
+    pub(crate) auto_detect_supervisor: bool,
+    pub(crate) wait_for_parent_exit: bool,
+    pub(crate) parent_behavior: ParentBehavior,
+    pub(crate) child_subreaper: bool,
+    pub(crate) foreground: bool,
+    pub(crate) double_fork: bool,
+    pub(crate) before_fork_hook: Option<PidHook<'a>>,
+    pub(crate) before_fork_payload_hook: Option<PayloadHook<'a>>,
+    pub(crate) payload: Option<Rc<dyn Any>>,
+    pub(crate) after_fork_parent_hook: Option<ForkHook<'a>>,
+    pub(crate) parent_cleanup: Option<Rc<dyn Fn() + 'a>>,
+    pub(crate) after_fork_child_hook: Option<ForkHook<'a>>,
+    pub(crate) after_fork_child_payload_hook: Option<ChildPayloadHook<'a>>,
+    pub(crate) on_fork_hook: Option<LevelHook<'a>>,
+    pub(crate) after_drop_hook: Option<DropHook<'a>>,
+    pub(crate) credential_command: Option<(OsString, Vec<OsString>)>,
+    pub(crate) credential: Option<Credential>,
     pub(crate) after_init_hook_data: Option<&'a dyn Any>,
-    pub(crate) after_init_hook: Option<fn(Option<&'a dyn Any>)>,
+    pub(crate) after_init_hook: Option<InitHook<'a>>,
+    pub(crate) after_init_payload_hook: Option<InitHook<'a>>,
+    pub(crate) after_init_credential_hook: Option<CredentialHook<'a>>,
+}
+
+#[cfg(feature = "json")]
+impl<'a> Daemon<'a> {
+    /// Renders the status file contents for the running daemon
+    fn status_json(&self, pid: Pid, started_at: std::time::SystemTime) -> String {
+        let started_at = match started_at.duration_since(std::time::UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs(),
+            Err(_) => 0,
+        };
+        let workdir = std::env::current_dir().unwrap_or_else(|_| self.chdir.clone());
+        serde_json::json!({
+            "pid": pid.as_raw(),
+            "uid": nix::unistd::getuid().as_raw(),
+            "gid": nix::unistd::getgid().as_raw(),
+            "started_at": started_at,
+            "workdir": workdir.to_string_lossy(),
+            "name": self.name.as_ref().map(|name| name.to_string_lossy()),
+        })
+        .to_string()
+    }
+}
+
+impl<'a> Daemon<'a> {
+    /// The work dir as seen from the current root, ie. inside the chroot directory when one is set
+    fn work_dir_path(&self) -> PathBuf {
+        match &self.chroot {
+            Some(root) => jailed_path(root, &self.chdir),
+            None => self.chdir.clone(),
+        }
+    }
+
+    /// Where a pid file in the work dir is found once the chroot is entered, below the new root
+    fn pid_file_in_chroot(&self, pid_file: &Path) -> PathBuf {
+        // a relative work dir is entered from the top of the new root
+        let work_dir = Path::new("/").join(&self.chdir);
+        match pid_file.strip_prefix(self.work_dir_path()) {
+            Ok(relative) => work_dir.join(relative),
+            Err(_) => work_dir.join(pid_file),
+        }
+    }
+
+    /// Privileges are only dropped with both a user and a group, tell which half is missing
+    fn check_user_group_pair(&self) -> Result<()> {
+        match (&self.user, &self.group) {
+            (Some(_), None) => Err(DaemonError::UserWithoutGroup),
+            (None, Some(_)) => Err(DaemonError::GroupWithoutUser),
+            (None, None) if self.chown_pid_file => Err(DaemonError::InvalidUserGroupPair),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the configuration on its own, without looking anything up: the user and group come in pairs,
+    /// a deferred user needs a group too, the umask bits are valid and no standard stream is among the fds
+    /// to keep. `start` runs this first, before
+    /// anything is forked, so a bad configuration is reported by the process that launched the daemon
+    pub fn validate(&self) -> Result<()> {
+        match &self.deferred_user {
+            Some(_) if self.group.is_none() => return Err(DaemonError::UserWithoutGroup),
+            // the user is only known in the child, which checks the pair again
+            Some(_) => (),
+            None => self.check_user_group_pair()?,
+        }
+        if !self.capabilities.is_empty() && self.user.is_none() && self.deferred_user.is_none() {
+            return Err(DaemonError::CapabilitiesWithoutUser);
+        }
+        if Mode::from_bits(self.umask as _).is_none() {
+            return Err(DaemonError::InvalidUmaskBits);
+        }
+        if let Some(mask) = self.file_creation_umask {
+            if Mode::from_bits(mask as _).is_none() {
+                return Err(DaemonError::InvalidUmaskBits);
+            }
+        }
+        if let Some(&fd) = self.keep_fds.iter().find(|&&fd| fd <= libc::STDERR_FILENO) {
+            return Err(DaemonError::KeepStdioFd { fd });
+        }
+        Ok(())
+    }
+
+    /// Creates the pid file under the file creation umask, owned by the user and group privileges are
+    /// dropped to when chown_pid_file is set. The ownership is applied before the pid is written
+    fn write_pid_file(&self, path: &Path, pid: Pid, mask: Option<Mode>) -> Result<()> {
+        let owner = match (&self.user, &self.group) {
+            (Some(user), Some(group)) if self.chown_pid_file => Some((Uid::from_raw(user.id), Gid::from_raw(group.id))),
+            _ => None,
+        };
+        with_umask(mask, || {
+            let (history, in_place, newline) = (self.pid_file_append_history, self.lock_pid_file, self.pid_file_newline);
+            write_pid_file(path, pid, history, in_place, self.pid_file_zero_pad, newline, owner)
+        })
+    }
+
+    /// Checks the real uid against the required launch user
+    fn check_launch_user(&self) -> Result<()> {
+        let expected = match &self.require_launch_user {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        let launch_user = User::try_from(expected.as_str())?;
+        if launch_user.id == getuid().as_raw() {
+            Ok(())
+        } else {
+            Err(DaemonError::WrongLaunchUser { expected: expected.clone() })
+        }
+    }
+
+    /// Make the private mounts and change the root directory if one is configured and move to its top
+    fn enter_chroot(&self) -> Result<()> {
+        if !self.tmpfs_mounts.is_empty() {
+            mount_private(&self.tmpfs_mounts)?;
+        }
+        if let Some(root) = &self.chroot {
+            if let Err(errno) = chroot(root.as_path()) {
+                return Err(DaemonError::Chroot(errno));
+            }
+            if let Err(errno) = chdir("/") {
+                return Err(DaemonError::ChDir(errno));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Binds the unix socket at `path` and sets its mode and owner, a socket file left at the path is removed first.
+/// It is bound under a umask only letting the owner in and only listens once its mode and owner are set,
+/// so nobody else can connect in between
+fn bind_admin_socket(path: &Path, mode: u32, owner: Option<(u32, u32)>) -> Result<UnixListener> {
+    let failed = |source: std::io::Error| DaemonError::AdminSocket { path: path.to_owned(), source };
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path).map_err(failed)?,
+        Ok(_) => return Err(failed(std::io::Error::from_raw_os_error(libc::EEXIST))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => return Err(failed(e)),
+    }
+    let fd = socket(AddressFamily::Unix, SockType::Stream, SockFlag::empty(), None).map_err(|errno| failed(errno.into()))?;
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    let addr = UnixAddr::new(path).map_err(|errno| failed(errno.into()))?;
+    let owner_only = Mode::from_bits_truncate(0o177);
+    with_umask(Some(owner_only), || bind(fd, &SockAddr::Unix(addr))).map_err(|errno| failed(errno.into()))?;
+    let configured = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+        .map_err(std::io::Error::from)
+        .and_then(|_| std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)))
+        .and_then(|_| match owner {
+            Some((uid, gid)) => std::os::unix::fs::chown(path, Some(uid), Some(gid)),
+            None => Ok(()),
+        })
+        .and_then(|_| listen(fd, 128).map_err(std::io::Error::from));
+    if let Err(e) = configured {
+        let _ = std::fs::remove_file(path);
+        return Err(failed(e));
+    }
+    Ok(listener)
+}
+
+/// Clears the close on exec flag of `fd`, its other descriptor flags are kept
+fn keep_open_on_exec(fd: RawFd) -> Result<()> {
+    let flags = match fcntl(fd, FcntlArg::F_GETFD) {
+        Ok(flags) => FdFlag::from_bits_truncate(flags),
+        Err(_) => return Err(DaemonError::KeepFd { fd }),
+    };
+    match fcntl(fd, FcntlArg::F_SETFD(flags - FdFlag::FD_CLOEXEC)) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(DaemonError::KeepFd { fd }),
+    }
+}
+
+/// Blocks until every write end of the pipe is closed then closes the read end
+fn wait_for_pipe_close(read_end: RawFd) {
+    let mut buf = [0u8; 1];
+    loop {
+        match read(read_end, &mut buf) {
+            Ok(0) => break,
+            Ok(_) | Err(nix::errno::Errno::EINTR) => continue,
+            Err(_) => break,
+        }
+    }
+    let _ = close(read_end);
+}
+
+/// Resolves `path` as it will be seen once `root` is the root directory
+fn jailed_path(root: &Path, path: &Path) -> PathBuf {
+    match path.strip_prefix("/") {
+        Ok(relative) => root.join(relative),
+        Err(_) => root.join(path),
+    }
+}
+
+/// Name of the environment variable overriding the supervisor detection, `1` for supervised and `0` for not
+const SUPERVISED_ENV: &str = "DAEMONIZE_ME_SUPERVISED";
+/// Supervisors running services as their direct children, matched against the name of the parent process
+const SUPERVISOR_PROCESSES: [&str; 3] = ["runsv", "s6-supervise", "supervise"];
+
+/// Name of the environment variable used to hand the parent pid to a re-executed child
+const REEXEC_PARENT_ENV: &str = "DAEMONIZE_ME_REEXEC_PARENT";
+
+/// How the daemon process is created
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForkStrategy {
+    /// Plain `fork(2)`, the child is a copy on write image of the parent, this is the default
+    Fork,
+    /// Spawn a fresh copy of the current executable with the same arguments, `posix_spawn` style,
+    /// and exit the parent. The new process runs `main` from the start and when it reaches `start`
+    /// again it carries on as the child instead of forking. This avoids copying the page tables of
+    /// very large processes, at the cost of redoing everything that happens before `start`,
+    /// so the program must build the same configuration on every run.
+    /// The pre fork hook only runs in the original process
+    Reexec,
+}
+
+/// What the parent does once the daemon is forked, see [`Daemon::parent_behavior`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParentBehavior {
+    /// Run the post fork parent hook or else the parent cleanup, then exit with this code, this is the default
+    Exit(i32),
+    /// Return [`DaemonOutcome::Parent`] from `start` and leave it to the caller, no parent hook is called
+    Return,
+}
+
+/// When the pid file first appears, see [`Daemon::pid_file_timing`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PidFileTiming {
+    /// The daemon writes it after the fork, this is the default. Until then there is no pid file, a supervisor
+    /// reading it right after the launcher exited may find nothing yet, but whatever it finds names the daemon
+    AfterFork,
+    /// [`PreparedDaemon::start`] writes it with the pid of the launching process right before the fork, after the
+    /// lock, the admin socket and the credential command, and the daemon replaces it with its own pid as usual.
+    /// The file is never missing, but until the daemon's write it names the launcher, which is about to exit,
+    /// so a reader must tolerate a stale pid for that window. The replacing write is atomic unless the pid file
+    /// is the locked one. If the fork or the daemon initialization fails while the file still names the
+    /// launcher it is removed again, except with the run history format where the launcher keeps a line of
+    /// its own. Combined with [`Daemon::pid_file_after_init`] the present file no longer means the daemon
+    /// is fully initialized
+    ReservedBeforeFork,
+}
+
+/// Path, mode and owner of the socket of [`Daemon::admin_socket`]
+type AdminSocket = (PathBuf, u32, Option<(u32, u32)>);
+
+// Hooks are reference counted rather than boxed so a configuration stays cloneable
+type PidHook<'a> = Rc<dyn Fn(i32) + 'a>;
+type PayloadHook<'a> = Rc<dyn Fn(i32) -> Box<dyn Any> + 'a>;
+type ForkHook<'a> = Rc<dyn Fn(i32, i32) + 'a>;
+type ChildPayloadHook<'a> = Rc<dyn Fn(i32, i32, Option<&dyn Any>) + 'a>;
+type InitHook<'a> = Rc<dyn Fn(Option<&dyn Any>) + 'a>;
+type CredentialHook<'a> = Rc<dyn Fn(&Credential) + 'a>;
+type LevelHook<'a> = Rc<dyn Fn(u8, Pid) + 'a>;
+type DropHook<'a> = Rc<dyn Fn(u32, u32) + 'a>;
+
+/// A configuration resolved by [`Daemon::prepare`], it can't be changed any more only started.
+/// It holds the lock on the pid file when one is used, dropping it without starting releases the lock
+pub struct PreparedDaemon<'a> {
+    daemon: Daemon<'a>,
+    reexec_parent: Option<Pid>,
+    account: Option<OsString>,
+    work_dir: Option<File>,
+    lockfile: Option<File>,
+    admin_socket: Option<UnixListener>,
+    already_daemon: bool,
+}
+
+/// What `start` did, it is only returned in the process that continues as the daemon
+/// unless the parent was kept running with [`ParentBehavior::Return`].
+/// Two outcomes are equal when they hold the same file descriptors
+#[derive(Debug)]
+pub enum DaemonOutcome {
+    /// This is the process that launched the daemon, only returned with [`ParentBehavior::Return`]
+    Parent { child: Pid },
+    /// The process was forked and this is the daemonized child
+    #[non_exhaustive]
+    Child {
+        /// Reopens the log files of the daemon
+        log: LogHandle,
+        /// The locked pid or lock file when [`Daemon::hand_over_pid_file_lock`] is set
+        lock: Option<File>,
+        /// The listener of [`Daemon::admin_socket`]
+        admin_socket: Option<UnixListener>,
+    },
+    /// The process already was a daemon so nothing was done, see [`Daemon::skip_if_already_daemonized`]
+    AlreadyDaemon,
+}
+
+impl PartialEq for DaemonOutcome {
+    fn eq(&self, other: &Self) -> bool {
+        fn fd<T: AsRawFd>(handle: &Option<T>) -> Option<RawFd> {
+            handle.as_ref().map(AsRawFd::as_raw_fd)
+        }
+        match (self, other) {
+            (DaemonOutcome::Parent { child }, DaemonOutcome::Parent { child: other }) => child == other,
+            (
+                DaemonOutcome::Child { log, lock, admin_socket },
+                DaemonOutcome::Child { log: other_log, lock: other_lock, admin_socket: other_socket },
+            ) => log == other_log && fd(lock) == fd(other_lock) && fd(admin_socket) == fd(other_socket),
+            (DaemonOutcome::AlreadyDaemon, DaemonOutcome::AlreadyDaemon) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for DaemonOutcome {}
+
+/// Set once `start` got as far as forking, or staying in the foreground, a second `start` in the same
+/// process fails with `AlreadyDaemonized`. It is per process and only an exec resets it
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+impl<'a> Default for Daemon<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<'a> Daemon<'a> {
     pub fn new() -> Self {
         Daemon {
             chdir: Path::new("/").to_owned(),
+            chroot: None,
+            tmpfs_mounts: Vec::new(),
             pid_file: None,
             chown_pid_file: false,
+            pid_file_append_history: false,
+            pid_file_relative_to_launch_dir: false,
+            pid_file_zero_pad: None,
+            pid_file_newline: false,
+            pid_file_after_init: false,
+            pid_file_timing: PidFileTiming::AfterFork,
+            pid_file_heartbeat: None,
+            lock_pid_file: false,
+            use_lockfile: false,
+            hand_over_pid_file_lock: false,
+            admin_socket: None,
+            ready_file: None,
+            #[cfg(feature = "json")]
+            status_file: None,
             user: None,
+            deferred_user: None,
             group: None,
+            require_valid_account: false,
+            require_launch_user: None,
+            supplementary_groups: Vec::new(),
+            capabilities: Vec::new(),
+            apparmor_profile: None,
+            no_new_privs: false,
+            verify_groups: false,
+            privilege_mode: PrivilegeMode::Permanent,
+            resuid: None,
+            resgid: None,
+            rlimits: Vec::new(),
+            login_class: None,
             umask: 0o027,
+            file_creation_umask: None,
             stdin: Stdio::devnull(),
             stdout: Stdio::devnull(),
             stderr: Stdio::devnull(),
+            no_stdio_redirect: false,
+            keep_fds: Vec::new(),
+            close_all_fds: false,
+            clear_env: false,
+            env: Vec::new(),
+            stdio_fallback_to_null: false,
+            verify_devnull: false,
+            stdio_buffering: None,
+            ring_buffer_dump_signal: None,
+            flush_stdio_on_signal: None,
+            install_panic_hook: false,
             name: None,
+            name_truncate: false,
+            argv0_basename: false,
+            skip_if_already_daemonized: false,
+            fork_strategy: ForkStrategy::Fork,
+            debug_friendly: false,
+            auto_detect_supervisor: false,
+            wait_for_parent_exit: false,
+            parent_behavior: ParentBehavior::Exit(0),
+            child_subreaper: false,
+            foreground: false,
+            double_fork: false,
             before_fork_hook: None,
+            before_fork_payload_hook: None,
+            payload: None,
             after_fork_parent_hook: None,
+            parent_cleanup: None,
             after_fork_child_hook: None,
+            after_fork_child_payload_hook: None,
+            on_fork_hook: None,
+            after_drop_hook: None,
+            credential_command: None,
+            credential: None,
             after_init_hook_data: None,
             after_init_hook: None,
+            after_init_payload_hook: None,
+            after_init_credential_hook: None,
         }
     }
 
@@ -94,15 +616,243 @@ impl<'a> Daemon<'a> {
         self
     }
 
-    /// As the last step the code will change the working directory to this one defaults to `/`
+    /// Instead of truncating the pid file on each start append a `{timestamp} {pid}` line to it,
+    /// the timestamp is in seconds since the unix epoch and the current pid is the one on the last line,
+    /// use [`Daemon::current_pid`] to read it back
+    pub fn pid_file_append_history(mut self, append: bool) -> Self {
+        self.pid_file_append_history = append;
+        self
+    }
+
+    /// Resolve a relative pid file against the working directory of the process when `start` is called
+    /// instead of the work dir, so `pid_file("example.pid")` lands next to where the program was launched
+    /// rather than in `/`. The lock file follows the pid file
+    pub fn pid_file_relative_to_launch_dir(mut self, relative: bool) -> Self {
+        self.pid_file_relative_to_launch_dir = relative;
+        self
+    }
+
+    /// Write the pid zero padded to `width` digits, eg. `00042`, for legacy tools parsing with `scanf("%5d")`.
+    /// The pid is never preceded by whitespace, if it doesn't fit in `width` digits `start` fails with `PidTooWide`
+    pub fn pid_file_zero_pad(mut self, width: usize) -> Self {
+        self.pid_file_zero_pad = Some(width);
+        self
+    }
+
+    /// End the pid with a newline, as `start-stop-daemon` and many init scripts expect of a text file.
+    /// Off by default so the pid file keeps holding only the digits, readers of this crate accept both.
+    /// History lines always end with a newline
+    pub fn pid_file_newline(mut self, newline: bool) -> Self {
+        self.pid_file_newline = newline;
+        self
+    }
+
+    /// Write the pid file only after the after_init hook returned, so a present pid file means the daemon
+    /// is fully initialized as monitoring tools assume. By default it is written before privileges are dropped
+    /// and the hook runs, an init that fails then leaves a pid file naming a process about to exit.
+    /// The deferred write happens as the daemon user: the pid file directory must be writable by it and,
+    /// with a chroot, the path made absolute against the work dir is looked up inside the new root
+    pub fn pid_file_after_init(mut self, after_init: bool) -> Self {
+        self.pid_file_after_init = after_init;
+        self
+    }
+
+    /// Choose when the pid file first appears, eg. [`PidFileTiming::ReservedBeforeFork`] for supervisors that
+    /// read it as soon as the launcher returns and treat a missing file as a failed start.
+    /// The timings trade a window without a pid file against a window with the pid of the launcher in it
+    pub fn pid_file_timing(mut self, timing: PidFileTiming) -> Self {
+        self.pid_file_timing = timing;
+        self
+    }
+
+    /// Start a thread in the daemon that updates the mtime of the pid file every `interval`,
+    /// external watchdogs that check the mtime can then tell a hung daemon from a live one.
+    /// The pid file stays open for this so a chroot doesn't get in the way, after dropping privileges
+    /// the user must still own or be able to write the pid file, see the `chmod` argument of [`Daemon::pid_file`]
+    pub fn pid_file_heartbeat(mut self, interval: Duration) -> Self {
+        self.pid_file_heartbeat = Some(interval);
+        self
+    }
+
+    /// Give the daemon a pid file that is opened without truncating it and `flock`ed before forking,
+    /// a second instance fails with `AlreadyRunning` before it touched the pid of the first one.
+    /// The lock is held for the lifetime of the daemon, it replaces the lock file of [`Daemon::use_lockfile`]
+    pub fn pid_file_locked<T: AsRef<Path>>(mut self, path: T) -> Self {
+        self.pid_file = Some(path.as_ref().to_owned());
+        self.lock_pid_file = true;
+        self
+    }
+
+    /// Return the file holding the lock of [`Daemon::pid_file_locked`] or [`Daemon::use_lockfile`] in
+    /// [`DaemonOutcome::Child`] rather than keeping it open for the lifetime of the process, for callers
+    /// managing the lock themselves. Dropping the file releases the `flock`, so store it for as long as the
+    /// daemon runs, and removing the pid or lock file from disk is up to the caller as well
+    pub fn hand_over_pid_file_lock(mut self, hand_over: bool) -> Self {
+        self.hand_over_pid_file_lock = hand_over;
+        self
+    }
+
+    /// Bind a unix control socket at `path` while still privileged, eg. `/run/x.sock` only root may create,
+    /// set its permission bits to `mode` and, when given, its owner to the `(uid, gid)` pair, then hand the
+    /// listener to the daemon in [`DaemonOutcome::Child`] to serve as the service user.
+    /// It is bound by [`Daemon::prepare`] right after the lock is taken, a socket left at the path by an
+    /// instance that is gone is replaced, so use a lock when a live instance may still serve it.
+    /// Any other kind of file at the path or a failed call fails with `AdminSocket`
+    pub fn admin_socket<T: AsRef<Path>>(mut self, path: T, mode: u32, owner: Option<(u32, u32)>) -> Self {
+        self.admin_socket = Some((path.as_ref().to_owned(), mode, owner));
+        self
+    }
+
+    /// Create and `flock` a `{pid_file}.lock` file next to the pid file, the lock is taken before forking
+    /// so a second instance fails right away with `AlreadyRunning`, and held for the lifetime of the daemon.
+    /// Keeping the lock separate from the pid file means the pid file can be rewritten freely on restart,
+    /// this does nothing if no pid file is set
+    pub fn use_lockfile(mut self, lock: bool) -> Self {
+        self.use_lockfile = lock;
+        self
+    }
+
+    /// Reads the current pid from a pid file written by the daemon, this is the last pid written
+    /// to it so it works for both the plain and the run history formats
+    pub fn current_pid<T: AsRef<Path>>(path: T) -> Result<Pid> {
+        read_current_pid(path.as_ref())
+    }
+
+    /// Everything a `status` command wants to know about the daemon owning a pid file: its pid,
+    /// whether it is still alive, for how long it has been running and the user it runs as,
+    /// see [`DaemonStatus`]. The uptime and user rely on `/proc` unless the run history format is used
+    pub fn status<T: AsRef<Path>>(pid_file: T) -> Result<DaemonStatus> {
+        read_status(pid_file.as_ref())
+    }
+
+    /// Stop the instance owning `pid_file` before starting a new one: send it `term_signal`, give it `grace`
+    /// to exit and kill it with SIGKILL if it is still around then, like `TimeoutStopSec` of systemd.
+    /// Returns the pid of the old instance and whether it had to be killed, `None` when no pid file
+    /// exists or it names a process that is gone already
+    pub fn takeover_with<T: AsRef<Path>>(pid_file: T, term_signal: Signal, grace: Duration) -> Result<Option<Takeover>> {
+        take_over(pid_file.as_ref(), term_signal, grace)
+    }
+
+    /// Create a file once the daemon is fully initialized, that is after the post init hook returns,
+    /// this is a readiness signal for supervisors that poll for a file instead of using `sd_notify`
+    /// # Arguments
+    /// * `path` - path to the file, relative paths are resolved against the work dir
+    pub fn ready_file<T: AsRef<Path>>(mut self, path: T) -> Self {
+        self.ready_file = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Routes the given signals to a self-pipe, each delivered signal writes one byte identifying it,
+    /// poll the returned pipe from your event loop instead of doing work in a signal handler.
+    /// Call this in the daemon after `start` returns so the handlers belong to the child,
+    /// only one signal pipe can be installed per process.
+    /// The installed handler is async-signal-safe, it only calls `write(2)` and preserves errno
+    pub fn signal_pipe(signals: &[Signal]) -> Result<SignalPipe> {
+        SignalPipe::install(signals)
+    }
+
+    /// Write a JSON status file once the daemon is initialized, for monitoring tools that want more than a pid:
+    /// `{"pid", "uid", "gid", "started_at", "workdir", "name"}` where `started_at` is in seconds since the unix epoch.
+    /// The file is written to a temporary file first and renamed into place so it is never seen half written
+    /// # Arguments
+    /// * `path` - path to the file, relative paths are resolved against the work dir
+    #[cfg(feature = "json")]
+    pub fn status_file<T: AsRef<Path>>(mut self, path: T) -> Self {
+        self.status_file = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// As the last step the code will change the working directory to this one defaults to `/`,
+    /// when a chroot is set this path is interpreted inside the new root, relative paths included.
+    /// Without a chroot the directory is opened by [`Daemon::prepare`] and entered with `fchdir`,
+    /// so replacing it after it was validated does not change where the daemon ends up
     pub fn work_dir<T: AsRef<Path>>(mut self, path: T) -> Self {
         self.chdir = path.as_ref().to_owned();
         self
     }
 
+    /// Use the directory of the pid file as the work dir, for daemons keeping all their runtime state in one place.
+    /// A relative pid file is resolved against the work dir set so far, so call this after [`Daemon::pid_file`]
+    /// and [`Daemon::work_dir`], the pid file path is made absolute so it still points at the same file.
+    /// Fails with `NoPidFile` when no pid file is set
+    pub fn work_dir_as_pid_dir(mut self) -> Result<Self> {
+        let pid_file = match &self.pid_file {
+            Some(pid_file) => pid_file,
+            None => return Err(DaemonError::NoPidFile),
+        };
+        let mut resolved = self.chdir.join(pid_file);
+        if resolved.is_relative() {
+            match std::env::current_dir() {
+                Ok(cwd) => resolved = cwd.join(resolved),
+                Err(e) => return Err(DaemonError::ChDir(errno_of(&e))),
+            }
+        }
+        match resolved.parent() {
+            Some(dir) => self.chdir = dir.to_owned(),
+            None => return Err(DaemonError::NoPidFile),
+        }
+        self.pid_file = Some(resolved);
+        Ok(self)
+    }
+
+    /// Change the root directory of the daemon to this path, then chdir to its top. This is done while
+    /// dropping privileges, after `setgid` and `initgroups` so the group lookups still see the system
+    /// databases and right before `setuid` as only root may chroot. The pid file is written before,
+    /// outside of the jail, and the ready and status files after, inside of it.
+    /// The work dir and relative pid file paths are resolved inside the new root, if the work dir resolves to
+    /// a different directory once inside the new root (eg. through an absolute symlink) `start` fails
+    /// with [`DaemonError::ChrootWorkDir`] instead of silently ending up somewhere else
+    pub fn chroot<T: AsRef<Path>>(mut self, path: T) -> Self {
+        self.chroot = Some(path.as_ref().to_owned());
+        self
+    }
+
+    /// Give the daemon a mount namespace of its own with these mounts, each given as
+    /// `(source, target, fstype, flags)` with the `MS_*` flags of mount(2), eg.
+    /// `("tmpfs", "/srv/jail/tmp", "tmpfs", libc::MS_NOSUID | libc::MS_NODEV)` for a private `/tmp`.
+    /// The namespace is unshared and the mounts made right before the chroot while the daemon is still root,
+    /// so targets are paths outside of the jail. A failed mount fails `start` with [`DaemonError::Mount`]
+    /// naming its target. Only supported on Linux, elsewhere `start` fails with `UnsupportedOnOS` before forking
+    pub fn tmpfs_mounts<T: AsRef<Path>>(mut self, mounts: &[(&str, T, &str, libc::c_ulong)]) -> Self {
+        self.tmpfs_mounts = mounts
+            .iter()
+            .map(|(source, target, fstype, flags)| (source.to_string(), target.as_ref().to_owned(), fstype.to_string(), *flags))
+            .collect();
+        self
+    }
+
     /// The code will attempt to drop privileges with `setuid` to the provided user
     pub fn user<T: Into<User>>(mut self, user: T) -> Self {
         self.user = Some(user.into());
+        self.deferred_user = None;
+        self
+    }
+
+    /// Like [`Daemon::user`] with the name looked up right away, so a missing user fails the builder chain
+    pub fn user_by_name(self, name: &str) -> Result<Self> {
+        Ok(self.user(User::try_from(name)?))
+    }
+
+    /// Like [`Daemon::user`] with the uid looked up right away
+    pub fn user_by_id(self, uid: u32) -> Result<Self> {
+        Ok(self.user(User::try_from(uid)?))
+    }
+
+    /// Drop privileges to the first of these users that exists, for deployments where the service account
+    /// depends on the distribution, eg. `&["_myservice", "daemon", "nobody"]`.
+    /// Fails with `InvalidUser` listing the names if none of them exists
+    pub fn user_first_available(mut self, names: &[&str]) -> Result<Self> {
+        self.user = Some(User::resolve_first_available(names, &SystemResolver)?);
+        self.deferred_user = None;
+        Ok(self)
+    }
+
+    /// Like [`Daemon::user`] but the name is only looked up in the child, right after the post fork child hook,
+    /// for setups where the identity databases only become visible after fork, eg. once the hook joined
+    /// a mount namespace. The lookup errors of [`User`] are returned by `start` from the child
+    pub fn user_name_deferred(mut self, name: &str) -> Self {
+        self.deferred_user = Some(name.to_owned());
+        self.user = None;
         self
     }
 
@@ -112,25 +862,224 @@ impl<'a> Daemon<'a> {
         self
     }
 
+    /// Like [`Daemon::group`] with the name looked up right away, so a missing group fails the builder chain
+    pub fn group_by_name(self, name: &str) -> Result<Self> {
+        Ok(self.group(Group::try_from(name)?))
+    }
+
+    /// Like [`Daemon::group`] with the gid looked up right away
+    pub fn group_by_id(self, gid: u32) -> Result<Self> {
+        Ok(self.group(Group::try_from(gid)?))
+    }
+
+    /// Drop privileges to the first of these groups that exists, fails with `InvalidGroup` listing the names if none does
+    pub fn group_first_available(mut self, names: &[&str]) -> Result<Self> {
+        self.group = Some(Group::resolve_first_available(names, &SystemResolver)?);
+        Ok(self)
+    }
+
     pub fn group_copy_user(mut self) -> Result<Self> {
         if let Some(user) = &self.user {
             self.group = Some(Group::try_from(&user.name)?);
             Ok(self)
         } else {
-            Err(InvalidUser)
+            Err(InvalidUser { names: Vec::new(), source: None })
+        }
+    }
+
+    /// Drop privileges to the primary group of the user set with [`Daemon::user`], looked up by the gid of
+    /// its passwd record, unlike [`Daemon::group_copy_user`] this doesn't assume the group is named after the user.
+    /// Fails with `InvalidUser` when no user is set yet
+    pub fn group_from_user_primary_gid(self) -> Result<Self> {
+        self.group_from_user_primary_gid_with(&SystemResolver)
+    }
+
+    fn group_from_user_primary_gid_with<R: RecordResolver>(mut self, resolver: &R) -> Result<Self> {
+        match &self.user {
+            Some(user) => {
+                self.group = Some(Group::resolve_id(user.details_with(resolver)?.primary_gid, resolver)?);
+                Ok(self)
+            }
+            None => Err(InvalidUser { names: Vec::new(), source: None }),
+        }
+    }
+
+    /// Refuse to start unless the real uid of the launching process is this user (a name or a uid),
+    /// for daemons only a deploy account may launch, `start` fails with `WrongLaunchUser` before doing anything.
+    /// This is only a precondition, it changes no ids
+    pub fn require_launch_user(mut self, name: &str) -> Self {
+        self.require_launch_user = Some(name.to_owned());
+        self
+    }
+
+    /// Refuse to drop privileges to an account that is locked (password field starting with `!`) or
+    /// has a nologin shell, `start` fails with `AccountLocked` then.
+    /// This is off by default since service users legitimately use nologin shells
+    pub fn require_valid_account(mut self, require: bool) -> Self {
+        self.require_valid_account = require;
+        self
+    }
+
+    /// Extra supplementary groups for the daemon, set with `setgroups(2)` after `setgid` and before `setuid`.
+    /// They are merged with the groups `initgroups` gives the user from the group database, not replacing them,
+    /// and only apply when both a user and a group are set. Unsupported on macOS
+    pub fn supplementary_groups(mut self, groups: Vec<Group>) -> Self {
+        self.supplementary_groups = groups;
+        self
+    }
+
+    /// Keep these capabilities when privileges are dropped to the user, everything else root had is gone.
+    /// They stay effective in the daemon and are raised in the ambient set so programs it executes get them too,
+    /// which lets eg. a service bind port 80 without staying root. Keeping `Setuid` would let the daemon
+    /// become root again, the privilege drop check runs before the capabilities are restored.
+    /// Needs a user, Linux only
+    pub fn keep_capabilities(mut self, capabilities: Vec<Capability>) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Change to this AppArmor profile once privileges are dropped, like `aa_change_profile(2)` does, the profile
+    /// must be loaded and the current one allow the change. It is applied before no_new_privs which would
+    /// restrict the transition and only to the thread calling `start`, threads a ring buffer or a heartbeat
+    /// started earlier keep the old profile. No library is linked, the kernel interface is used directly.
+    /// Linux only, `start` fails with `AppArmor` when the change is refused
+    pub fn apparmor_profile(mut self, profile: &str) -> Self {
+        self.apparmor_profile = Some(profile.to_owned());
+        self
+    }
+
+    /// Set `PR_SET_NO_NEW_PRIVS` once privileges are dropped, so neither the daemon nor anything it executes
+    /// can gain privileges through setuid binaries or file capabilities. Linux only
+    pub fn no_new_privs(mut self, enable: bool) -> Self {
+        self.no_new_privs = enable;
+        self
+    }
+
+    /// Apply every option of a shared [`SecurityProfile`], replacing the ones set on this daemon so far.
+    /// Options the profile leaves unset are kept, call [`SecurityProfile::validate_profile`] first
+    pub fn apply_profile(mut self, profile: SecurityProfile) -> Self {
+        if let Some(user) = profile.user {
+            self = self.user(user);
+        }
+        if let Some(group) = profile.group {
+            self = self.group(group);
         }
+        if !profile.supplementary_groups.is_empty() {
+            self.supplementary_groups = profile.supplementary_groups;
+        }
+        if !profile.capabilities.is_empty() {
+            self.capabilities = profile.capabilities;
+        }
+        if let Some(mask) = profile.umask {
+            self.umask = mask;
+        }
+        for limit in profile.rlimits {
+            self = self.rlimit(limit.resource, limit.soft, limit.hard, limit.when);
+        }
+        if let Some(enable) = profile.no_new_privs {
+            self.no_new_privs = enable;
+        }
+        self
+    }
+
+    /// After dropping privileges check with `getgroups(2)` that the supplementary groups of the user
+    /// are actually in place, if any is missing `start` fails with `GroupVerificationFailed`
+    pub fn verify_groups(mut self, verify: bool) -> Self {
+        self.verify_groups = verify;
+        self
+    }
+
+    /// Set a resource limit for the daemon, `None` meaning unlimited, this can be called once per resource.
+    /// `when` says whether it is set before or after dropping privileges: raising a hard limit
+    /// (eg. `RLIMIT_NOFILE`) needs root so it has to come before, while a cap meant for the unprivileged
+    /// user can come after. Limits set before the drop are inherited by the unprivileged process
+    pub fn rlimit(mut self, resource: Resource, soft: Option<libc::rlim_t>, hard: Option<libc::rlim_t>, when: RlimitPhase) -> Self {
+        self.rlimits.retain(|limit| limit.resource != resource);
+        self.rlimits.push(Rlimit { resource, soft, hard, when });
+        self
+    }
+
+    /// Choose how privileges are dropped, see [`PrivilegeMode`], the temporary mode keeps root as the saved uid
+    /// so [`crate::with_privileges`] can regain it for scoped operations. A permanent drop from root is checked
+    /// by trying setuid(0) afterwards, `start` fails with `PrivilegeDropFailed` if root comes back
+    pub fn privilege_mode(mut self, mode: PrivilegeMode) -> Self {
+        self.privilege_mode = mode;
+        self
+    }
+
+    /// Set the real, effective and saved uids independently with `setresuid(2)` where the user would be set,
+    /// eg. keeping a saved uid of 0 while the effective uid is the service user. It replaces the uid change
+    /// of [`Daemon::privilege_mode`] and also applies without a user, chowning and the supplementary groups
+    /// still follow [`Daemon::user`]. Only on Linux, Android, FreeBSD and OpenBSD, elsewhere `start` fails
+    /// with `UnsupportedOnOS`
+    pub fn set_resuid(mut self, real: u32, effective: u32, saved: u32) -> Self {
+        self.resuid = Some([real, effective, saved]);
+        self
+    }
+
+    /// The gid counterpart of [`Daemon::set_resuid`] using `setresgid(2)`
+    pub fn set_resgid(mut self, real: u32, effective: u32, saved: u32) -> Self {
+        self.resgid = Some([real, effective, saved]);
+        self
+    }
+
+    /// Apply the resource limits, priority and umask of a login class with `setusercontext(3)`
+    /// right before dropping privileges, this is the idiomatic way to apply per user policy on FreeBSD,
+    /// on every other os `start` fails with `UnsupportedOnOS`
+    pub fn login_class(mut self, class: &str) -> Self {
+        self.login_class = Some(class.to_owned());
+        self
     }
 
+    /// Set the umask of the daemon from its octal bits, eg. `0o027`, bits outside of the permission
+    /// bits fail [`Daemon::validate`] with `InvalidUmaskBits`, see [`Daemon::umask_mode`] for a typed mask
     pub fn umask(mut self, mask: u16) -> Self {
         self.umask = mask;
         self
     }
 
+    /// Set the umask of the daemon from a typed mode, eg. `Mode::S_IRWXG | Mode::S_IRWXO`, which can't be invalid
+    pub fn umask_mode(mut self, mode: Mode) -> Self {
+        self.umask = mode.bits() as u16;
+        self
+    }
+
+    /// Preset for daemons sharing a directory with their group, sets the umask to 0o002 so the files
+    /// created keep group write permission (rw-rw-r--).
+    /// Combined with a setgid directory (`chmod g+s`) new files also inherit the group of the directory,
+    /// so every member of that group can keep working on them whoever created them
+    pub fn umask_group_writable(self) -> Self {
+        self.umask_mode(Mode::S_IWOTH)
+    }
+
+    /// Umask used only while the files managed by the daemon (pid file, ready file) are created,
+    /// the process umask set with [`Daemon::umask`] is restored right after, this allows for a strict
+    /// process umask while keeping those files readable to operators
+    pub fn file_creation_umask(mut self, mask: u16) -> Self {
+        self.file_creation_umask = Some(mask);
+        self
+    }
+
     pub fn stdin<T: Into<Stdio>>(mut self, stdio: T) -> Self {
         self.stdin = stdio.into();
         self
     }
 
+    /// Redirect stdin to `/dev/null` opened read only, reads from it return EOF immediately
+    pub fn stdin_null_readonly(mut self) -> Self {
+        self.stdin = Stdio::devnull_readonly();
+        self
+    }
+
+    /// Close stdin, stdout and stderr instead of redirecting them to /dev/null,
+    /// see [`Stdio::close`] for the caveats
+    pub fn close_inherited_streams(mut self) -> Self {
+        self.stdin = Stdio::close();
+        self.stdout = Stdio::close();
+        self.stderr = Stdio::close();
+        self
+    }
+
     pub fn stdout<T: Into<Stdio>>(mut self, stdio: T) -> Self {
         self.stdout = stdio.into();
         self
@@ -141,173 +1090,2768 @@ impl<'a> Daemon<'a> {
         self
     }
 
-    pub fn name(mut self, name: &OsStr) -> Self {
-        self.name = Some(OsString::from(name));
+    /// Leave fds 0, 1 and 2 exactly as the parent had them, for launchers that set up the streams themselves.
+    /// The stdin, stdout and stderr settings are ignored and the handle returned by `start` reopens nothing
+    pub fn no_stdio_redirect(mut self) -> Self {
+        self.no_stdio_redirect = true;
         self
     }
 
-    pub fn setup_pre_fork_hook(mut self, pre_fork_hook: fn(pid: i32)) -> Self {
-        self.before_fork_hook = Some(pre_fork_hook);
+    /// File descriptors the daemon must inherit, eg. a listening socket from systemd socket activation or
+    /// a metrics fd opened by a launcher. Their close on exec flag is cleared in the daemon so programs it
+    /// executes get them as well, and fds closed by this crate never include them.
+    /// `start` fails with `KeepFd` before forking when one of them isn't open and with `KeepStdioFd` for
+    /// 0, 1 and 2 which are the standard streams, set with [`Daemon::stdin`] and the others instead
+    pub fn keep_fds(mut self, fds: Vec<RawFd>) -> Self {
+        self.keep_fds = fds;
         self
     }
 
-    pub fn setup_post_fork_parent_hook(mut self, post_fork_parent_hook: fn(parent_pid: i32, child_pid: i32) -> !) -> Self {
-        self.after_fork_parent_hook = Some(post_fork_parent_hook);
+    /// Close every fd above stderr the daemon inherited, so files and sockets opened by the launching shell
+    /// or the program before `start` don't leak into it. The fds of [`Daemon::keep_fds`] are spared as are
+    /// the ones this crate still needs: the lock, the files the standard streams are redirected to and the
+    /// parent exit pipe. Files or sockets the program holds on to past `start`, eg. in a hook, must be kept as
+    /// well, once closed their handles would close whatever fd later reuses the number. Off by default
+    pub fn close_all_fds(mut self, enable: bool) -> Self {
+        self.close_all_fds = enable;
         self
     }
 
-    pub fn setup_post_fork_child_hook(mut self, post_fork_child_hook: fn(parent_pid: i32, child_pid: i32) -> ()) -> Self {
-        self.after_fork_child_hook = Some(post_fork_child_hook);
+    /// Remove every variable the daemon inherited from its environment right after the fork, so secrets or
+    /// settings of the launching shell don't leak into it and it runs the same however it was started.
+    /// The variables given to [`Daemon::env`] are set afterwards, both happen before the standard streams are
+    /// redirected, so with a journal stream `JOURNAL_STREAM` must be kept to be recognized. Off by default
+    pub fn clear_env(mut self, enable: bool) -> Self {
+        self.clear_env = enable;
         self
     }
 
-    pub fn setup_post_init_hook(mut self, post_fork_child_hook: fn(ctx: Option<&'a dyn Any>),
-                                data: Option<&'a dyn Any>) -> Self {
-        self.after_init_hook = Some(post_fork_child_hook);
-        self.after_init_hook_data = data;
+    /// Set an environment variable in the daemon after the fork, setting the same key again replaces the value.
+    /// [`Daemon::to_args`] only renders the key so the value doesn't end up in a command line
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(mut self, key: K, value: V) -> Self {
+        let key = key.as_ref().to_owned();
+        self.env.retain(|(existing, _)| *existing != key);
+        self.env.push((key, value.as_ref().to_owned()));
         self
     }
 
-    /// Using the parameters set, daemonize the process
-    pub fn start(self) -> Result<()> {
-        let mut pid: Pid;
-        let parent_pid = getpid();
-        // resolve options to concrete values to please the borrow checker
-        let has_pid_file = self.pid_file.is_some();
-        let pid_file_path = match self.pid_file {
-            Some(path) => path.clone(),
-            None => Path::new("").to_path_buf(),
-        };
+    /// When a standard stream can't be redirected point it at /dev/null and write a warning to stderr
+    /// instead of failing `start`, for operators preferring a running daemon with lost logs over a dead one.
+    /// The default is to fail with `RedirectStream`
+    pub fn stdio_fallback_to_null(mut self, fallback: bool) -> Self {
+        self.stdio_fallback_to_null = fallback;
+        self
+    }
 
-        // If the hook is set call it with the parent pid
-        if let Some(hook) = self.before_fork_hook {
-            hook(parent_pid.as_raw());
-        }
+    /// Check that /dev/null is the null character device (major 1 minor 3 on Linux) before pointing
+    /// streams at it, so a regular file planted in its place can't capture the daemon output.
+    /// `start` fails with `DevNullInvalid` otherwise
+    pub fn verify_devnull(mut self, verify: bool) -> Self {
+        self.verify_devnull = verify;
+        self
+    }
 
-        // Fork and if the process is the parent exit gracefully
-        // if the  process is the child just continue execution
-        // this was made unsafe by the nix upstream in between versions
-        // thus the unsafe block is required here
-        unsafe {
-            match fork() {
-                Ok(ForkResult::Parent { child: cpid }) => {
-                    if let Some(hook) = self.after_fork_parent_hook {
-                        hook(parent_pid.as_raw(), cpid.as_raw());
-                    } else {
-                        exit(0)
-                    }
-                }
-                Ok(ForkResult::Child) => {
-                    // Set up stream redirection as early as possible
-                    redirect_stdio(&self.stdin, &self.stdout, &self.stderr)?;
-                    pid = getpid();
-                    if let Some(hook) = self.after_fork_child_hook {
-                        hook(parent_pid.as_raw(), pid.as_raw());
-                    }
-                    ()
-                }
-                Err(_) => return Err(DaemonError::Fork),
-            }
-        }
+    /// Set the buffering mode of the libc stdout and stderr streams right after they are redirected,
+    /// line buffering makes sure a crash doesn't lose the last lines logged to a file.
+    /// This only affects libc stdio as used from C code, Rust's `println!` is not buffered by libc
+    pub fn stdio_buffering(mut self, mode: BufferMode) -> Self {
+        self.stdio_buffering = Some(mode);
+        self
+    }
 
-        if self.chown_pid_file && (self.user.is_none() || self.group.is_none()) {
-            return Err(DaemonError::InvalidUserGroupPair);
-        } else if (self.user.is_some() || self.group.is_some())
-            && (self.user.is_none() || self.group.is_none())
-        {
-            return Err(DaemonError::InvalidUserGroupPair);
-        }
+    /// Replace the panic hook in the daemon with one writing the panic message and a backtrace straight to
+    /// the redirected stderr fd, so a daemon that panics after detaching always leaves a trace in its log
+    pub fn install_panic_hook(mut self, install: bool) -> Self {
+        self.install_panic_hook = install;
+        self
+    }
+
+    /// Dump the stdio ring buffers to their dump paths whenever this signal is delivered to the daemon,
+    /// the dump is done by a dedicated thread as it isn't safe to do from a signal handler
+    pub fn ring_buffer_dump_signal(mut self, signal: Signal) -> Self {
+        self.ring_buffer_dump_signal = Some(signal);
+        self
+    }
+
+    /// When this signal is delivered, usually SIGTERM, flush the stdio buffers and drain the pump threads
+    /// waiting at most `timeout` for them, then die from the signal as an unhandled one would.
+    /// Daemons handling the signal themselves should rather call [`crate::flush_stdio`] on their way out
+    pub fn flush_stdio_on_signal(mut self, signal: Signal, timeout: Duration) -> Self {
+        self.flush_stdio_on_signal = Some((signal, timeout));
+        self
+    }
+
+    /// Name the daemon process. Linux keeps at most 15 bytes, see [`Daemon::name_truncate`], the BSDs set the whole title shown by ps(1)
+    /// and macOS only names the thread that called `start`, other targets fail `start` with `UnsupportedOnOS`
+    pub fn name(mut self, name: &OsStr) -> Self {
+        self.name = Some(OsString::from(name));
+        self
+    }
+
+    /// On Linux a name longer than 15 bytes fails `start` with `InvalidProcName` before forking,
+    /// with this set it is cut to 15 bytes instead like the kernel would do silently
+    pub fn name_truncate(mut self, truncate: bool) -> Self {
+        self.name_truncate = truncate;
+        self
+    }
+
+    /// Rewrite argv[0] of the daemon to its basename, eg. `/usr/sbin/mydaemon` becomes `mydaemon` in the command
+    /// line `ps` shows, [`Daemon::name`] only changes the short name. The arguments can't be moved without
+    /// clobbering the environment stored right after them, so the freed bytes are left as empty arguments.
+    /// Only supported on Linux, elsewhere `start` fails with `UnsupportedOnOS` in the child
+    pub fn argv0_basename(mut self) -> Self {
+        self.argv0_basename = true;
+        self
+    }
+
+    /// Make the child wait until the parent has fully exited, including its post fork parent hook,
+    /// before it writes the pid file, so the pid file only ever names a process once the parent is gone.
+    /// The child notices through a pipe whose write end only the parent holds, processes forked from the
+    /// parent hook without exec keep it open as well. This only applies to the plain fork strategy
+    pub fn wait_for_parent_exit(mut self, wait: bool) -> Self {
+        self.wait_for_parent_exit = wait;
+        self
+    }
+
+    /// Mark the daemon as a child subreaper with `prctl(PR_SET_CHILD_SUBREAPER)`, orphaned descendants are then
+    /// reparented to the daemon rather than to init so a supervisor can reap them all without being pid 1.
+    /// Linux only, on every other os `start` fails with `UnsupportedOnOS`
+    pub fn child_subreaper(mut self, subreaper: bool) -> Self {
+        self.child_subreaper = subreaper;
+        self
+    }
+
+    /// Fork a second time right after `setsid` and let the intermediate process exit, the classic SysV recipe:
+    /// the daemon is then not a session leader and can never acquire a controlling terminal by opening one.
+    /// Everything else, the child hooks and the pid file included, happens in the final process.
+    /// The intermediate process reports the pid of the final one before exiting, so the post fork parent hook
+    /// and [`DaemonOutcome::Parent`] get the pid of the daemon, not of the short lived intermediate process.
+    /// Off by default, it has no effect in the foreground
+    pub fn double_fork(mut self, enable: bool) -> Self {
+        self.double_fork = enable;
+        self
+    }
+
+    /// If the process already is a daemon, that is it was reparented to init and is a session leader
+    /// or has no controlling terminal,
+    /// `start` returns [`DaemonOutcome::AlreadyDaemon`] right away without forking or applying any setting,
+    /// this makes daemonizing twice from idempotent init code harmless.
+    /// Note that under a subreaper the parent pid isn't 1 so the process is never detected as a daemon
+    /// Also makes a second `start` in a process that already started return [`DaemonOutcome::AlreadyDaemon`]
+    /// instead of failing with `AlreadyDaemonized`
+    pub fn skip_if_already_daemonized(mut self, skip: bool) -> Self {
+        self.skip_if_already_daemonized = skip;
+        self
+    }
+
+    /// Choose what the parent does once the daemon is forked, by default it exits with 0 which ends the whole
+    /// program. With [`ParentBehavior::Return`] `start` returns [`DaemonOutcome::Parent`] in the parent instead,
+    /// for using the crate inside larger programs and tests. The post fork parent hook and the parent cleanup
+    /// aren't called then and [`Daemon::wait_for_parent_exit`] is ignored, the parent may also start other daemons
+    pub fn parent_behavior(mut self, behavior: ParentBehavior) -> Self {
+        self.parent_behavior = behavior;
+        self
+    }
+
+    /// Choose how the daemon process is created, see [`ForkStrategy`], re-executing is meant for
+    /// daemons with a very large memory footprint where even a copy on write fork is costly
+    pub fn fork_strategy(mut self, strategy: ForkStrategy) -> Self {
+        self.fork_strategy = strategy;
+        self
+    }
+
+    /// When a debugger or `strace` is attached stay in the foreground: no fork and no new session,
+    /// every other step is applied as usual and a warning is printed before the streams are redirected.
+    /// This keeps the daemon in the process being debugged instead of vanishing into a child,
+    /// the detection reads `TracerPid` from `/proc/self/status` so it only works on Linux
+    pub fn debug_friendly(mut self) -> Self {
+        self.debug_friendly = true;
+        self
+    }
+
+    /// Stay in the foreground, without fork nor new session, when a service supervisor started the process
+    /// and daemonize fully when it was launched from a shell, so one binary fits both. A supervisor is detected
+    /// by the variables systemd sets (`INVOCATION_ID`, `NOTIFY_SOCKET`) or s6 ones (`S6_*`) while stdin isn't
+    /// a terminal, they leak into shells of desktop sessions, or by a parent process named `runsv`,
+    /// `s6-supervise` or `supervise`. Operators override the detection with `DAEMONIZE_ME_SUPERVISED` set
+    /// to `1` to stay in the foreground or `0` to daemonize
+    pub fn auto_detect_supervisor(mut self) -> Self {
+        self.auto_detect_supervisor = true;
+        self
+    }
+
+    /// Run `program` with `args` before detaching, while stdin and the terminal are still attached so it
+    /// can prompt (eg. an askpass helper), and capture its stdout as a [`Credential`]. The credential is
+    /// handed to [`Daemon::setup_post_init_credential_hook`].
+    /// It runs right before forking rather than in the child, once the parent exits the child loses the
+    /// terminal to the shell. With the re-exec strategy it runs in the re-executed child instead.
+    /// `start` fails with `CredentialCommand` if the command can't be run or exits unsuccessfully
+    pub fn credential_command<P: AsRef<OsStr>, A: AsRef<OsStr>>(mut self, program: P, args: &[A]) -> Self {
+        let args = args.iter().map(|arg| arg.as_ref().to_owned()).collect();
+        self.credential_command = Some((program.as_ref().to_owned(), args));
+        self
+    }
+
+    pub fn setup_pre_fork_hook<F: Fn(i32) + 'a>(mut self, pre_fork_hook: F) -> Self {
+        self.before_fork_hook = Some(Rc::new(pre_fork_hook));
+        self
+    }
+
+    /// Like [`Daemon::setup_pre_fork_hook`] but whatever the hook returns is kept for the child, eg. a large
+    /// read only dataset loaded once and shared with the daemon through copy on write instead of being
+    /// loaded again after the fork. The payload is handed to [`Daemon::setup_post_fork_child_payload_hook`]
+    /// and to [`Daemon::setup_post_init_payload_hook`].
+    /// It runs after the pre fork hook, with the re-exec strategy it runs in the re-executed child
+    /// as memory can't be shared across exec
+    pub fn setup_pre_fork_payload_hook<F: Fn(i32) -> Box<dyn Any> + 'a>(mut self, pre_fork_hook: F) -> Self {
+        self.before_fork_payload_hook = Some(Rc::new(pre_fork_hook));
+        self
+    }
+
+    /// Runs in the parent with the parent and child pids, it may diverge to carry on with some work in the
+    /// parent, when it returns the parent exits with status 0. Any return value is ignored so diverging
+    /// functions (`-> !`) are accepted as well
+    pub fn setup_post_fork_parent_hook<R, F: Fn(i32, i32) -> R + 'a>(mut self, post_fork_parent_hook: F) -> Self {
+        self.after_fork_parent_hook = Some(Rc::new(move |parent_pid, child_pid| {
+            post_fork_parent_hook(parent_pid, child_pid);
+        }));
+        self
+    }
+
+    /// Called in the parent right before it exits, this gives the parent a chance to release
+    /// resources without having to provide a full parent hook, it is not called if a parent hook is set
+    pub fn parent_cleanup<F: Fn() + 'a>(mut self, cleanup: F) -> Self {
+        self.parent_cleanup = Some(Rc::new(cleanup));
+        self
+    }
+
+    /// Runs in the child right after the fork, where only async-signal-safe calls are safe if the parent
+    /// had other threads, log with [`crate::raw_log`] rather than `println!`
+    pub fn setup_post_fork_child_hook<F: Fn(i32, i32) + 'a>(mut self, post_fork_child_hook: F) -> Self {
+        self.after_fork_child_hook = Some(Rc::new(post_fork_child_hook));
+        self
+    }
+
+    /// Like [`Daemon::setup_post_fork_child_hook`] with the value returned by the pre fork payload hook,
+    /// it is called right after the plain child hook when both are set
+    pub fn setup_post_fork_child_payload_hook<F: Fn(i32, i32, Option<&dyn Any>) + 'a>(
+        mut self,
+        post_fork_child_hook: F,
+    ) -> Self {
+        self.after_fork_child_payload_hook = Some(Rc::new(post_fork_child_hook));
+        self
+    }
+
+    /// Runs after every fork level with the level, counted from 1, and the new pid, in the process that
+    /// carries on: after the first fork in the intermediate process when [`Daemon::double_fork`] is set and
+    /// after the second one in the daemon, to make the fork chain visible. A re-executed child counts as
+    /// level 1, nothing is called in the foreground. The same caveats as for the post fork child hook apply
+    pub fn on_fork<F: Fn(u8, Pid) + 'a>(mut self, hook: F) -> Self {
+        self.on_fork_hook = Some(Rc::new(hook));
+        self
+    }
+
+    /// Runs in the daemon right after setuid succeeded, before the work dir is entered and before the post init
+    /// hook, with the effective uid and gid so it can check the drop really happened or open what the daemon user
+    /// may open. It is only called when a user and group are set
+    pub fn setup_post_drop_hook<F: Fn(u32, u32) + 'a>(mut self, hook: F) -> Self {
+        self.after_drop_hook = Some(Rc::new(hook));
+        self
+    }
+
+    /// Runs in the daemon once it is fully initialized, `data` is handed to it as is. Closures can capture
+    /// what they need instead, `data` is then usually `None`
+    pub fn setup_post_init_hook<F: Fn(Option<&dyn Any>) + 'a>(mut self, post_fork_child_hook: F,
+                                data: Option<&'a dyn Any>) -> Self {
+        self.after_init_hook = Some(Rc::new(post_fork_child_hook));
+        self.after_init_hook_data = data;
+        self
+    }
+
+    /// Like [`Daemon::setup_post_init_hook`] with the value returned by the pre fork payload hook,
+    /// it is called right after the plain post init hook when both are set
+    pub fn setup_post_init_payload_hook<F: Fn(Option<&dyn Any>) + 'a>(mut self, post_init_hook: F) -> Self {
+        self.after_init_payload_hook = Some(Rc::new(post_init_hook));
+        self
+    }
+
+    /// Runs in the daemon after the post init hooks with the [`Credential`] captured by
+    /// [`Daemon::credential_command`], it is not called when no credential command is set
+    pub fn setup_post_init_credential_hook<F: Fn(&Credential) + 'a>(mut self, post_init_hook: F) -> Self {
+        self.after_init_credential_hook = Some(Rc::new(post_init_hook));
+        self
+    }
+
+    /// Render the configuration as the command line flags a wrapper would take, eg.
+    /// `--user svc --group svc --pid-file /run/x.pid --umask 027`, to document or reproduce how a daemon was set up.
+    /// Options left at their default are omitted except the umask. Hooks can't be rendered,
+    /// the ones set are only named by a final `--hooks` flag
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut flag = |name: &str, value: Option<String>| {
+            args.push(format!("--{}", name));
+            args.extend(value);
+        };
+        let join = |ids: &[u32]| ids.iter().map(u32::to_string).collect::<Vec<_>>().join(",");
+        if let Some(name) = &self.name {
+            flag("name", Some(name.to_string_lossy().into_owned()));
+        }
+        if let Some(root) = &self.chroot {
+            flag("chroot", Some(root.display().to_string()));
+        }
+        for (source, target, fstype, flags) in &self.tmpfs_mounts {
+            flag("tmpfs-mount", Some(format!("{}:{}:{}:{:#x}", source, target.display(), fstype, flags)));
+        }
+        if self.chdir != Path::new("/") {
+            flag("work-dir", Some(self.chdir.display().to_string()));
+        }
+        if let Some(pid_file) = &self.pid_file {
+            flag(if self.lock_pid_file { "pid-file-locked" } else { "pid-file" }, Some(pid_file.display().to_string()));
+        }
+        let switches = [
+            ("chown-pid-file", self.chown_pid_file),
+            ("pid-file-history", self.pid_file_append_history),
+            ("pid-file-relative-to-launch-dir", self.pid_file_relative_to_launch_dir),
+            ("pid-file-after-init", self.pid_file_after_init),
+            ("pid-file-newline", self.pid_file_newline),
+            ("lockfile", self.use_lockfile),
+            ("hand-over-pid-file-lock", self.hand_over_pid_file_lock),
+        ];
+        for (name, _) in switches.iter().filter(|(_, set)| *set) {
+            flag(name, None);
+        }
+        if let Some(width) = self.pid_file_zero_pad {
+            flag("pid-file-zero-pad", Some(width.to_string()));
+        }
+        if self.pid_file_timing == PidFileTiming::ReservedBeforeFork {
+            flag("pid-file-timing", Some("reserved-before-fork".to_owned()));
+        }
+        if let Some(interval) = self.pid_file_heartbeat {
+            flag("pid-file-heartbeat", Some(format!("{}ms", interval.as_millis())));
+        }
+        if let Some(ready_file) = &self.ready_file {
+            flag("ready-file", Some(ready_file.display().to_string()));
+        }
+        if let Some((path, mode, owner)) = &self.admin_socket {
+            flag("admin-socket", Some(path.display().to_string()));
+            flag("admin-socket-mode", Some(format!("{:03o}", mode)));
+            if let Some((uid, gid)) = owner {
+                flag("admin-socket-owner", Some(format!("{}:{}", uid, gid)));
+            }
+        }
+        #[cfg(feature = "json")]
+        if let Some(status_file) = &self.status_file {
+            flag("status-file", Some(status_file.display().to_string()));
+        }
+        if let Some(user) = &self.user {
+            flag("user", Some(user.name.clone()));
+        }
+        if let Some(user) = &self.deferred_user {
+            flag("user-deferred", Some(user.clone()));
+        }
+        if let Some(group) = &self.group {
+            flag("group", Some(group.name.clone()));
+        }
+        if !self.supplementary_groups.is_empty() {
+            let names: Vec<&str> = self.supplementary_groups.iter().map(|group| group.name.as_str()).collect();
+            flag("supplementary-groups", Some(names.join(",")));
+        }
+        if !self.capabilities.is_empty() {
+            let names: Vec<&str> = self.capabilities.iter().map(|capability| capability.as_str()).collect();
+            flag("keep-capabilities", Some(names.join(",")));
+        }
+        if let Some(user) = &self.require_launch_user {
+            flag("require-launch-user", Some(user.clone()));
+        }
+        if self.privilege_mode == PrivilegeMode::Temporary {
+            flag("privilege-mode", Some(String::from("temporary")));
+        }
+        if let Some(ids) = self.resuid {
+            flag("resuid", Some(join(&ids)));
+        }
+        if let Some(ids) = self.resgid {
+            flag("resgid", Some(join(&ids)));
+        }
+        for limit in &self.rlimits {
+            let name = match limit.when {
+                RlimitPhase::BeforePrivilegeDrop => "rlimit",
+                RlimitPhase::AfterPrivilegeDrop => "rlimit-after-drop",
+            };
+            flag(name, Some(format!("{:?}={}:{}", limit.resource, Limit(limit.soft), Limit(limit.hard))));
+        }
+        if let Some(class) = &self.login_class {
+            flag("login-class", Some(class.clone()));
+        }
+        if let Some(profile) = &self.apparmor_profile {
+            flag("apparmor-profile", Some(profile.clone()));
+        }
+        flag("umask", Some(format!("{:03o}", self.umask)));
+        if let Some(mask) = self.file_creation_umask {
+            flag("file-creation-umask", Some(format!("{:03o}", mask)));
+        }
+        for (name, stdio) in [("stdin", &self.stdin), ("stdout", &self.stdout), ("stderr", &self.stderr)] {
+            let arg = stdio.to_arg();
+            if arg != "null" {
+                flag(name, Some(arg));
+            }
+        }
+        if !self.keep_fds.is_empty() {
+            let fds: Vec<_> = self.keep_fds.iter().map(RawFd::to_string).collect();
+            flag("keep-fds", Some(fds.join(",")));
+        }
+        // only the keys, the values are often secrets that must not show up in argv
+        for (key, _) in &self.env {
+            flag("env", Some(key.to_string_lossy().into_owned()));
+        }
+        if let Some(mode) = self.stdio_buffering {
+            flag("stdio-buffering", Some(format!("{:?}", mode).to_lowercase()));
+        }
+        if let Some(signal) = self.ring_buffer_dump_signal {
+            flag("dump-ring-buffers-on", Some(signal.as_str().to_owned()));
+        }
+        if let Some((signal, timeout)) = self.flush_stdio_on_signal {
+            flag("flush-stdio-on", Some(format!("{}:{}ms", signal.as_str(), timeout.as_millis())));
+        }
+        if self.fork_strategy == ForkStrategy::Reexec {
+            flag("fork-strategy", Some(String::from("reexec")));
+        }
+        match self.parent_behavior {
+            ParentBehavior::Exit(0) => (),
+            ParentBehavior::Exit(code) => flag("parent-exit-code", Some(code.to_string())),
+            ParentBehavior::Return => flag("parent-return", None),
+        }
+        if let Some((program, program_args)) = &self.credential_command {
+            let command: Vec<_> = std::iter::once(program).chain(program_args).map(|arg| arg.to_string_lossy()).collect();
+            flag("credential-command", Some(command.join(" ")));
+        }
+        let switches = [
+            ("name-truncate", self.name_truncate),
+            ("argv0-basename", self.argv0_basename),
+            ("require-valid-account", self.require_valid_account),
+            ("no-new-privs", self.no_new_privs),
+            ("verify-groups", self.verify_groups),
+            ("no-stdio-redirect", self.no_stdio_redirect),
+            ("close-all-fds", self.close_all_fds),
+            ("clear-env", self.clear_env),
+            ("stdio-fallback-to-null", self.stdio_fallback_to_null),
+            ("verify-devnull", self.verify_devnull),
+            ("panic-hook", self.install_panic_hook),
+            ("skip-if-already-daemonized", self.skip_if_already_daemonized),
+            ("debug-friendly", self.debug_friendly),
+            ("auto-detect-supervisor", self.auto_detect_supervisor),
+            ("wait-for-parent-exit", self.wait_for_parent_exit),
+            ("child-subreaper", self.child_subreaper),
+            ("foreground", self.foreground),
+            ("double-fork", self.double_fork),
+        ];
+        for (name, _) in switches.iter().filter(|(_, set)| *set) {
+            flag(name, None);
+        }
+        let hooks = [
+            ("before_fork", self.before_fork_hook.is_some()),
+            ("before_fork_payload", self.before_fork_payload_hook.is_some()),
+            ("after_fork_parent", self.after_fork_parent_hook.is_some()),
+            ("parent_cleanup", self.parent_cleanup.is_some()),
+            ("after_fork_child", self.after_fork_child_hook.is_some()),
+            ("after_fork_child_payload", self.after_fork_child_payload_hook.is_some()),
+            ("on_fork", self.on_fork_hook.is_some()),
+            ("after_drop", self.after_drop_hook.is_some()),
+            ("after_init", self.after_init_hook.is_some()),
+            ("after_init_payload", self.after_init_payload_hook.is_some()),
+            ("after_init_credential", self.after_init_credential_hook.is_some()),
+        ];
+        let hooks: Vec<&str> = hooks.iter().filter(|(_, set)| *set).map(|(name, _)| *name).collect();
+        if !hooks.is_empty() {
+            flag("hooks", Some(hooks.join(",")));
+        }
+        args
+    }
+
+    /// List the operations `start` would perform with this configuration, in order and with every id
+    /// and path resolved, without performing any of them, for reviewing what a daemon does as root.
+    /// A deferred user is looked up right away so the plan reflects the identity databases visible now.
+    /// Fails like `start` would on an incomplete user and group pair
+    pub fn plan(&self) -> Result<Vec<DaemonStep>> {
+        let user = match &self.deferred_user {
+            Some(name) => Some(User::try_from(name.as_str())?),
+            None => self.user.clone(),
+        };
+        let mut resolved = self.clone();
+        resolved.user = user;
+        resolved.check_user_group_pair()?;
+        if resolved.pid_file_relative_to_launch_dir {
+            if let Some(pid_file) = resolved.pid_file.take() {
+                match std::env::current_dir() {
+                    Ok(launch_dir) => resolved.pid_file = Some(launch_dir.join(pid_file)),
+                    Err(e) => return Err(DaemonError::LaunchDir(e)),
+                }
+            }
+        }
+        let foreground =
+            self.foreground || (self.debug_friendly && is_traced()) || (self.auto_detect_supervisor && is_supervised());
+        // the pid file is written once in the work dir, a deferred write inside a chroot sees it below the new root
+        let pid_file = resolved.pid_file.as_ref().map(|pid_file| resolved.work_dir_path().join(pid_file));
+        let deferred_pid_file = match &pid_file {
+            Some(pid_file) if self.chroot.is_some() => Some(resolved.pid_file_in_chroot(pid_file)),
+            _ => pid_file.clone(),
+        };
+        let pid_file_steps = |steps: &mut Vec<DaemonStep>, pid_file: &Option<PathBuf>| {
+            if let Some(pid_file) = pid_file {
+                steps.push(DaemonStep::WritePidFile(pid_file.clone()));
+                if let (Some(user), Some(group), true) = (&resolved.user, &self.group, self.chown_pid_file) {
+                    steps.push(DaemonStep::ChownPidFile { path: pid_file.clone(), uid: user.id, gid: group.id });
+                }
+            }
+        };
+
+        let mut steps = Vec::new();
+        match &pid_file {
+            Some(pid_file) if self.lock_pid_file => steps.push(DaemonStep::LockPidFile(pid_file.clone())),
+            Some(pid_file) if self.use_lockfile => steps.push(DaemonStep::LockPidFile(lock_file_path(pid_file))),
+            _ => (),
+        }
+        if let Some((path, _, _)) = &self.admin_socket {
+            steps.push(DaemonStep::BindAdminSocket(path.clone()));
+        }
+        if let Some((program, _)) = &self.credential_command {
+            steps.push(DaemonStep::RunCredentialCommand(program.clone()));
+        }
+        if self.pid_file_timing == PidFileTiming::ReservedBeforeFork {
+            pid_file_steps(&mut steps, &pid_file);
+        }
+        match self.fork_strategy {
+            _ if foreground => (),
+            ForkStrategy::Fork => steps.push(DaemonStep::Fork),
+            ForkStrategy::Reexec => steps.push(DaemonStep::Reexec),
+        }
+        if self.close_all_fds {
+            steps.push(DaemonStep::CloseFds { keep: self.keep_fds.clone() });
+        }
+        let double_fork = self.double_fork && !foreground;
+        if double_fork {
+            steps.push(DaemonStep::Setsid);
+            steps.push(DaemonStep::Fork);
+        }
+        if !self.no_stdio_redirect {
+            steps.push(DaemonStep::RedirectStdio);
+        }
+        if let Some(name) = &self.name {
+            steps.push(DaemonStep::SetProcName(name.clone()));
+        }
+        if self.argv0_basename {
+            steps.push(DaemonStep::SetArgv0Basename);
+        }
+        steps.push(DaemonStep::Umask(self.umask));
+        if !foreground && !double_fork {
+            steps.push(DaemonStep::Setsid);
+        }
+        if self.child_subreaper {
+            steps.push(DaemonStep::SetChildSubreaper);
+        }
+        steps.push(DaemonStep::Chdir(resolved.work_dir_path()));
+        if !self.pid_file_after_init {
+            pid_file_steps(&mut steps, &pid_file);
+        }
+        if let Some(class) = &self.login_class {
+            steps.push(DaemonStep::SetLoginClass(class.clone()));
+        }
+        let rlimit_steps = |phase: RlimitPhase| {
+            self.rlimits.iter().filter(move |limit| limit.when == phase).map(|limit| DaemonStep::SetRlimit {
+                resource: limit.resource,
+                soft: limit.soft,
+                hard: limit.hard,
+            })
+        };
+        steps.extend(rlimit_steps(RlimitPhase::BeforePrivilegeDrop));
+        let chroot_steps = |steps: &mut Vec<DaemonStep>| {
+            if !self.tmpfs_mounts.is_empty() {
+                steps.push(DaemonStep::UnshareMountNamespace);
+            }
+            for (source, target, fstype, _) in &self.tmpfs_mounts {
+                steps.push(DaemonStep::Mount { source: source.clone(), target: target.clone(), fstype: fstype.clone() });
+            }
+            if let Some(root) = &self.chroot {
+                steps.push(DaemonStep::Chroot(root.clone()));
+                steps.push(DaemonStep::Chdir(PathBuf::from("/")));
+            }
+        };
+        match (&resolved.user, &self.group) {
+            (Some(user), Some(group)) => {
+                steps.push(match (self.resgid, self.privilege_mode) {
+                    (Some(ids), _) => DaemonStep::Setresgid(ids),
+                    (None, PrivilegeMode::Permanent) => DaemonStep::Setgid(group.id),
+                    (None, PrivilegeMode::Temporary) => DaemonStep::Setegid(group.id),
+                });
+                #[cfg(not(target_os = "macos"))]
+                steps.push(DaemonStep::Initgroups { user: user.name.clone(), gid: group.id });
+                if !self.supplementary_groups.is_empty() {
+                    steps.push(DaemonStep::Setgroups(self.supplementary_groups.iter().map(|group| group.id).collect()));
+                }
+                chroot_steps(&mut steps);
+                steps.push(match (self.resuid, self.privilege_mode) {
+                    (Some(ids), _) => DaemonStep::Setresuid(ids),
+                    (None, PrivilegeMode::Permanent) => DaemonStep::Setuid(user.id),
+                    (None, PrivilegeMode::Temporary) => DaemonStep::Seteuid(user.id),
+                });
+                if !self.capabilities.is_empty() {
+                    steps.push(DaemonStep::KeepCapabilities(self.capabilities.clone()));
+                }
+            }
+            _ => {
+                if let Some(ids) = self.resgid {
+                    steps.push(DaemonStep::Setresgid(ids));
+                }
+                chroot_steps(&mut steps);
+                if let Some(ids) = self.resuid {
+                    steps.push(DaemonStep::Setresuid(ids));
+                }
+            }
+        }
+        steps.extend(rlimit_steps(RlimitPhase::AfterPrivilegeDrop));
+        if let Some(profile) = &self.apparmor_profile {
+            steps.push(DaemonStep::ChangeApparmorProfile(profile.clone()));
+        }
+        if self.no_new_privs {
+            steps.push(DaemonStep::SetNoNewPrivs);
+        }
+        steps.push(DaemonStep::Chdir(self.chdir.clone()));
+        if self.pid_file_after_init {
+            pid_file_steps(&mut steps, &deferred_pid_file);
+        }
+        if let Some(ready_file) = &self.ready_file {
+            steps.push(DaemonStep::CreateReadyFile(ready_file.clone()));
+        }
+        Ok(steps)
+    }
+
+    /// Like [`Daemon::start`] but the parent returns [`DaemonOutcome::Parent`] with the pid of the daemon
+    /// instead of exiting, eg. to keep running supervisor logic, this is short for [`ParentBehavior::Return`]
+    pub fn start_and_detach(self) -> Result<DaemonOutcome> {
+        self.parent_behavior(ParentBehavior::Return).start()
+    }
+
+    /// Daemonize ahead of an async runtime, eg. right before `tokio::runtime::Runtime::new()`. Only the thread
+    /// calling fork lives on in the child, so runtimes and anything else spawning threads must be built after
+    /// this returns, in the daemon. On Linux this fails with `ThreadsRunning` before forking when the process
+    /// has more than one thread already, other targets can't tell and skip the check.
+    /// The parent always exits, [`ParentBehavior::Return`] is replaced by an exit with 0, so this only returns
+    /// in the daemon. Use [`Daemon::start`] to get the log handle or the handed over lock, here the lock is
+    /// held until the daemon exits
+    pub fn start_before_runtime(self) -> Result<()> {
+        if let Some(count) = thread_count().filter(|&count| count > 1) {
+            return Err(DaemonError::ThreadsRunning { count });
+        }
+        let daemon = match self.parent_behavior {
+            ParentBehavior::Return => self.parent_behavior(ParentBehavior::Exit(0)),
+            ParentBehavior::Exit(_) => self,
+        };
+        match daemon.start()? {
+            DaemonOutcome::Child { lock, .. } => {
+                // dropping the handed over file would release the lock
+                let _ = lock.map(IntoRawFd::into_raw_fd);
+                Ok(())
+            }
+            // the parent exited, it never gets here
+            DaemonOutcome::Parent { .. } | DaemonOutcome::AlreadyDaemon => Ok(()),
+        }
+    }
+
+    /// Using the parameters set, daemonize the process, this is short for [`Daemon::prepare`] followed by
+    /// [`PreparedDaemon::start`]
+    pub fn start(self) -> Result<DaemonOutcome> {
+        self.prepare()?.start()
+    }
+
+    /// Resolves and checks everything that can be done before forking: the launch user and the process name
+    /// are checked, the umasks validated, the account looked up, the work dir or chroot directory
+    /// canonicalized, the pid file path made absolute and the lock taken. Nothing is forked or run yet,
+    /// so an error here leaves the process as it was and a failed configuration can be fixed and retried
+    pub fn prepare(mut self) -> Result<PreparedDaemon<'a>> {
+        self.validate()?;
+        // A re-executed child carries on from where its parent forked, see `ForkStrategy::Reexec`
+        let reexec_parent = match self.fork_strategy {
+            ForkStrategy::Reexec => take_reexec_parent(),
+            ForkStrategy::Fork => None,
+        };
+        let parent_pid = reexec_parent.unwrap_or_else(getpid);
+        if self.pid_file_relative_to_launch_dir {
+            // an absolute pid file path survives all the chdir calls that follow
+            if let Some(pid_file) = self.pid_file.take() {
+                match std::env::current_dir() {
+                    Ok(launch_dir) => self.pid_file = Some(launch_dir.join(pid_file)),
+                    Err(e) => return Err(DaemonError::LaunchDir(e)),
+                }
+            }
+        }
+        // a re-executed child was launched by this crate, the original process checked already
+        if reexec_parent.is_none() {
+            self.check_launch_user()?;
+        }
+        // the name is only set in the child, a bad one is caught while the caller can still see the error
+        if let Some(name) = &self.name {
+            proc_name_cstring(name, self.name_truncate)?;
+        }
+        if let Some(&fd) = self.keep_fds.iter().find(|&&fd| fcntl(fd, FcntlArg::F_GETFD).is_err()) {
+            return Err(DaemonError::KeepFd { fd });
+        }
+        if cfg!(not(target_os = "linux")) && !self.tmpfs_mounts.is_empty() {
+            return Err(DaemonError::UnsupportedOnOS);
+        }
+        let account = match (&self.user, &self.group) {
+            (Some(user), Some(_)) if self.deferred_user.is_none() => match PasswdRecord::lookup_record_by_id(user.id) {
+                Ok(record) if self.require_valid_account && is_account_locked(&record) => {
+                    return Err(DaemonError::AccountLocked)
+                }
+                Ok(record) => Some(record.pw_name),
+                Err(e) => return Err(DaemonError::InvalidUser { names: vec![user.name.clone()], source: Some(Box::new(e)) }),
+            },
+            _ => None,
+        };
+        // inside a chroot the work dir only exists once the root is changed
+        match &self.chroot {
+            Some(root) => match root.canonicalize() {
+                Ok(root) => self.chroot = Some(root),
+                Err(e) => return Err(DaemonError::Chroot(errno_of(&e))),
+            },
+            None => match self.chdir.canonicalize() {
+                Ok(work_dir) => self.chdir = work_dir,
+                Err(e) => return Err(DaemonError::ChDir(errno_of(&e))),
+            },
+        }
+        // the daemon moves into the directory opened here with fchdir, swapping the path afterwards has no effect
+        let work_dir = match &self.chroot {
+            Some(_) => None,
+            None => match OpenOptions::new().read(true).custom_flags(libc::O_DIRECTORY).open(&self.chdir) {
+                Ok(dir) => Some(dir),
+                Err(e) => return Err(DaemonError::ChDir(errno_of(&e))),
+            },
+        };
+        // a relative pid file is created in the work dir, before entering the chroot
+        if let Some(pid_file) = self.pid_file.take() {
+            self.pid_file = Some(self.work_dir_path().join(pid_file));
+        }
+        if STARTED.load(Ordering::SeqCst) {
+            return if self.skip_if_already_daemonized {
+                Ok(PreparedDaemon::already_daemon(self))
+            } else {
+                Err(DaemonError::AlreadyDaemonized)
+            };
+        }
+        if reexec_parent.is_none() && self.skip_if_already_daemonized && is_daemonized(parent_pid) {
+            return Ok(PreparedDaemon::already_daemon(self));
+        }
+        if self.debug_friendly && is_traced() {
+            // Best effort, a closed stderr must not make the start fail
+            let _ = raw_log(libc::STDERR_FILENO, b"daemonize-me: a tracer is attached, staying in the foreground without forking\n");
+            self.foreground = true;
+        }
+        if self.auto_detect_supervisor && is_supervised() {
+            self.foreground = true;
+        }
+
+        // Take the lock before forking so a second instance fails in the process that launched it
+        let lockfile = match &self.pid_file {
+            Some(pid_file) if self.lock_pid_file => Some(lock_file(pid_file)?),
+            Some(pid_file) if self.use_lockfile => Some(lock_file(&lock_file_path(pid_file))?),
+            _ => None,
+        };
+        let admin_socket = match &self.admin_socket {
+            Some((path, mode, owner)) => Some(bind_admin_socket(path, *mode, *owner)?),
+            None => None,
+        };
+        Ok(PreparedDaemon { daemon: self, reexec_parent, account, work_dir, lockfile, admin_socket, already_daemon: false })
+    }
+
+    /// Runs the parent side of the fork, it diverges so the parent can never reach the child initialization
+    fn finish_parent(&self, parent_pid: Pid, child_pid: Pid, code: i32) -> ! {
+        if let Some(hook) = &self.after_fork_parent_hook {
+            hook(parent_pid.as_raw(), child_pid.as_raw());
+        } else if let Some(cleanup) = &self.parent_cleanup {
+            cleanup();
+        }
+        exit(code)
+    }
+
+    /// Turns the freshly forked child into the daemon, must only be called in the child
+    fn init_child(
+        mut self,
+        parent_pid: Pid,
+        mut pid: Pid,
+        parent_exit: Option<RawFd>,
+        daemon_pid_report: Option<RawFd>,
+        account: Option<OsString>,
+        work_dir: Option<File>,
+    ) -> Result<DaemonOutcome> {
+        #[cfg(feature = "json")]
+        let started_at = std::time::SystemTime::now();
+        // resolve options to concrete values to please the borrow checker
+        let has_pid_file = self.pid_file.is_some();
+        let pid_file_path = match &self.pid_file {
+            Some(path) => path.clone(),
+            None => Path::new("").to_path_buf(),
+        };
+
+        if let Some(hook) = self.on_fork_hook.as_ref().filter(|_| !self.foreground) {
+            hook(1, pid);
+        }
+        // The second fork comes first so threads and the other hooks only ever start in the final process
+        let double_fork = self.double_fork && !self.foreground;
+        if double_fork {
+            let forked = match setsid() {
+                Ok(_) => unsafe { fork() }.map_err(|errno| DaemonError::Fork(errno.into())),
+                Err(errno) => Err(DaemonError::SetSid(errno)),
+            };
+            match forked {
+                // the intermediate process shares the stdio buffers and the lock, it must not run any cleanup
+                Ok(ForkResult::Parent { child }) => {
+                    if let Some(fd) = daemon_pid_report {
+                        let _ = nix::unistd::write(fd, &child.as_raw().to_ne_bytes());
+                    }
+                    unsafe { libc::_exit(0) }
+                }
+                Ok(ForkResult::Child) => (),
+                Err(e) => {
+                    // the parent stops waiting for the pid of the daemon at EOF
+                    if let Some(fd) = daemon_pid_report {
+                        let _ = close(fd);
+                    }
+                    return Err(e);
+                }
+            }
+            if let Some(fd) = daemon_pid_report {
+                let _ = close(fd);
+            }
+            pid = getpid();
+            if let Some(hook) = &self.on_fork_hook {
+                hook(2, pid);
+            }
+        }
+
+        // The environment is settled before the redirection starts threads that may read it, eg. for the journal
+        if self.clear_env {
+            for (key, _) in std::env::vars_os() {
+                std::env::remove_var(key);
+            }
+        }
+        for (key, value) in &self.env {
+            std::env::set_var(key, value);
+        }
+        // The hook writes to whatever fd 2 is when a panic happens, it goes in before the redirection
+        // only so the ring buffer panic hook installed by the redirection chains onto it
+        if self.install_panic_hook {
+            install_stderr_panic_hook();
+        }
+        // Set up stream redirection as early as possible
+        if !self.no_stdio_redirect {
+            redirect_stdio(&self.stdin, &self.stdout, &self.stderr, self.stdio_fallback_to_null, self.verify_devnull)?;
+        }
+        for &fd in &self.keep_fds {
+            keep_open_on_exec(fd)?;
+        }
+        if let Some(signal) = self.ring_buffer_dump_signal {
+            dump_on_signal(signal)?;
+        }
+        if let Some((signal, timeout)) = self.flush_stdio_on_signal {
+            flush_on_signal(signal, timeout)?;
+        }
+        if let Some(mode) = self.stdio_buffering {
+            set_stdio_buffering(mode.as_raw())?;
+        }
+        if let Some(hook) = &self.after_fork_child_hook {
+            hook(parent_pid.as_raw(), pid.as_raw());
+        }
+        if let Some(hook) = &self.after_fork_child_payload_hook {
+            hook(parent_pid.as_raw(), pid.as_raw(), self.payload.as_deref());
+        }
+        // the post fork child hook may have made the identity databases visible
+        if let Some(name) = self.deferred_user.take() {
+            self.user = Some(User::try_from(name.as_str())?);
+        }
+
+        self.check_user_group_pair()?;
 
         if let Some(proc_name) = &self.name {
-            match set_proc_name(proc_name.as_ref()) {
+            match set_proc_name(proc_name.as_ref(), self.name_truncate) {
                 Ok(()) => (),
                 Err(e) => return Err(e)
             }
         }
+        if self.argv0_basename {
+            set_argv0_basename()?;
+        }
         // Set the umask either to 0o027 (rwxr-x---) or provided value
         let umask_mode = match Mode::from_bits(self.umask as _) {
             Some(mode) => mode,
             None => return Err(DaemonError::InvalidUmaskBits),
         };
         umask(umask_mode);
-        // Set the sid so the process isn't session orphan
-        if let Err(_) = setsid() {
-            return Err(DaemonError::SetSid);
+        let file_umask_mode = match self.file_creation_umask {
+            Some(mask) => match Mode::from_bits(mask as _) {
+                Some(mode) => Some(mode),
+                None => return Err(DaemonError::InvalidUmaskBits),
+            },
+            None => None,
+        };
+        // Set the sid so the process isn't session orphan, in the foreground the process keeps its session
+        // after a double fork the session exists already, a new one would make the daemon its leader again
+        if !self.foreground && !double_fork {
+            if let Err(errno) = setsid() {
+                return Err(DaemonError::SetSid(errno));
+            }
+        }
+        if self.child_subreaper {
+            set_child_subreaper()?;
+        }
+        let entered = match &work_dir {
+            Some(dir) => fchdir(dir.as_raw_fd()),
+            None => chdir::<Path>(self.work_dir_path().as_path()),
+        };
+        if let Err(errno) = entered {
+            return Err(DaemonError::ChDir(errno));
         };
-        if let Err(_) = chdir::<Path>(self.chdir.as_path()) {
-            return Err(DaemonError::ChDir);
+        // Remember which directory this is so it can be checked again from inside the chroot
+        let work_dir_id = match &self.chroot {
+            Some(_) => match stat(".") {
+                Ok(st) => Some((st.st_dev, st.st_ino)),
+                Err(errno) => return Err(DaemonError::ChDir(errno)),
+            },
+            None => None,
         };
         pid = getpid();
-        // create pid file and if configured to, chmod it
-        if has_pid_file {
-            // chmod of the pid file is deferred to after checking for the presence of the user and group
-            let pid_file = &pid_file_path;
-            match File::create(pid_file) {
-                Ok(mut fp) => {
-                    if let Err(_) = fp.write_all(pid.to_string().as_ref()) {
-                        return Err(DaemonError::WritePid);
-                    }
-                }
-                Err(_) => return Err(DaemonError::WritePid),
-            };
+        if let Some(read_end) = parent_exit {
+            wait_for_pipe_close(read_end);
+        }
+        if has_pid_file && !self.pid_file_after_init {
+            self.write_pid_file(&pid_file_path, pid, file_umask_mode)?;
+            if let Some(interval) = self.pid_file_heartbeat {
+                start_heartbeat(&pid_file_path, interval)?;
+            }
+        }
+        // a relative pid file is in the work dir, the daemon moves to chdir before the deferred write,
+        // after entering a chroot the host path no longer leads there
+        let deferred_pid_file = if !has_pid_file || !self.pid_file_after_init {
+            None
+        } else if self.chroot.is_some() {
+            Some(self.pid_file_in_chroot(&pid_file_path))
+        } else {
+            match std::env::current_dir() {
+                Ok(work_dir) => Some(work_dir.join(&pid_file_path)),
+                Err(e) => return Err(DaemonError::OpenPid(e)),
+            }
+        };
+        if let Some(class) = &self.login_class {
+            set_login_class(class)?;
         }
+        apply_rlimits(&self.rlimits, RlimitPhase::BeforePrivilegeDrop)?;
         // Drop privileges and chown the requested files
         if self.user.is_some() && self.group.is_some() {
-            let user = match self.user {
+            let user = match &self.user {
                 Some(user) => Uid::from_raw(user.id),
-                None => return Err(InvalidUser),
+                None => return Err(InvalidUser { names: Vec::new(), source: None }),
             };
 
-            let uname = match PasswdRecord::lookup_record_by_id(user.as_raw()) {
-                Ok(record) => record.pw_name,
-                Err(_) => return Err(DaemonError::InvalidUser),
+            // the account was looked up by prepare unless the user was deferred
+            let uname = match account {
+                Some(name) => name,
+                None => match PasswdRecord::lookup_record_by_id(user.as_raw()) {
+                    Ok(record) => {
+                        if self.require_valid_account && is_account_locked(&record) {
+                            return Err(DaemonError::AccountLocked);
+                        }
+                        record.pw_name
+                    }
+                    Err(e) => return Err(DaemonError::InvalidUser { names: vec![user.to_string()], source: Some(Box::new(e)) }),
+                },
             };
 
-            let gr = match self.group {
+            let gr = match &self.group {
                 Some(grp) => Gid::from_raw(grp.id),
-                None => return Err(InvalidGroup),
+                None => return Err(InvalidGroup { names: Vec::new(), source: None }),
             };
 
-            if self.chown_pid_file && has_pid_file {
-                match chown(&pid_file_path, Some(user), Some(gr)) {
-                    Ok(_) => (),
-                    Err(_) => return Err(DaemonError::ChownPid),
-                };
-            }
-
-            match setgid(gr) {
-                Ok(_) => (),
-                Err(_) => return Err(DaemonError::SetGid),
+            let gid_result = match (self.resgid, self.privilege_mode) {
+                (Some(ids), _) => set_resgid(ids),
+                (None, PrivilegeMode::Permanent) => setgid(gr).map_err(DaemonError::SetGid),
+                (None, PrivilegeMode::Temporary) => setegid(gr).map_err(DaemonError::SetGid),
             };
+            gid_result?;
+            #[cfg(target_os = "macos")]
+                if self.verify_groups || !self.supplementary_groups.is_empty() {
+                    return Err(DaemonError::UnsupportedOnOS);
+                }
             #[cfg(not(target_os = "macos"))]
-                {
-                    let u_cstr = match CString::new(uname) {
+                let expected_groups = {
+                    let u_cstr = match CString::new(uname.into_vec()) {
                         Ok(cstr) => cstr,
-                        Err(_) => return Err(DaemonError::SetGid),
+                        Err(_) => return Err(DaemonError::SetGid(nix::errno::Errno::EINVAL)),
                     };
                     match initgroups(&u_cstr, gr) {
                         Ok(_) => (),
-                        Err(_) => return Err(DaemonError::InitGroups),
+                        Err(errno) => return Err(DaemonError::InitGroups(errno.into())),
                     };
-                }
-            match setuid(user) {
-                Ok(_) => (),
-                Err(_) => return Err(DaemonError::SetUid),
+                    let extra_groups: Vec<Gid> = self.supplementary_groups.iter().map(|group| Gid::from_raw(group.id)).collect();
+                    if !extra_groups.is_empty() {
+                        add_supplementary_groups(&extra_groups)?;
+                    }
+                    if self.verify_groups {
+                        match getgrouplist(&u_cstr, gr) {
+                            Ok(mut groups) => {
+                                groups.extend(extra_groups);
+                                Some(groups)
+                            }
+                            Err(_) => return Err(DaemonError::GroupVerificationFailed),
+                        }
+                    } else {
+                        None
+                    }
+                };
+            self.enter_chroot()?;
+            // only a drop from root to ids that all differ from it is meant to be permanent
+            let permanent_drop = geteuid().is_root() && match (self.resuid, self.privilege_mode) {
+                (Some(ids), _) => ids.iter().all(|&id| id != 0),
+                (None, PrivilegeMode::Permanent) => !user.is_root(),
+                (None, PrivilegeMode::Temporary) => false,
+            };
+            if !self.capabilities.is_empty() {
+                set_keep_capabilities(true)?;
+            }
+            let uid_result = match (self.resuid, self.privilege_mode) {
+                (Some(ids), _) => set_resuid(ids),
+                (None, PrivilegeMode::Permanent) => setuid(user).map_err(DaemonError::SetUid),
+                (None, PrivilegeMode::Temporary) => seteuid(user).map_err(DaemonError::SetUid),
+            };
+            uid_result?;
+            if permanent_drop {
+                verify_privilege_drop()?;
+            }
+            if !self.capabilities.is_empty() {
+                restrict_capabilities(&self.capabilities)?;
+                set_keep_capabilities(false)?;
+            }
+            #[cfg(not(target_os = "macos"))]
+                if let Some(expected) = expected_groups {
+                    verify_supplementary_groups(&expected)?;
+                }
+            if let Some(hook) = &self.after_drop_hook {
+                hook(geteuid().as_raw(), getegid().as_raw());
+            }
+        } else {
+            if let Some(ids) = self.resgid {
+                set_resgid(ids)?;
+            }
+            self.enter_chroot()?;
+            if let Some(ids) = self.resuid {
+                set_resuid(ids)?;
             }
         };
+        apply_rlimits(&self.rlimits, RlimitPhase::AfterPrivilegeDrop)?;
+        if let Some(profile) = &self.apparmor_profile {
+            change_apparmor_profile(profile)?;
+        }
+        if self.no_new_privs {
+            set_no_new_privs()?;
+        }
         // chdir
         let chdir_path = self.chdir.to_owned();
-        match chdir::<Path>(chdir_path.as_ref()) {
+        let entered = match work_dir {
+            Some(dir) => fchdir(dir.as_raw_fd()),
+            None => chdir::<Path>(chdir_path.as_ref()),
+        };
+        match entered {
             Ok(_) => (),
-            Err(_) => return Err(DaemonError::ChDir),
+            Err(_) if self.chroot.is_some() => return Err(DaemonError::ChrootWorkDir),
+            Err(errno) => return Err(DaemonError::ChDir(errno)),
         };
+        if let Some(expected) = work_dir_id {
+            match stat(".") {
+                Ok(st) if (st.st_dev, st.st_ino) == expected => (),
+                _ => return Err(DaemonError::ChrootWorkDir),
+            }
+        }
 
-        // Now this process should be a daemon, we run the hook and return or just return
-        if let Some(hook) = self.after_init_hook {
+        // Now this process should be a daemon, we run the hook and signal readiness
+        if let Some(hook) = &self.after_init_hook {
             hook(self.after_init_hook_data);
-            Ok(())
+        }
+        if let Some(hook) = &self.after_init_payload_hook {
+            hook(self.payload.as_deref());
+        }
+        if let (Some(hook), Some(credential)) = (&self.after_init_credential_hook, &self.credential) {
+            hook(credential);
+        }
+        if let Some(pid_file) = &deferred_pid_file {
+            self.write_pid_file(pid_file, pid, file_umask_mode)?;
+            if let Some(interval) = self.pid_file_heartbeat {
+                start_heartbeat(pid_file, interval)?;
+            }
+        }
+        #[cfg(feature = "json")]
+        if let Some(status_file) = &self.status_file {
+            let status = self.status_json(pid, started_at);
+            if let Err(e) = with_umask(file_umask_mode, || write_file_atomically(status_file, status.as_bytes())) {
+                return Err(DaemonError::WriteStatusFile(e));
+            }
+        }
+        if let Some(ready_file) = &self.ready_file {
+            if let Err(e) = with_umask(file_umask_mode, || File::create(ready_file)) {
+                return Err(DaemonError::CreateReadyFile(e));
+            }
+        }
+        if self.no_stdio_redirect {
+            return Ok(DaemonOutcome::Child { log: LogHandle::default(), lock: None, admin_socket: None });
+        }
+        Ok(DaemonOutcome::Child { log: LogHandle::new(&self.stdout, &self.stderr), lock: None, admin_socket: None })
+    }
+}
+
+impl<'a> PreparedDaemon<'a> {
+    /// A daemon that is already running, `start` returns `DaemonOutcome::AlreadyDaemon` right away
+    fn already_daemon(daemon: Daemon<'a>) -> Self {
+        PreparedDaemon {
+            daemon,
+            reexec_parent: None,
+            account: None,
+            work_dir: None,
+            lockfile: None,
+            admin_socket: None,
+            already_daemon: true,
+        }
+    }
+
+    /// The operations `start` will perform with the resolved configuration, see [`Daemon::plan`]
+    pub fn plan(&self) -> Result<Vec<DaemonStep>> {
+        self.daemon.plan()
+    }
+
+    /// Daemonizes the process, only the credential command, the hooks and the system calls themselves
+    /// can fail from here on
+    pub fn start(self) -> Result<DaemonOutcome> {
+        let PreparedDaemon { mut daemon, reexec_parent, account, work_dir, lockfile, admin_socket, already_daemon } = self;
+        if already_daemon {
+            return Ok(DaemonOutcome::AlreadyDaemon);
+        }
+        let parent_pid = reexec_parent.unwrap_or_else(getpid);
+
+        // The write end of this pipe is only held by the parent, the child sees EOF once it is gone
+        let parent_exit = match (daemon.fork_strategy, reexec_parent) {
+            (ForkStrategy::Fork, None) if daemon.wait_for_parent_exit && !daemon.foreground && daemon.parent_behavior != ParentBehavior::Return => {
+                Some(cloexec_pipe().map_err(DaemonError::ParentExitPipe)?)
+            }
+            _ => None,
+        };
+        // The intermediate process of a double fork writes the pid of the daemon here before exiting
+        let daemon_pid_report = match (daemon.fork_strategy, reexec_parent) {
+            (ForkStrategy::Fork, None) if daemon.double_fork && !daemon.foreground => Some(cloexec_pipe().map_err(DaemonError::ParentExitPipe)?),
+            _ => None,
+        };
+
+        // The original process of a re-exec can't hand the credential or the payload over, the new one makes its own
+        let shares_with_child = match daemon.fork_strategy {
+            ForkStrategy::Fork => true,
+            ForkStrategy::Reexec => reexec_parent.is_some() || daemon.foreground,
+        };
+        if let Some((program, args)) = daemon.credential_command.as_ref().filter(|_| shares_with_child) {
+            daemon.credential = Some(run_credential_command(program, args)?);
+        }
+
+        // If the hook is set call it with the parent pid
+        if let Some(hook) = daemon.before_fork_hook.as_ref().filter(|_| reexec_parent.is_none()) {
+            hook(parent_pid.as_raw());
+        }
+        if let Some(hook) = daemon.before_fork_payload_hook.clone().filter(|_| shares_with_child) {
+            daemon.payload = Some(Rc::from(hook(parent_pid.as_raw())));
+        }
+
+        // Reserved last so nothing fails in between, a re-executed child finds it reserved by the original process
+        let reserved_pid_file = match (&daemon.pid_file, daemon.pid_file_timing, reexec_parent) {
+            (Some(pid_file), PidFileTiming::ReservedBeforeFork, None) => {
+                let mask = daemon.file_creation_umask.and_then(|mask| Mode::from_bits(mask as _));
+                daemon.write_pid_file(pid_file, parent_pid, mask)?;
+                Some(pid_file.clone()).filter(|_| !daemon.pid_file_append_history)
+            }
+            _ => None,
+        };
+        let release_pid_file = || {
+            if let Some(pid_file) = &reserved_pid_file {
+                release_reserved_pid_file(pid_file, parent_pid);
+            }
+        };
+
+        // from here on this process has daemonized or is about to, failures before can be retried
+        STARTED.store(true, Ordering::SeqCst);
+
+        // Fork and if the process is the parent exit gracefully
+        // if the  process is the child just continue execution
+        // this was made unsafe by the nix upstream in between versions
+        // thus the unsafe block is required here
+        let pid = match (daemon.fork_strategy, reexec_parent) {
+            _ if daemon.foreground => getpid(),
+            (_, Some(_)) => getpid(),
+            (ForkStrategy::Fork, None) => match unsafe { fork() } {
+                // the parent never returns from here
+                Ok(ForkResult::Parent { child: cpid }) => {
+                    if let Some((read_end, _)) = parent_exit {
+                        let _ = close(read_end);
+                    }
+                    let cpid = match daemon_pid_report {
+                        Some((read_end, write_end)) => {
+                            let _ = close(write_end);
+                            read_daemon_pid(read_end, cpid)
+                        }
+                        None => cpid,
+                    };
+                    match daemon.parent_behavior {
+                        ParentBehavior::Exit(code) => daemon.finish_parent(parent_pid, cpid, code),
+                        ParentBehavior::Return => return Ok(detached_parent(cpid)),
+                    }
+                }
+                Ok(ForkResult::Child) => {
+                    if let Some((_, write_end)) = parent_exit {
+                        let _ = close(write_end);
+                    }
+                    if let Some((read_end, _)) = daemon_pid_report {
+                        let _ = close(read_end);
+                    }
+                    getpid()
+                }
+                Err(errno) => {
+                    release_pid_file();
+                    return Err(DaemonError::Fork(errno.into()));
+                }
+            },
+            (ForkStrategy::Reexec, None) => {
+                // the new process can't inherit the lock, it takes it again once the parent lets go
+                drop(lockfile);
+                let cpid = match spawn_reexec(parent_pid) {
+                    Ok(cpid) => cpid,
+                    Err(e) => {
+                        release_pid_file();
+                        return Err(e);
+                    }
+                };
+                match daemon.parent_behavior {
+                    ParentBehavior::Exit(code) => daemon.finish_parent(parent_pid, cpid, code),
+                    ParentBehavior::Return => return Ok(detached_parent(cpid)),
+                }
+            }
+        };
+
+        // Everything from here on only ever runs in the child
+        // the fds are closed before the redirection opens any of its own, eg. the ring buffer pipes
+        if daemon.close_all_fds {
+            let mut keep = daemon.keep_fds.clone();
+            keep.extend(lockfile.as_ref().map(AsRawFd::as_raw_fd));
+            keep.extend(work_dir.as_ref().map(AsRawFd::as_raw_fd));
+            keep.extend(admin_socket.as_ref().map(AsRawFd::as_raw_fd));
+            keep.extend(parent_exit.map(|(read_end, _)| read_end));
+            keep.extend(daemon_pid_report.map(|(_, write_end)| write_end));
+            keep.extend([&daemon.stdin, &daemon.stdout, &daemon.stderr].iter().filter_map(|stdio| stdio.raw_fd()));
+            close_fds_from(libc::STDERR_FILENO + 1, &keep);
+        }
+        let hand_over_lock = daemon.hand_over_pid_file_lock;
+        let (parent_exit, daemon_pid_report) = (parent_exit.map(|(read_end, _)| read_end), daemon_pid_report.map(|(_, write_end)| write_end));
+        let outcome = daemon.init_child(parent_pid, pid, parent_exit, daemon_pid_report, account, work_dir);
+        if outcome.is_err() {
+            release_pid_file();
+        }
+        match outcome? {
+            DaemonOutcome::Child { log, .. } if hand_over_lock => Ok(DaemonOutcome::Child { log, lock: lockfile, admin_socket }),
+            DaemonOutcome::Child { log, .. } => {
+                // the lock must be held for as long as the daemon runs
+                if let Some(lockfile) = lockfile {
+                    let _ = lockfile.into_raw_fd();
+                }
+                Ok(DaemonOutcome::Child { log, lock: None, admin_socket })
+            }
+            outcome => Ok(outcome),
+        }
+    }
+}
+
+/// Removes a pid file reserved by `PidFileTiming::ReservedBeforeFork` if it still names the launcher,
+/// once the daemon wrote its own pid the file is left to it
+fn release_reserved_pid_file(pid_file: &Path, launcher: Pid) {
+    if read_current_pid(pid_file).ok() == Some(launcher) {
+        let _ = std::fs::remove_file(pid_file);
+    }
+}
+
+/// Reads the pid of the daemon reported by the intermediate process of a double fork and reaps that process.
+/// If it failed before forking again there is no daemon pid, the intermediate one is returned then
+fn read_daemon_pid(read_end: RawFd, intermediate: Pid) -> Pid {
+    let mut buf = [0u8; 4];
+    let mut filled = 0;
+    while filled < buf.len() {
+        match read(read_end, &mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(nix::errno::Errno::EINTR) => (),
+            Err(_) => break,
+        }
+    }
+    let _ = close(read_end);
+    let _ = nix::sys::wait::waitpid(intermediate, None);
+    if filled == buf.len() {
+        Pid::from_raw(i32::from_ne_bytes(buf))
+    } else {
+        intermediate
+    }
+}
+
+/// The parent kept running by `ParentBehavior::Return` didn't daemonize itself, it may start again
+fn detached_parent(child: Pid) -> DaemonOutcome {
+    STARTED.store(false, Ordering::SeqCst);
+    DaemonOutcome::Parent { child }
+}
+
+/// Returns the parent pid if this process was re-executed by `ForkStrategy::Reexec`,
+/// the marker is removed so it isn't passed on any further
+fn take_reexec_parent() -> Option<Pid> {
+    let parent = std::env::var(REEXEC_PARENT_ENV).ok()?;
+    std::env::remove_var(REEXEC_PARENT_ENV);
+    parent.parse::<i32>().ok().map(Pid::from_raw)
+}
+
+/// Spawns a copy of the current executable with the same arguments that continues as the child
+fn spawn_reexec(parent_pid: Pid) -> Result<Pid> {
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => return Err(DaemonError::Fork(e)),
+    };
+    match std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .env(REEXEC_PARENT_ENV, parent_pid.to_string())
+        .spawn()
+    {
+        Ok(child) => Ok(Pid::from_raw(child.id() as i32)),
+        Err(e) => Err(DaemonError::Fork(e)),
+    }
+}
+
+/// Daemonize the process in one call like the BSD `daemon(3)`: fork with the parent exiting, start a new
+/// session, change to `/` unless `nochdir` is set and point the standard streams at /dev/null unless
+/// `noclose` is set. The umask is left as it is, everything else is what [`Daemon::new`] does by default.
+/// Only the daemon returns, use the [`Daemon`] builder for anything more
+pub fn daemonize(nochdir: bool, noclose: bool) -> Result<()> {
+    let current = umask(Mode::empty());
+    umask(current);
+    let mut daemon = Daemon::new().umask_mode(current);
+    if nochdir {
+        match std::env::current_dir() {
+            Ok(cwd) => daemon = daemon.work_dir(cwd),
+            Err(e) => return Err(DaemonError::ChDir(errno_of(&e))),
+        }
+    }
+    if noclose {
+        daemon = daemon.no_stdio_redirect();
+    }
+    daemon.start().map(|_| ())
+}
+
+/// Checks whether a tracer such as a debugger is attached to the process
+fn is_traced() -> bool {
+    match std::fs::read_to_string("/proc/self/status") {
+        Ok(status) => status
+            .lines()
+            .find_map(|line| line.strip_prefix("TracerPid:"))
+            .map(|pid| pid.trim() != "0")
+            .unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+/// Checks whether a service supervisor started the process, see [`Daemon::auto_detect_supervisor`]
+fn is_supervised() -> bool {
+    match std::env::var(SUPERVISED_ENV).as_deref() {
+        Ok("1") => return true,
+        Ok("0") => return false,
+        _ => (),
+    }
+    let supervisor_env = std::env::var_os("INVOCATION_ID").is_some()
+        || std::env::var_os("NOTIFY_SOCKET").is_some()
+        || std::env::vars_os().any(|(key, _)| key.to_string_lossy().starts_with("S6_"));
+    if supervisor_env && !nix::unistd::isatty(libc::STDIN_FILENO).unwrap_or(false) {
+        return true;
+    }
+    match std::fs::read_to_string(format!("/proc/{}/comm", getppid())) {
+        Ok(comm) => SUPERVISOR_PROCESSES.contains(&comm.trim()),
+        Err(_) => false,
+    }
+}
+
+/// A process is considered a daemon if it was reparented to init and either leads its own session
+/// or has no controlling terminal, the second fork of the double fork recipe gives the latter
+fn is_daemonized(pid: Pid) -> bool {
+    getppid() == Pid::from_raw(1) && (matches!(getsid(None), Ok(sid) if sid == pid) || !has_controlling_terminal())
+}
+
+/// Opening `/dev/tty` only succeeds when the process has a controlling terminal
+fn has_controlling_terminal() -> bool {
+    OpenOptions::new().read(true).write(true).custom_flags(libc::O_NOCTTY).open("/dev/tty").is_ok()
+}
+
+/// An account is considered locked if its password is locked or its shell refuses logins
+fn is_account_locked(record: &PasswdRecord) -> bool {
+    let shell = Path::new(&record.pw_shell).file_name();
+    record.pw_passwd.starts_with('!')
+        || shell == Some(OsStr::new("nologin"))
+        || shell == Some(OsStr::new("false"))
+}
+
+/// The errno of a failed std call, for the errors that carry the errno of the equivalent syscall
+fn errno_of(error: &std::io::Error) -> nix::errno::Errno {
+    nix::errno::Errno::from_i32(error.raw_os_error().unwrap_or(0))
+}
+
+/// Runs `f` with the umask temporarily set to `mask` if one is given
+fn with_umask<R, F: FnOnce() -> R>(mask: Option<Mode>, f: F) -> R {
+    match mask {
+        Some(mask) => {
+            let previous = umask(mask);
+            let result = f();
+            umask(previous);
+            result
+        }
+        None => f(),
+    }
+}
+
+/// Adds `extra` to the supplementary groups set up by `initgroups`
+#[cfg(not(target_os = "macos"))]
+fn add_supplementary_groups(extra: &[Gid]) -> Result<()> {
+    let mut groups = getgroups().map_err(DaemonError::SetGroups)?;
+    for gid in extra {
+        if !groups.contains(gid) {
+            groups.push(*gid);
+        }
+    }
+    setgroups(&groups).map_err(DaemonError::SetGroups)
+}
+
+/// Checks that every expected group is part of the process supplementary groups
+#[cfg(not(target_os = "macos"))]
+fn verify_supplementary_groups(expected: &[Gid]) -> Result<()> {
+    let groups = match getgroups() {
+        Ok(groups) => groups,
+        Err(_) => return Err(DaemonError::GroupVerificationFailed),
+    };
+    if expected.iter().all(|gid| groups.contains(gid)) {
+        Ok(())
+    } else {
+        Err(DaemonError::GroupVerificationFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    use nix::sys::time::{TimeVal, TimeValLike};
+    use nix::sys::wait::waitpid;
+
+    use crate::resolver::fake::FakeResolver;
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("daemonize-me-{}-{}", name, std::process::id()))
+    }
+
+    /// Polls for a file written by a daemon started from a test
+    fn wait_for_file(path: &Path) -> Option<String> {
+        for _ in 0..100 {
+            if let Ok(contents) = std::fs::read_to_string(path) {
+                if !contents.is_empty() {
+                    return Some(contents);
+                }
+            }
+            sleep(Duration::from_millis(50));
+        }
+        None
+    }
+
+    fn exit_parent(_: i32, _: i32) -> ! {
+        unsafe { libc::_exit(0) }
+    }
+
+    /// Starts the daemon built by `build` from a forked launcher so the test process survives,
+    /// the daemon exits as soon as `start` returns and the launcher exit is awaited before returning
+    fn launch<'a, F: FnOnce() -> Daemon<'a>>(build: F) {
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                waitpid(child, None).unwrap();
+            }
+            ForkResult::Child => {
+                let result = build().setup_post_fork_parent_hook(exit_parent).start();
+                unsafe { libc::_exit(if result.is_ok() { 0 } else { 1 }) }
+            }
+        }
+    }
+
+    fn write_pid_marker(ctx: Option<&dyn Any>) {
+        if let Some(path) = ctx.and_then(|ctx| ctx.downcast_ref::<PathBuf>()) {
+            let _ = std::fs::write(path, getpid().to_string());
+        }
+    }
+
+    fn write_euid(ctx: Option<&dyn Any>) {
+        if let Some(path) = ctx.and_then(|ctx| ctx.downcast_ref::<PathBuf>()) {
+            let _ = std::fs::write(path, nix::unistd::geteuid().to_string());
+        }
+    }
+
+    #[test]
+    /// Asserts that a deferred user name is resolved in the child and privileges are dropped to it
+    fn test_user_name_deferred() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let marker = temp_path("deferred-user");
+        let _ = std::fs::remove_file(&marker);
+        let nobody = User::try_from(65534).unwrap();
+        launch(|| {
+            Daemon::new()
+                .user_name_deferred(&nobody.name)
+                .group(Group::try_from(65534).unwrap())
+                .setup_post_init_hook(write_euid, Some(&marker))
+        });
+        let euid = wait_for_file(&marker).expect("the daemon never reported its euid");
+        assert_eq!(euid, "65534");
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the post drop hook gets the ids dropped to and runs before the post init hook
+    fn test_post_drop_hook() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let marker = temp_path("post-drop");
+        let _ = std::fs::remove_file(&marker);
+        let ids = Rc::new(std::cell::RefCell::new(None));
+        let drop_ids = Rc::clone(&ids);
+        let init_marker = marker.clone();
+        launch(move || {
+            Daemon::new()
+                .user(User::try_from(65534).unwrap())
+                .group(Group::try_from(65534).unwrap())
+                .setup_post_drop_hook(move |uid, gid| {
+                    *drop_ids.borrow_mut() = Some(format!("{}:{}", uid, gid));
+                })
+                .setup_post_init_hook(move |_| {
+                    if let Some(ids) = ids.borrow().as_ref() {
+                        let _ = std::fs::write(&init_marker, ids);
+                    }
+                }, None)
+        });
+        let ids = wait_for_file(&marker).expect("the post drop hook never ran before the post init hook");
+        assert_eq!(ids, "65534:65534");
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    fn write_nofile_limit(ctx: Option<&dyn Any>) {
+        if let Some(path) = ctx.and_then(|ctx| ctx.downcast_ref::<PathBuf>()) {
+            if let Ok((_, hard)) = nix::sys::resource::getrlimit(Resource::RLIMIT_NOFILE) {
+                let _ = std::fs::write(path, format!("{:?}", hard));
+            }
+        }
+    }
+
+    #[test]
+    /// Asserts that a hard limit raised before dropping privileges survives into the unprivileged daemon
+    fn test_rlimit_raised_before_drop() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        // containers often run root without CAP_SYS_RESOURCE, then only lowering the limit can be checked
+        let can_raise = match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                matches!(waitpid(child, None).unwrap(), nix::sys::wait::WaitStatus::Exited(_, 0))
+            }
+            ForkResult::Child => {
+                let raised = nix::sys::resource::setrlimit(Resource::RLIMIT_NOFILE, Some(1024), Some(4096)).is_ok()
+                    && nix::sys::resource::setrlimit(Resource::RLIMIT_NOFILE, Some(1024), Some(8192)).is_ok();
+                unsafe { libc::_exit(if raised { 0 } else { 1 }) }
+            }
+        };
+        let hard_limit = if can_raise { 8192 } else { 2048 };
+        let marker = temp_path("rlimit");
+        let _ = std::fs::remove_file(&marker);
+        launch(|| {
+            // start from a lower hard limit so the daemon has something to raise
+            nix::sys::resource::setrlimit(Resource::RLIMIT_NOFILE, Some(1024), Some(4096)).unwrap();
+            Daemon::new()
+                .user(User::try_from(65534).unwrap())
+                .group(Group::try_from(65534).unwrap())
+                .rlimit(Resource::RLIMIT_NOFILE, Some(1024), Some(hard_limit), RlimitPhase::BeforePrivilegeDrop)
+                .setup_post_init_hook(write_nofile_limit, Some(&marker))
+        });
+        let hard = wait_for_file(&marker).expect("the unprivileged daemon never reported its limit");
+        assert_eq!(hard, format!("Some({})", hard_limit));
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the post init hook runs in the daemon and never in the parent
+    fn test_after_init_runs_in_child_only() {
+        let marker = temp_path("after-init");
+        let _ = std::fs::remove_file(&marker);
+        launch(|| Daemon::new().setup_post_init_hook(write_pid_marker, Some(&marker)));
+        let daemon_pid = wait_for_file(&marker).expect("the post init hook never ran");
+        assert_ne!(daemon_pid, getpid().to_string());
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    fn write_resuid(ctx: Option<&dyn Any>) {
+        if let Some(path) = ctx.and_then(|ctx| ctx.downcast_ref::<PathBuf>()) {
+            if let (Ok(uids), Ok(gids)) = (nix::unistd::getresuid(), nix::unistd::getresgid()) {
+                let ids = [uids.real.as_raw(), uids.effective.as_raw(), uids.saved.as_raw(),
+                    gids.real.as_raw(), gids.effective.as_raw(), gids.saved.as_raw()];
+                let _ = std::fs::write(path, format!("{:?}", ids));
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Asserts that explicit id triples are applied as given, keeping root as the saved ids
+    fn test_set_resuid_resgid() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let marker = temp_path("resuid");
+        let _ = std::fs::remove_file(&marker);
+        launch(|| {
+            Daemon::new()
+                .set_resgid(65534, 65534, 0)
+                .set_resuid(65534, 65534, 0)
+                .setup_post_init_hook(write_resuid, Some(&marker))
+        });
+        let ids = wait_for_file(&marker).expect("the daemon never reported its ids");
+        assert_eq!(ids, "[65534, 65534, 0, 65534, 65534, 0]");
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    /// Writes the supplementary groups of the process to the path given as the hook data
+    #[cfg(target_os = "linux")]
+    fn write_groups(ctx: Option<&dyn Any>) {
+        if let Some(path) = ctx.and_then(|ctx| ctx.downcast_ref::<PathBuf>()) {
+            if let Ok(groups) = getgroups() {
+                let mut gids: Vec<u32> = groups.iter().map(|gid| gid.as_raw()).collect();
+                gids.sort_unstable();
+                let _ = std::fs::write(path, format!("{:?}", gids));
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Asserts that extra supplementary groups are added on top of those of the user
+    fn test_supplementary_groups() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let (user, group) = match (User::try_from(65534), Group::try_from(65534)) {
+            (Ok(user), Ok(group)) => (user, group),
+            _ => return,
+        };
+        let marker = temp_path("supplementary-groups");
+        let _ = std::fs::remove_file(&marker);
+        launch(|| {
+            Daemon::new()
+                .user(user.clone())
+                .group(group.clone())
+                .supplementary_groups(vec![Group { id: 4242, name: String::from("extra") }])
+                .verify_groups(true)
+                .setup_post_init_hook(write_groups, Some(&marker))
+        });
+        let groups = wait_for_file(&marker).expect("the daemon never reported its groups");
+        assert_eq!(groups, "[4242, 65534]");
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Asserts that only the kept capabilities survive the privilege drop, ambient ones included
+    fn test_keep_capabilities() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let (user, group) = match (User::try_from(65534), Group::try_from(65534)) {
+            (Ok(user), Ok(group)) => (user, group),
+            _ => return,
+        };
+        assert!(matches!(Daemon::new().keep_capabilities(vec![Capability::NetBindService]).validate(),
+            Err(DaemonError::CapabilitiesWithoutUser)));
+        let marker = temp_path("keep-capabilities");
+        let _ = std::fs::remove_file(&marker);
+        let hook_marker = marker.clone();
+        launch(|| {
+            Daemon::new()
+                .user(user.clone())
+                .group(group.clone())
+                .keep_capabilities(vec![Capability::NetBindService])
+                .setup_post_init_hook(move |_| {
+                    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+                    let sets: Vec<&str> = status.lines()
+                        .filter(|line| ["CapPrm:", "CapEff:", "CapAmb:"].iter().any(|set| line.starts_with(set)))
+                        .filter_map(|line| line.split_whitespace().nth(1))
+                        .collect();
+                    let _ = std::fs::write(&hook_marker, sets.join(" "));
+                }, None)
+        });
+        let sets = wait_for_file(&marker).expect("the daemon never reported its capabilities");
+        assert_eq!(sets, "0000000000000400 0000000000000400 0000000000000400");
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that a deferred pid file is missing while the after_init hook runs and written after it
+    fn test_pid_file_after_init() {
+        let marker = temp_path("pid-after-init");
+        let pid_file = temp_path("pid-after-init-pid");
+        let _ = std::fs::remove_file(&marker);
+        let _ = std::fs::remove_file(&pid_file);
+        let (hook_marker, hook_pid_file) = (marker.clone(), pid_file.clone());
+        launch(|| {
+            Daemon::new().pid_file(&pid_file, None).pid_file_after_init(true).setup_post_init_hook(move |_| {
+                let _ = std::fs::write(&hook_marker, format!("{} {}", getpid(), hook_pid_file.exists()));
+            }, None)
+        });
+        let contents = wait_for_file(&marker).expect("the daemon never initialized");
+        let (pid, existed) = contents.split_once(' ').unwrap();
+        assert_eq!(existed, "false");
+        assert_eq!(wait_for_file(&pid_file).expect("the pid file was never written"), pid);
+        std::fs::remove_file(&marker).unwrap();
+        std::fs::remove_file(&pid_file).unwrap();
+    }
+
+    #[test]
+    /// Asserts that a reserved pid file names the launcher right after the fork and the daemon once it runs
+    fn test_pid_file_reserved_before_fork() {
+        let marker = temp_path("pid-reserved");
+        let pid_file = temp_path("pid-reserved-pid");
+        let _ = std::fs::remove_file(&marker);
+        let _ = std::fs::remove_file(&pid_file);
+        let (fork_pid_file, init_pid_file, init_marker) = (pid_file.clone(), pid_file.clone(), marker.clone());
+        launch(|| {
+            let reserved = Rc::new(std::cell::Cell::new(false));
+            let fork_reserved = Rc::clone(&reserved);
+            Daemon::new()
+                .pid_file(&pid_file, None)
+                .pid_file_timing(PidFileTiming::ReservedBeforeFork)
+                .setup_post_fork_child_hook(move |parent_pid, _| {
+                    let reserved = read_current_pid(&fork_pid_file).map(|reserved| reserved.as_raw() == parent_pid);
+                    fork_reserved.set(reserved.unwrap_or(false));
+                })
+                .setup_post_init_hook(move |_| {
+                    let current = read_current_pid(&init_pid_file).map(|pid| pid.to_string()).unwrap_or_default();
+                    let _ = std::fs::write(&init_marker, format!("{} {} {}", reserved.get(), getpid(), current));
+                }, None)
+        });
+        let contents = wait_for_file(&marker).expect("the daemon never initialized");
+        let parts: Vec<&str> = contents.split(' ').collect();
+        assert_eq!(parts[0], "true", "the pid file didn't name the launcher before the fork");
+        assert_eq!(parts[1], parts[2]);
+        std::fs::remove_file(&marker).unwrap();
+        std::fs::remove_file(&pid_file).unwrap();
+
+        // a daemon failing to initialize takes the reservation naming the launcher with it
+        launch(|| {
+            Daemon::new()
+                .pid_file(&pid_file, None)
+                .pid_file_timing(PidFileTiming::ReservedBeforeFork)
+                .user_name_deferred("daemonize-me-no-such-user")
+                .group(Group::try_from(0).unwrap())
+        });
+        let released = (0..100).any(|_| {
+            sleep(Duration::from_millis(50));
+            !pid_file.exists()
+        });
+        assert!(released, "the reserved pid file was left behind");
+    }
+
+    #[test]
+    /// Asserts that the parent exits with the configured code
+    fn test_parent_exit_code() {
+        use nix::sys::wait::WaitStatus;
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 3));
+            }
+            ForkResult::Child => {
+                let _ = Daemon::new().parent_behavior(ParentBehavior::Exit(3)).start();
+                unsafe { libc::_exit(0) }
+            }
+        }
+    }
+
+    #[test]
+    /// Asserts that the detached parent gets the pid of the daemon back and keeps running,
+    /// from a forked launcher so the test process never sees `start` flag it as started
+    fn test_start_and_detach() {
+        let marker = temp_path("detach");
+        let parent_marker = temp_path("detach-parent");
+        let _ = std::fs::remove_file(&marker);
+        let _ = std::fs::remove_file(&parent_marker);
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                waitpid(child, None).unwrap();
+            }
+            ForkResult::Child => {
+                let hook_marker = marker.clone();
+                let daemon = Daemon::new().setup_post_init_hook(move |_| {
+                    let _ = std::fs::write(&hook_marker, getpid().to_string());
+                }, None);
+                if let Ok(DaemonOutcome::Parent { child }) = daemon.start_and_detach() {
+                    // the parent may start again once detached
+                    let restartable = !STARTED.load(Ordering::SeqCst);
+                    let _ = std::fs::write(&parent_marker, format!("{} {}", child, restartable));
+                }
+                unsafe { libc::_exit(0) }
+            }
+        }
+        let reported = wait_for_file(&marker).expect("the daemon never initialized");
+        let parent = wait_for_file(&parent_marker).expect("the parent never got the daemon pid");
+        assert_eq!(parent, format!("{} true", reported));
+        std::fs::remove_file(&marker).unwrap();
+        std::fs::remove_file(&parent_marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that a detached parent of a double fork gets the pid of the daemon, not the intermediate one
+    fn test_double_fork_reports_daemon_pid() {
+        let marker = temp_path("double-fork-detach");
+        let parent_marker = temp_path("double-fork-detach-parent");
+        let _ = std::fs::remove_file(&marker);
+        let _ = std::fs::remove_file(&parent_marker);
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                waitpid(child, None).unwrap();
+            }
+            ForkResult::Child => {
+                let hook_marker = marker.clone();
+                let daemon = Daemon::new().double_fork(true).setup_post_init_hook(move |_| {
+                    let _ = std::fs::write(&hook_marker, getpid().to_string());
+                }, None);
+                if let Ok(DaemonOutcome::Parent { child }) = daemon.start_and_detach() {
+                    let _ = std::fs::write(&parent_marker, child.to_string());
+                }
+                unsafe { libc::_exit(0) }
+            }
+        }
+        let reported = wait_for_file(&marker).expect("the daemon never initialized");
+        let parent = wait_for_file(&parent_marker).expect("the parent never got the daemon pid");
+        assert_eq!(parent, reported);
+        std::fs::remove_file(&marker).unwrap();
+        std::fs::remove_file(&parent_marker).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Asserts that only the daemon returns from `start_before_runtime` and that it refuses to fork a threaded process
+    fn test_start_before_runtime() {
+        let marker = temp_path("before-runtime");
+        let _ = std::fs::remove_file(&marker);
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                waitpid(child, None).unwrap();
+            }
+            ForkResult::Child => {
+                // the parent exits even when asked to return
+                if Daemon::new().parent_behavior(ParentBehavior::Return).start_before_runtime().is_ok() {
+                    let threads = thread_count().unwrap_or(0);
+                    let _ = std::fs::write(&marker, format!("{} {}", getpid(), threads));
+                }
+                unsafe { libc::_exit(0) }
+            }
+        }
+        let written = wait_for_file(&marker).expect("the daemon never returned");
+        let (pid, threads) = written.split_once(' ').unwrap();
+        assert_ne!(pid, getpid().to_string());
+        assert_eq!(threads, "1");
+        std::fs::remove_file(&marker).unwrap();
+
+        let (stop, parked) = std::sync::mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || parked.recv());
+        assert!(matches!(Daemon::new().start_before_runtime(), Err(DaemonError::ThreadsRunning { .. })));
+        drop(stop);
+        let _ = thread.join();
+    }
+
+    #[test]
+    /// Asserts that a second instance can't lock the pid file of a running daemon nor truncate it
+    fn test_pid_file_locked() {
+        let pid_file = temp_path("locked-pid");
+        let _ = std::fs::remove_file(&pid_file);
+        launch(|| {
+            Daemon::new().pid_file_locked(&pid_file).setup_post_init_hook(|_| {
+                sleep(Duration::from_secs(2));
+            }, None)
+        });
+        let pid = wait_for_file(&pid_file).expect("the daemon never wrote its pid file");
+        assert!(matches!(lock_file(&pid_file), Err(DaemonError::AlreadyRunning)));
+        assert_eq!(std::fs::read_to_string(&pid_file).unwrap(), pid);
+        std::fs::remove_file(&pid_file).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Asserts that the daemon keeps the inherited streams when the redirection is turned off
+    fn test_no_stdio_redirect() {
+        let marker = temp_path("no-stdio-redirect");
+        let _ = std::fs::remove_file(&marker);
+        let hook_marker = marker.clone();
+        launch(|| {
+            Daemon::new().no_stdio_redirect().setup_post_init_hook(move |_| {
+                let targets: Vec<_> = (0..3).map(|fd| std::fs::read_link(format!("/proc/self/fd/{}", fd)).ok()).collect();
+                let _ = std::fs::write(&hook_marker, format!("{:?}", targets));
+            }, None)
+        });
+        let inherited: Vec<_> = (0..3).map(|fd| std::fs::read_link(format!("/proc/self/fd/{}", fd)).ok()).collect();
+        let contents = wait_for_file(&marker).expect("the daemon never initialized");
+        assert_eq!(contents, format!("{:?}", inherited));
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that a kept listening socket is inherited without close on exec and still accepts connections
+    fn test_keep_fds() {
+        use std::io::{Read, Write};
+        use std::net::{TcpListener, TcpStream};
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let fd = listener.as_raw_fd();
+        let marker = temp_path("keep-fds");
+        let _ = std::fs::remove_file(&marker);
+        let hook_marker = marker.clone();
+        launch(|| {
+            Daemon::new().keep_fds(vec![fd]).setup_post_init_hook(move |_| {
+                let cloexec = fcntl(fd, FcntlArg::F_GETFD).map(|flags| FdFlag::from_bits_truncate(flags).contains(FdFlag::FD_CLOEXEC));
+                let inherited = unsafe { TcpListener::from_raw_fd(fd) };
+                if let Ok((mut stream, _)) = inherited.accept() {
+                    let _ = stream.write_all(b"hello");
+                }
+                let _ = std::fs::write(&hook_marker, format!("{:?}", cloexec));
+            }, None)
+        });
+        let mut reply = String::new();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        // the test still holds the listener so connecting succeeds without the daemon, which may never answer
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "hello");
+        assert_eq!(wait_for_file(&marker).expect("the daemon never initialized"), "Ok(false)");
+        std::fs::remove_file(&marker).unwrap();
+
+        assert!(matches!(Daemon::new().keep_fds(vec![fd, 4095]).prepare(), Err(DaemonError::KeepFd { fd: 4095 })));
+        assert!(matches!(Daemon::new().keep_fds(vec![fd, 1]).validate(), Err(DaemonError::KeepStdioFd { fd: 1 })));
+    }
+
+    #[test]
+    /// Asserts that an inherited file is closed in the daemon while the kept one and the lock survive
+    fn test_close_all_fds() {
+        use std::os::unix::io::AsRawFd;
+
+        let leaked = File::open("/dev/null").unwrap();
+        let kept = File::open("/dev/null").unwrap();
+        let (leaked_fd, kept_fd) = (leaked.as_raw_fd(), kept.as_raw_fd());
+        let pid_file = temp_path("close-all-fds-pid");
+        let marker = temp_path("close-all-fds");
+        let _ = std::fs::remove_file(&marker);
+        let hook_marker = marker.clone();
+        launch(|| {
+            Daemon::new().close_all_fds(true).keep_fds(vec![kept_fd]).pid_file(&pid_file, None).use_lockfile(true)
+                .setup_post_init_hook(move |_| {
+                    let open = [leaked_fd, kept_fd].map(|fd| fcntl(fd, FcntlArg::F_GETFD).is_ok());
+                    let _ = std::fs::write(&hook_marker, format!("{:?}", open));
+                    sleep(Duration::from_millis(500));
+                }, None)
+        });
+        assert_eq!(wait_for_file(&marker).expect("the daemon never initialized"), "[false, true]");
+        let lock_path = lock_file_path(&pid_file);
+        assert!(matches!(lock_file(&lock_path), Err(DaemonError::AlreadyRunning)));
+        std::fs::remove_file(&marker).unwrap();
+        sleep(Duration::from_millis(600));
+        let _ = std::fs::remove_file(&pid_file);
+        let _ = std::fs::remove_file(&lock_path);
+    }
+
+    #[test]
+    /// Asserts that a cleared environment only holds the variables given to the daemon
+    fn test_clear_env() {
+        let marker = temp_path("clear-env");
+        let _ = std::fs::remove_file(&marker);
+        let hook_marker = marker.clone();
+        launch(|| {
+            Daemon::new().clear_env(true).env("DAEMONIZE_ME_TEST_ENV", "kept").setup_post_init_hook(move |_| {
+                let vars: Vec<_> = std::env::vars_os().map(|(key, value)| format!("{}={}", key.to_string_lossy(), value.to_string_lossy())).collect();
+                let _ = std::fs::write(&hook_marker, vars.join("\n"));
+            }, None)
+        });
+        let vars = wait_for_file(&marker).expect("the daemon never initialized");
+        assert_eq!(vars, "DAEMONIZE_ME_TEST_ENV=kept");
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the admin socket is bound with its mode and owner by prepare and replaces a stale one only
+    fn test_admin_socket() {
+        use std::os::unix::fs::MetadataExt;
+
+        let path = temp_path("admin.sock");
+        let _ = std::fs::remove_file(&path);
+        // only root may give the socket away
+        let owner = if getuid().is_root() { (65534, 65534) } else { (getuid().as_raw(), nix::unistd::getgid().as_raw()) };
+        let prepared = Daemon::new().admin_socket(&path, 0o660, Some(owner)).prepare().unwrap();
+        let meta = std::fs::symlink_metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.mode() & 0o777, 0o660);
+        assert_eq!((meta.uid(), meta.gid()), owner);
+        assert!(prepared.admin_socket.is_some());
+        drop(prepared);
+        // the socket of the dropped listener is stale now
+        assert!(Daemon::new().admin_socket(&path, 0o600, None).prepare().is_ok());
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "not a socket").unwrap();
+        let refused = Daemon::new().admin_socket(&path, 0o600, None).prepare();
+        assert!(matches!(refused, Err(DaemonError::AdminSocket { .. })));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    /// Asserts that `daemonize` returns in a session leader that kept the working directory when asked to
+    fn test_daemonize() {
+        let marker = temp_path("daemonize");
+        let _ = std::fs::remove_file(&marker);
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                waitpid(child, None).unwrap();
+            }
+            ForkResult::Child => {
+                if daemonize(true, true).is_ok() {
+                    let leader = getsid(None).map(|sid| sid == getpid()).unwrap_or(false);
+                    let cwd = std::env::current_dir().map(|cwd| cwd.display().to_string()).unwrap_or_default();
+                    let _ = std::fs::write(&marker, format!("{} {}", leader, cwd));
+                }
+                unsafe { libc::_exit(0) }
+            }
+        }
+        let written = wait_for_file(&marker).expect("the daemon never returned");
+        let cwd = std::env::current_dir().unwrap().canonicalize().unwrap();
+        assert_eq!(written, format!("true {}", cwd.display()));
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the fork hook is called once per fork level and last with the pid of the daemon
+    fn test_on_fork() {
+        use std::io::Write;
+
+        let levels = temp_path("on-fork-levels");
+        let marker = temp_path("on-fork");
+        let _ = std::fs::remove_file(&levels);
+        let _ = std::fs::remove_file(&marker);
+        let (hook_levels, hook_marker) = (levels.clone(), marker.clone());
+        launch(|| {
+            Daemon::new().double_fork(true).on_fork(move |level, pid| {
+                if let Ok(mut file) = std::fs::OpenOptions::new().create(true).append(true).open(&hook_levels) {
+                    let _ = writeln!(file, "{} {}", level, pid);
+                }
+            }).setup_post_init_hook(move |_| {
+                let _ = std::fs::write(&hook_marker, getpid().to_string());
+            }, None)
+        });
+        let daemon = wait_for_file(&marker).expect("the daemon never initialized");
+        let levels_written = std::fs::read_to_string(&levels).unwrap();
+        let chain: Vec<_> = levels_written.lines().map(|line| line.split_once(' ').unwrap()).collect();
+        assert_eq!(chain.len(), 2);
+        assert_eq!((chain[0].0, chain[1].0), ("1", "2"));
+        assert_eq!(chain[1].1, daemon);
+        assert_ne!(chain[0].1, daemon);
+        std::fs::remove_file(&levels).unwrap();
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the handed over lock is held until the daemon drops the file
+    fn test_hand_over_pid_file_lock() {
+        let pid_file = temp_path("hand-over-pid");
+        let (locked, released) = (temp_path("hand-over-locked"), temp_path("hand-over-released"));
+        for path in [&pid_file, &locked, &released] {
+            let _ = std::fs::remove_file(path);
+        }
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                waitpid(child, None).unwrap();
+            }
+            ForkResult::Child => {
+                let daemon = Daemon::new().pid_file_locked(&pid_file).hand_over_pid_file_lock(true);
+                if let Ok(DaemonOutcome::Child { lock: Some(lock), .. }) = daemon.setup_post_fork_parent_hook(exit_parent).start() {
+                    let _ = std::fs::write(&locked, "locked");
+                    sleep(Duration::from_millis(300));
+                    drop(lock);
+                    let _ = std::fs::write(&released, "released");
+                }
+                unsafe { libc::_exit(0) }
+            }
+        }
+        wait_for_file(&locked).expect("the daemon never got its lock");
+        assert!(matches!(lock_file(&pid_file), Err(DaemonError::AlreadyRunning)));
+        wait_for_file(&released).expect("the daemon never released its lock");
+        assert!(lock_file(&pid_file).is_ok());
+        for path in [&pid_file, &locked, &released] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    /// Asserts that a double forked daemon isn't a session leader and writes its own pid to the pid file
+    fn test_double_fork() {
+        let marker = temp_path("double-fork");
+        let pid_file = temp_path("double-fork-pid");
+        let _ = std::fs::remove_file(&marker);
+        let hook_marker = marker.clone();
+        launch(|| {
+            Daemon::new().double_fork(true).pid_file(&pid_file, None).setup_post_init_hook(move |_| {
+                let sid = getsid(None).map(|sid| sid.to_string()).unwrap_or_default();
+                let _ = std::fs::write(&hook_marker, format!("{} {}", getpid(), sid));
+            }, None)
+        });
+        let contents = wait_for_file(&marker).expect("the double forked daemon never initialized");
+        let (pid, sid) = contents.split_once(' ').unwrap();
+        assert_ne!(pid, sid);
+        assert_eq!(std::fs::read_to_string(&pid_file).unwrap().trim(), pid);
+        std::fs::remove_file(&marker).unwrap();
+        std::fs::remove_file(&pid_file).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the lookup setters resolve right away and fail on unknown accounts
+    fn test_user_group_by_name() {
+        let daemon = Daemon::new().user_by_name("root").unwrap().group_by_id(0).unwrap();
+        assert_eq!(daemon.user.as_ref().map(|user| user.id), Some(0));
+        assert_eq!(daemon.group.as_ref().map(|group| group.name.as_str()), Some("root"));
+        assert!(Daemon::new().user_by_name("daemonize-me-no-such-user").is_err());
+        assert!(Daemon::new().group_by_name("daemonize-me-no-such-group").is_err());
+    }
+
+    #[test]
+    /// Asserts that the primary group is taken from the gid of the user, whatever the user is named
+    fn test_group_from_user_primary_gid() {
+        let resolver = FakeResolver::default().with_user("svc", 113, 120).with_group("services", 120);
+        let svc = User { id: 113, name: "svc".to_owned() };
+        let daemon = Daemon::new().user(svc).group_from_user_primary_gid_with(&resolver).unwrap();
+        assert_eq!(daemon.group.as_ref().map(|group| (group.id, group.name.as_str())), Some((120, "services")));
+        assert!(matches!(Daemon::new().group_from_user_primary_gid(), Err(DaemonError::InvalidUser { .. })));
+    }
+
+    #[test]
+    /// Asserts that the configuration renders as flags, hooks only being named
+    fn test_to_args() {
+        let daemon = Daemon::new()
+            .user(User { id: 113, name: "svc".to_owned() })
+            .group(Group { id: 120, name: "svc".to_owned() })
+            .pid_file("/run/x.pid", Some(true))
+            .rlimit(Resource::RLIMIT_NOFILE, Some(1024), None, RlimitPhase::BeforePrivilegeDrop)
+            .double_fork(true)
+            .setup_post_init_hook(|_| (), None);
+        let expected = [
+            "--pid-file", "/run/x.pid", "--chown-pid-file", "--user", "svc", "--group", "svc",
+            "--rlimit", "RLIMIT_NOFILE=1024:unlimited", "--umask", "027", "--double-fork", "--hooks", "after_init",
+        ];
+        assert_eq!(daemon.to_args(), expected);
+        assert_eq!(Daemon::new().to_args(), ["--umask", "027"]);
+        assert_eq!(Daemon::new().env("API_TOKEN", "secret").to_args(), ["--umask", "027", "--env", "API_TOKEN"]);
+        let reserved = Daemon::new().pid_file("/run/x.pid", None).pid_file_timing(PidFileTiming::ReservedBeforeFork);
+        assert_eq!(reserved.to_args(), ["--pid-file", "/run/x.pid", "--pid-file-timing", "reserved-before-fork", "--umask", "027"]);
+    }
+
+    #[test]
+    /// Asserts that the supervisor variables keep the daemon in the foreground unless overridden,
+    /// in a forked child so the variables don't leak into the other tests
+    fn test_auto_detect_supervisor() {
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), nix::sys::wait::WaitStatus::Exited(child, 0));
+            }
+            ForkResult::Child => {
+                let forks = || {
+                    let steps = Daemon::new().auto_detect_supervisor().plan().unwrap_or_default();
+                    steps.contains(&DaemonStep::Fork)
+                };
+                // the variables only count when stdin isn't a terminal
+                let devnull = File::open("/dev/null").unwrap();
+                let _ = nix::unistd::dup2(devnull.as_raw_fd(), libc::STDIN_FILENO);
+                std::env::remove_var(SUPERVISED_ENV);
+                std::env::set_var("INVOCATION_ID", "0f3c");
+                let supervised = !forks();
+                std::env::set_var(SUPERVISED_ENV, "0");
+                let overridden = forks();
+                std::env::remove_var("INVOCATION_ID");
+                std::env::set_var(SUPERVISED_ENV, "1");
+                let forced = !forks();
+                unsafe { libc::_exit(if supervised && overridden && forced { 0 } else { 1 }) }
+            }
+        }
+    }
+
+    #[test]
+    /// Asserts that only the required user may launch the daemon
+    fn test_require_launch_user() {
+        let current = User::try_from(getuid().as_raw()).unwrap();
+        assert!(Daemon::new().require_launch_user(&current.name).check_launch_user().is_ok());
+        let other_uid = if getuid().is_root() { 65534 } else { 0 };
+        let other = match User::try_from(other_uid) {
+            Ok(other) => other,
+            Err(_) => return,
+        };
+        match Daemon::new().require_launch_user(&other.name).check_launch_user() {
+            Err(DaemonError::WrongLaunchUser { expected }) => assert_eq!(expected, other.name),
+            other => panic!("expected WrongLaunchUser, got {:?}", other),
+        }
+    }
+
+    #[test]
+    /// Asserts that syscall failures keep the errno telling them apart
+    fn test_syscall_error_names_errno() {
+        let errno = chdir("/nonexistent-daemonize-me").unwrap_err();
+        let error = DaemonError::ChDir(errno);
+        assert_eq!(error.to_string(), "Failed to chdir");
+        assert_eq!(std::error::Error::source(&error).unwrap().to_string(), "ENOENT: No such file or directory");
+        let error = DaemonError::SetSid(nix::errno::Errno::EPERM);
+        assert_eq!(std::error::Error::source(&error).unwrap().to_string(), "EPERM: Operation not permitted");
+    }
+
+    #[test]
+    /// Asserts that privilege operations failing with EPERM hint at running as root
+    fn test_eperm_hint() {
+        assert_eq!(
+            DaemonError::SetUid(nix::errno::Errno::EPERM).to_string(),
+            "Failed to set uid \
+             (insufficient privileges, daemonization usually requires starting as root to drop to another user)"
+        );
+        assert_eq!(DaemonError::ChownPid(nix::errno::Errno::ENOENT).to_string(),
+            "Failed to chown the pid file");
+    }
+
+    #[test]
+    /// Asserts that a raw umask is checked by validate and a typed one is taken as is
+    fn test_umask() {
+        assert!(matches!(Daemon::new().umask(0o10000).validate(), Err(DaemonError::InvalidUmaskBits)));
+        assert!(matches!(Daemon::new().file_creation_umask(0o10000).validate(), Err(DaemonError::InvalidUmaskBits)));
+        assert_eq!(Daemon::new().umask(0o077).umask, 0o077);
+        assert_eq!(Daemon::new().umask_mode(Mode::S_IWGRP | Mode::S_IRWXO).umask, 0o027);
+        assert_eq!(Daemon::new().umask_group_writable().umask, 0o002);
+    }
+
+    #[test]
+    /// Asserts that prepare resolves the paths and takes the lock, and that it fails before forking
+    fn test_prepare() {
+        let dir = temp_path("prepare");
+        std::fs::create_dir_all(&dir).unwrap();
+        let prepared = Daemon::new().work_dir(&dir).pid_file("daemon.pid", None).use_lockfile(true).prepare().unwrap();
+        assert_eq!(prepared.daemon.pid_file, Some(dir.canonicalize().unwrap().join("daemon.pid")));
+        assert!(prepared.lockfile.is_some());
+        // the lock is held until the prepared daemon is dropped
+        let second = Daemon::new().work_dir(&dir).pid_file("daemon.pid", None).use_lockfile(true).prepare();
+        assert!(second.is_err());
+        drop(prepared);
+        assert!(Daemon::new().work_dir(&dir).pid_file("daemon.pid", None).use_lockfile(true).prepare().is_ok());
+
+        let missing = Daemon::new().work_dir(dir.join("missing")).prepare();
+        assert!(matches!(missing, Err(DaemonError::ChDir(nix::errno::Errno::ENOENT))));
+        let bad_umask = Daemon::new().file_creation_umask(0o10000).prepare();
+        assert!(matches!(bad_umask, Err(DaemonError::InvalidUmaskBits)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the daemon moves into the directory prepare opened even when the path is swapped afterwards
+    fn test_work_dir_opened_by_prepare() {
+        let dir = temp_path("fchdir");
+        let moved = temp_path("fchdir-moved");
+        let marker = temp_path("fchdir-cwd");
+        let _ = std::fs::remove_dir_all(&dir);
+        let _ = std::fs::remove_dir_all(&moved);
+        let _ = std::fs::remove_file(&marker);
+        std::fs::create_dir_all(&dir).unwrap();
+        let hook_marker = marker.clone();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                // the launcher exits with 0 from the post fork hook, any other status tells what went wrong first
+                let status = waitpid(child, None).unwrap();
+                let reason = std::fs::read_to_string(&marker).unwrap_or_default();
+                assert_eq!(status, nix::sys::wait::WaitStatus::Exited(child, 0), "{}", reason);
+            }
+            ForkResult::Child => {
+                let prepared = Daemon::new().work_dir(&dir).setup_post_fork_parent_hook(exit_parent).setup_post_init_hook(move |_| {
+                    if let Ok(cwd) = std::env::current_dir() {
+                        let _ = std::fs::write(&hook_marker, cwd.to_string_lossy().as_bytes());
+                    }
+                }, None).prepare();
+                let prepared = match prepared {
+                    Ok(prepared) => prepared,
+                    Err(e) => {
+                        let _ = std::fs::write(&marker, format!("prepare failed: {:?}", e));
+                        unsafe { libc::_exit(2) }
+                    }
+                };
+                // the directory that was validated is moved away and another one takes its path
+                if let Err(e) = std::fs::rename(&dir, &moved).and_then(|_| std::fs::create_dir(&dir)) {
+                    let _ = std::fs::write(&marker, format!("swapping the work dir failed: {}", e));
+                    unsafe { libc::_exit(3) }
+                }
+                let code = match prepared.start() {
+                    Ok(_) => 0,
+                    Err(e) => {
+                        let _ = std::fs::write(&marker, format!("start failed: {:?}", e));
+                        1
+                    }
+                };
+                unsafe { libc::_exit(code) }
+            }
+        }
+        let cwd = wait_for_file(&marker).expect("the daemon never initialized");
+        assert_eq!(PathBuf::from(cwd), moved.canonicalize().unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&moved).unwrap();
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the plan lists the privilege drop in the order it is performed
+    fn test_plan() {
+        let steps = Daemon::new()
+            .user(User { id: 113, name: "svc".to_owned() })
+            .group(Group { id: 120, name: "svc".to_owned() })
+            .chroot("/var/lib/x")
+            .pid_file("/run/x.pid", Some(true))
+            .umask(0o027)
+            .plan()
+            .unwrap();
+        let steps: Vec<String> = steps.iter().map(DaemonStep::to_string).collect();
+        #[cfg(not(target_os = "macos"))]
+        let expected = [
+            "fork()", "redirect_stdio()", "umask(0o27)", "setsid()", "chdir(/var/lib/x/)",
+            "write_pid_file(/run/x.pid)", "chown(/run/x.pid, 113, 120)", "setgid(120)", "initgroups(svc, 120)", "chroot(/var/lib/x)", "chdir(/)",
+            "setuid(113)", "chdir(/)",
+        ];
+        #[cfg(target_os = "macos")]
+        let expected = [
+            "fork()", "redirect_stdio()", "umask(0o27)", "setsid()", "chdir(/var/lib/x/)",
+            "write_pid_file(/run/x.pid)", "chown(/run/x.pid, 113, 120)", "setgid(120)", "chroot(/var/lib/x)", "chdir(/)", "setuid(113)", "chdir(/)",
+        ];
+        assert_eq!(steps, expected);
+        // written after init, the pid file is reached from inside the new root
+        let steps = Daemon::new()
+            .chroot("/var/lib/x")
+            .work_dir("data")
+            .pid_file("x.pid", None)
+            .pid_file_after_init(true)
+            .plan()
+            .unwrap();
+        let steps: Vec<String> = steps.iter().map(DaemonStep::to_string).collect();
+        assert!(!steps.contains(&"write_pid_file(/var/lib/x/data/x.pid)".to_owned()));
+        assert_eq!(steps.last().map(String::as_str), Some("write_pid_file(/data/x.pid)"));
+        assert!(matches!(Daemon::new().group(Group { id: 120, name: "svc".to_owned() }).plan(),
+            Err(DaemonError::GroupWithoutUser)));
+    }
+
+    #[test]
+    /// Asserts that hooks can be closures capturing state from the launching scope
+    fn test_closure_hooks() {
+        let marker = temp_path("closure-hooks");
+        let _ = std::fs::remove_file(&marker);
+        let greeting = String::from("captured");
+        let daemon_marker = marker.clone();
+        launch(move || {
+            Daemon::new().setup_post_init_hook(move |_| {
+                let _ = std::fs::write(&daemon_marker, format!("{} {}", greeting, getpid()));
+            }, None)
+        });
+        let contents = wait_for_file(&marker).expect("the closure never ran in the daemon");
+        let (greeting, pid) = contents.split_once(' ').unwrap();
+        assert_eq!(greeting, "captured");
+        assert_ne!(pid, getpid().to_string());
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    fn payload_marker(_: i32) -> Box<dyn Any> {
+        // runs in the launcher, a child of the test process
+        let marker = std::env::temp_dir().join(format!("daemonize-me-payload-{}", getppid()));
+        Box::new(marker)
+    }
+
+    #[test]
+    /// Asserts that the value returned by the pre fork payload hook reaches the post init payload hook in the daemon
+    fn test_pre_fork_payload() {
+        let marker = temp_path("payload");
+        let _ = std::fs::remove_file(&marker);
+        launch(|| Daemon::new().setup_pre_fork_payload_hook(payload_marker).setup_post_init_payload_hook(write_pid_marker));
+        let daemon_pid = wait_for_file(&marker).expect("the payload never reached the post init payload hook");
+        assert_ne!(daemon_pid, getpid().to_string());
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the output of the credential command reaches the post init credential hook in the daemon
+    fn test_post_init_credential_hook() {
+        let marker = temp_path("credential");
+        let _ = std::fs::remove_file(&marker);
+        launch(|| {
+            let marker = marker.clone();
+            Daemon::new().credential_command("printf", &["hunter2"]).setup_post_init_credential_hook(move |credential| {
+                let _ = std::fs::write(&marker, credential.as_bytes());
+            })
+        });
+        let secret = wait_for_file(&marker).expect("the credential never reached the post init credential hook");
+        assert_eq!(secret, "hunter2");
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    fn start_again(ctx: Option<&dyn Any>) {
+        // the launcher might still be exiting
+        for _ in 0..100 {
+            if getppid() == Pid::from_raw(1) {
+                break;
+            }
+            sleep(Duration::from_millis(20));
+        }
+        // under a subreaper the daemon is never detected, there is nothing to test then
+        let outcome = if getppid() == Pid::from_raw(1) {
+            format!("{:?}", Daemon::new().skip_if_already_daemonized(true).start())
         } else {
-            Ok(())
+            "subreaper".to_owned()
+        };
+        if let Some(path) = ctx.and_then(|ctx| ctx.downcast_ref::<PathBuf>()) {
+            let _ = std::fs::write(path, outcome);
+        }
+    }
+
+    fn check_daemonized(ctx: Option<&dyn Any>) {
+        for _ in 0..100 {
+            if getppid() == Pid::from_raw(1) {
+                break;
+            }
+            sleep(Duration::from_millis(20));
+        }
+        let outcome = if getppid() == Pid::from_raw(1) {
+            format!("{}", is_daemonized(getpid()))
+        } else {
+            "subreaper".to_owned()
+        };
+        if let Some(path) = ctx.and_then(|ctx| ctx.downcast_ref::<PathBuf>()) {
+            let _ = std::fs::write(path, outcome);
         }
     }
+
+    fn start_twice(ctx: Option<&dyn Any>) {
+        let outcome = format!("{:?}", Daemon::new().start());
+        if let Some(path) = ctx.and_then(|ctx| ctx.downcast_ref::<PathBuf>()) {
+            let _ = std::fs::write(path, outcome);
+        }
+    }
+
+    #[test]
+    /// Asserts that a second start in the daemon fails instead of forking again
+    fn test_start_twice() {
+        let marker = temp_path("start-twice");
+        let _ = std::fs::remove_file(&marker);
+        launch(|| Daemon::new().setup_post_init_hook(start_twice, Some(&marker)));
+        let outcome = wait_for_file(&marker).expect("the daemon never started again");
+        assert_eq!(outcome, "Err(AlreadyDaemonized)");
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that starting from within a daemon is a no-op when asked to skip
+    fn test_skip_if_already_daemonized() {
+        let marker = temp_path("already-daemonized");
+        let _ = std::fs::remove_file(&marker);
+        launch(|| Daemon::new().setup_post_init_hook(start_again, Some(&marker)));
+        let outcome = wait_for_file(&marker).expect("the daemon never started again");
+        if outcome != "subreaper" {
+            assert_eq!(outcome, "Ok(AlreadyDaemon)");
+        }
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the double forked daemon, which doesn't lead its session, is detected as a daemon
+    fn test_is_daemonized_after_double_fork() {
+        let marker = temp_path("daemonized-double-fork");
+        let _ = std::fs::remove_file(&marker);
+        launch(|| Daemon::new().double_fork(true).setup_post_init_hook(check_daemonized, Some(&marker)));
+        let outcome = wait_for_file(&marker).expect("the daemon never checked");
+        if outcome != "subreaper" {
+            assert_eq!(outcome, "true");
+        }
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that nologin shells and locked passwords are detected
+    fn test_is_account_locked() {
+        let record = |passwd: &str, shell: &str| PasswdRecord {
+            pw_name: "svc".into(),
+            pw_passwd: passwd.to_owned(),
+            pw_uid: 1000,
+            pw_gid: 1000,
+            pw_gecos: String::new(),
+            pw_dir: "/".to_owned(),
+            pw_shell: shell.to_owned(),
+        };
+        assert!(!is_account_locked(&record("x", "/bin/sh")));
+        assert!(is_account_locked(&record("x", "/usr/sbin/nologin")));
+        assert!(is_account_locked(&record("x", "/bin/false")));
+        assert!(is_account_locked(&record("!", "/bin/sh")));
+    }
+
+    #[test]
+    /// Asserts that a clone can be changed without affecting the base configuration
+    fn test_clone_variant() {
+        let base = Daemon::new().pid_file("a.pid", None).umask(0o077);
+        let variant = base.clone().pid_file("b.pid", None);
+        assert_eq!(base.pid_file, Some(PathBuf::from("a.pid")));
+        assert_eq!(variant.pid_file, Some(PathBuf::from("b.pid")));
+        assert_eq!(variant.umask, 0o077);
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    /// Asserts that the status file describes the running daemon
+    fn test_status_file() {
+        let status_file = temp_path("status.json");
+        let _ = std::fs::remove_file(&status_file);
+        launch(|| Daemon::new().name(OsStr::new("status-test")).status_file(&status_file));
+        let status = wait_for_file(&status_file).expect("the status file was never written");
+        let status: serde_json::Value = serde_json::from_str(&status).unwrap();
+        assert_eq!(status["name"], "status-test");
+        assert_eq!(status["workdir"], "/");
+        assert!(status["pid"].as_i64().unwrap() > 0);
+        std::fs::remove_file(&status_file).unwrap();
+    }
+
+    fn slow_exit_parent(_: i32, _: i32) -> ! {
+        sleep(Duration::from_millis(300));
+        let marker = std::env::temp_dir().join(format!("daemonize-me-parent-exited-{}", getppid()));
+        let _ = std::fs::write(marker, "exited");
+        unsafe { libc::_exit(0) }
+    }
+
+    #[test]
+    /// Asserts that the pid file is only written once the parent is gone
+    fn test_wait_for_parent_exit() {
+        let pid_file = temp_path("parent-exit.pid");
+        let marker = temp_path("parent-exited");
+        let _ = std::fs::remove_file(&pid_file);
+        let _ = std::fs::remove_file(&marker);
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                waitpid(child, None).unwrap();
+            }
+            ForkResult::Child => {
+                let result = Daemon::new()
+                    .pid_file(&pid_file, None)
+                    .wait_for_parent_exit(true)
+                    .setup_post_fork_parent_hook(slow_exit_parent)
+                    .start();
+                unsafe { libc::_exit(if result.is_ok() { 0 } else { 1 }) }
+            }
+        }
+        wait_for_file(&pid_file).expect("the pid file was never written");
+        assert!(marker.exists(), "the pid file was written before the parent exited");
+        std::fs::remove_file(&pid_file).unwrap();
+        std::fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    /// Asserts that a missing half of the user and group pair is named
+    fn test_user_group_pair() {
+        let user = Daemon::new().user(User::try_from(0).unwrap());
+        assert!(matches!(user.check_user_group_pair(), Err(DaemonError::UserWithoutGroup)));
+        let group = Daemon::new().group(Group::try_from(0).unwrap());
+        assert!(matches!(group.check_user_group_pair(), Err(DaemonError::GroupWithoutUser)));
+        let both = group.user(User::try_from(0).unwrap());
+        assert!(both.check_user_group_pair().is_ok());
+        assert!(Daemon::new().check_user_group_pair().is_ok());
+    }
+
+    #[test]
+    /// Asserts that a bad configuration is refused by `prepare` in the calling process, before any fork
+    fn test_validate_before_fork() {
+        let pid = getpid();
+        let user = Daemon::new().user(User::try_from(0).unwrap());
+        assert!(matches!(user.validate(), Err(DaemonError::UserWithoutGroup)));
+        assert!(matches!(user.prepare(), Err(DaemonError::UserWithoutGroup)));
+        let group = Daemon::new().group(Group::try_from(0).unwrap());
+        assert!(matches!(group.prepare(), Err(DaemonError::GroupWithoutUser)));
+        let deferred = Daemon::new().user_name_deferred("root");
+        assert!(matches!(deferred.validate(), Err(DaemonError::UserWithoutGroup)));
+        assert!(deferred.group(Group::try_from(0).unwrap()).validate().is_ok());
+        let bad_umask = Daemon { file_creation_umask: Some(0o10000), ..Daemon::new() };
+        assert!(matches!(bad_umask.prepare(), Err(DaemonError::InvalidUmaskBits)));
+        assert_eq!(getpid(), pid);
+    }
+
+    #[test]
+    /// Asserts that the work dir follows the pid file and the pid file keeps pointing at the same path
+    fn test_work_dir_as_pid_dir() {
+        assert!(matches!(Daemon::new().work_dir_as_pid_dir(), Err(DaemonError::NoPidFile)));
+        let daemon = Daemon::new().work_dir("/var").pid_file("run/app.pid", None).work_dir_as_pid_dir().unwrap();
+        assert_eq!(daemon.chdir, PathBuf::from("/var/run"));
+        assert_eq!(daemon.pid_file, Some(PathBuf::from("/var/run/app.pid")));
+        let daemon = Daemon::new().pid_file("/run/app/app.pid", None).work_dir_as_pid_dir().unwrap();
+        assert_eq!(daemon.chdir, PathBuf::from("/run/app"));
+    }
+
+    #[test]
+    /// Asserts that absolute and relative work dirs are both resolved inside the chroot
+    fn test_jailed_path() {
+        let root = Path::new("/srv/jail");
+        assert_eq!(jailed_path(root, Path::new("/")), PathBuf::from("/srv/jail"));
+        assert_eq!(jailed_path(root, Path::new("/var/lib")), PathBuf::from("/srv/jail/var/lib"));
+        assert_eq!(jailed_path(root, Path::new("data")), PathBuf::from("/srv/jail/data"));
+    }
+
+    #[test]
+    /// Asserts that a relative work dir combined with a chroot ends up inside the new root
+    fn test_chroot_relative_work_dir() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let root = temp_path("chroot");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("data")).unwrap();
+        launch(|| Daemon::new().chroot(&root).work_dir("data").ready_file("ready"));
+        let ready = root.join("data").join("ready");
+        for _ in 0..100 {
+            if ready.exists() {
+                break;
+            }
+            sleep(Duration::from_millis(50));
+        }
+        assert!(ready.exists(), "the daemon never got ready inside the chroot");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    /// Asserts that a pid file written after init from inside a chroot lands in the work dir below the new root
+    fn test_chroot_pid_file_after_init() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let root = temp_path("chroot-pid-file");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("data")).unwrap();
+        launch(|| Daemon::new().chroot(&root).work_dir("data").pid_file("x.pid", None).pid_file_after_init(true));
+        let pid = wait_for_file(&root.join("data").join("x.pid")).expect("the pid file was never written in the chroot");
+        assert_ne!(pid, getpid().to_string());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the pid file is created with the file creation umask instead of the process one
+    fn test_file_creation_umask() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let pid_file = temp_path("file-creation-umask.pid");
+        let _ = std::fs::remove_file(&pid_file);
+        launch(|| {
+            Daemon::new()
+                .umask_mode(Mode::S_IRWXG | Mode::S_IRWXO)
+                .file_creation_umask(0o022)
+                .pid_file(&pid_file, None)
+        });
+        wait_for_file(&pid_file).expect("the pid file was never written");
+        let mode = std::fs::metadata(&pid_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o644);
+        std::fs::remove_file(&pid_file).unwrap();
+    }
+
+    #[test]
+    /// Asserts that a relative pid file lands in the launch directory instead of the work dir
+    fn test_pid_file_relative_to_launch_dir() {
+        let launch_dir = temp_path("launch-dir");
+        let _ = std::fs::remove_dir_all(&launch_dir);
+        std::fs::create_dir(&launch_dir).unwrap();
+        launch(|| {
+            std::env::set_current_dir(&launch_dir).unwrap();
+            Daemon::new().pid_file("relative.pid", None).pid_file_relative_to_launch_dir(true)
+        });
+        wait_for_file(&launch_dir.join("relative.pid")).expect("the pid file isn't in the launch dir");
+        std::fs::remove_dir_all(&launch_dir).unwrap();
+    }
+
+    fn linger(_: Option<&dyn Any>) {
+        sleep(Duration::from_secs(2));
+    }
+
+    #[test]
+    /// Asserts that the heartbeat keeps refreshing the mtime of the pid file
+    fn test_pid_file_heartbeat() {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let pid_file = temp_path("heartbeat.pid");
+        let _ = std::fs::remove_file(&pid_file);
+        launch(|| {
+            Daemon::new()
+                .pid_file(&pid_file, None)
+                .pid_file_heartbeat(Duration::from_millis(50))
+                .setup_post_init_hook(linger, None)
+        });
+        wait_for_file(&pid_file).expect("the pid file was never written");
+        let stale = UNIX_EPOCH + Duration::from_secs(1000);
+        let stale_time = TimeVal::seconds(1000);
+        nix::sys::stat::utimes(&pid_file, &stale_time, &stale_time).unwrap();
+        let mut refreshed = false;
+        for _ in 0..20 {
+            sleep(Duration::from_millis(50));
+            if std::fs::metadata(&pid_file).unwrap().modified().unwrap() > stale {
+                refreshed = true;
+                break;
+            }
+        }
+        assert!(refreshed, "the heartbeat never touched the pid file");
+        assert!(std::fs::metadata(&pid_file).unwrap().modified().unwrap() <= SystemTime::now());
+        std::fs::remove_file(&pid_file).unwrap();
+    }
+
+    #[test]
+    /// Asserts that files created in a setgid directory keep group write and the directory group
+    fn test_umask_group_writable() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = temp_path("setgid-dir");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        if nix::unistd::geteuid().is_root() {
+            nix::unistd::chown(&dir, None, Some(Gid::from_raw(65534))).unwrap();
+        }
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o2775)).unwrap();
+        let dir_gid = std::fs::metadata(&dir).unwrap().gid();
+        let pid_file = dir.join("shared.pid");
+        launch(|| Daemon::new().umask_group_writable().pid_file(&pid_file, None));
+        wait_for_file(&pid_file).expect("the pid file was never written");
+        let metadata = std::fs::metadata(&pid_file).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o664);
+        assert_eq!(metadata.gid(), dir_gid);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    /// Asserts that the current groups verify and that a group the process isn't in is caught
+    fn test_verify_supplementary_groups() {
+        let groups = getgroups().unwrap();
+        assert!(verify_supplementary_groups(&groups).is_ok());
+        let missing = Gid::from_raw(u32::MAX - 1);
+        assert!(verify_supplementary_groups(&[missing]).is_err());
+    }
 }
@@ -3,17 +3,23 @@
 extern crate libc;
 
 use std::ffi::{CStr, CString, OsStr, OsString};
+use std::mem::MaybeUninit;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+
+use nix::errno::Errno;
 
 #[cfg(target_os = "linux")]
 use {
-    crate::DaemonError::{GetPasswdRecord, SetProcName},
+    crate::DaemonError::SetProcName,
     libc::{PR_SET_NAME, prctl},
 };
 
 use crate::{DaemonError, Result};
-#[cfg(not(target_os = "linux"))]
-use crate::DaemonError::{GetPasswdRecord, SetProcName, UnsupportedOnOS};
+#[cfg(target_os = "macos")]
+use crate::DaemonError::SetProcName;
+use crate::DaemonError::UnsupportedOnOS;
 use crate::DaemonError::InvalidProcName;
 
 #[repr(C)]
@@ -77,11 +83,62 @@ struct FFIPasswd {
 extern "C" {
     fn getgrnam(name: *const libc::c_char) -> *const FFIGroup;
     fn getgrgid(name: libc::gid_t) -> *const FFIGroup;
-    fn getpwnam(name: *const libc::c_char) -> *const FFIPasswd;
-    fn getpwuid(name: libc::uid_t) -> *const FFIPasswd;
+    fn getpwnam_r(
+        name: *const libc::c_char,
+        pwd: *mut FFIPasswd,
+        buf: *mut libc::c_char,
+        buflen: libc::size_t,
+        result: *mut *mut FFIPasswd,
+    ) -> libc::c_int;
+    fn getpwuid_r(
+        uid: libc::uid_t,
+        pwd: *mut FFIPasswd,
+        buf: *mut libc::c_char,
+        buflen: libc::size_t,
+        result: *mut *mut FFIPasswd,
+    ) -> libc::c_int;
+}
+
+/// Largest buffer handed to the reentrant passwd lookups before giving up on `ERANGE`
+const MAX_PASSWD_BUFFER: usize = 1 << 20;
+
+// login_cap(3) lives in libutil on FreeBSD
+#[cfg(target_os = "freebsd")]
+#[link(name = "util")]
+extern "C" {
+    fn login_getclass(class: *const libc::c_char) -> *mut libc::c_void;
+    fn login_close(lc: *mut libc::c_void);
+    fn setusercontext(
+        lc: *mut libc::c_void,
+        pwd: *const libc::c_void,
+        uid: libc::uid_t,
+        flags: libc::c_uint,
+    ) -> libc::c_int;
+}
+
+#[cfg(target_os = "freebsd")]
+const LOGIN_SETPRIORITY: libc::c_uint = 0x0008;
+#[cfg(target_os = "freebsd")]
+const LOGIN_SETRESOURCES: libc::c_uint = 0x0010;
+#[cfg(target_os = "freebsd")]
+const LOGIN_SETUMASK: libc::c_uint = 0x0020;
+
+// The libc standard streams are plain globals on linux and macros over globals on the BSDs
+#[cfg(target_os = "linux")]
+extern "C" {
+    static mut stdout: *mut libc::FILE;
+    static mut stderr: *mut libc::FILE;
+}
+
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+extern "C" {
+    #[link_name = "__stdoutp"]
+    static mut stdout: *mut libc::FILE;
+    #[link_name = "__stderrp"]
+    static mut stderr: *mut libc::FILE;
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct GroupRecord {
     pub gr_name: String,
@@ -89,10 +146,11 @@ pub struct GroupRecord {
     pub gr_gid: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct PasswdRecord {
-    pub pw_name: String,
+    /// Kept as the raw bytes of the database, `initgroups` needs the exact name
+    pub pw_name: OsString,
     pub pw_passwd: String,
     pub pw_uid: u32,
     pub pw_gid: u32,
@@ -102,49 +160,70 @@ pub struct PasswdRecord {
 }
 
 unsafe fn check_group_record(grp: *const FFIGroup) -> Result<GroupRecord> {
-    return if grp.is_null() {
+    if grp.is_null() {
         Err(DaemonError::GetGrRecord)
     } else {
         let gr = &*grp;
         let sgr = GroupRecord {
             gr_name: CStr::from_ptr(gr.gr_name).to_string_lossy().to_string(),
             gr_passwd: CStr::from_ptr(gr.gr_passwd).to_string_lossy().to_string(),
-            gr_gid: gr.gr_gid as u32,
+            gr_gid: gr.gr_gid,
         };
         Ok(sgr)
-    };
+    }
+}
+
+/// Runs one of the `getpw*_r` functions growing the buffer as needed, a missing entry is reported as
+/// `UserNotFound` and a failing lookup (eg. an unreachable directory server) as `PasswdLookupFailed`
+fn lookup_passwd<F>(lookup: F) -> Result<PasswdRecord>
+where
+    F: Fn(*mut FFIPasswd, *mut libc::c_char, libc::size_t, *mut *mut FFIPasswd) -> libc::c_int,
+{
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let mut pwd = MaybeUninit::<FFIPasswd>::uninit();
+        let mut result: *mut FFIPasswd = std::ptr::null_mut();
+        let code = lookup(pwd.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut result);
+        match code {
+            0 if result.is_null() => return Err(DaemonError::UserNotFound),
+            // the strings in the record point into `buf` so they are copied out before it goes away
+            0 => return unsafe { check_passwd_record(result) },
+            libc::ERANGE if buf.len() < MAX_PASSWD_BUFFER => buf.resize(buf.len() * 2, 0),
+            errno => return Err(DaemonError::PasswdLookupFailed(Errno::from_i32(errno))),
+        }
+    }
 }
 
 unsafe fn check_passwd_record(passwd: *const FFIPasswd) -> Result<PasswdRecord> {
-    return if passwd.is_null() {
-        Err(GetPasswdRecord)
+    if passwd.is_null() {
+        Err(DaemonError::UserNotFound)
     } else {
         let pw = &*passwd;
         let pwr = PasswdRecord {
-            pw_name: CStr::from_ptr(pw.pw_name).to_string_lossy().to_string(),
+            pw_name: OsStr::from_bytes(CStr::from_ptr(pw.pw_name).to_bytes()).to_owned(),
             pw_passwd: CStr::from_ptr(pw.pw_passwd).to_string_lossy().to_string(),
-            pw_uid: pw.pw_uid as u32,
-            pw_gid: pw.pw_gid as u32,
+            pw_uid: pw.pw_uid,
+            pw_gid: pw.pw_gid,
             pw_gecos: CStr::from_ptr(pw.pw_gecos).to_string_lossy().to_string(),
             pw_dir: CStr::from_ptr(pw.pw_dir).to_string_lossy().to_string(),
             pw_shell: CStr::from_ptr(pw.pw_shell).to_string_lossy().to_string(),
         };
         Ok(pwr)
-    };
+    }
 }
 
 #[allow(dead_code)]
 impl GroupRecord {
-    pub fn lookup_record_by_name(name: &str) -> Result<GroupRecord> {
-        let record_name = match CString::new(name) {
+    pub fn lookup_record_by_name(name: &OsStr) -> Result<GroupRecord> {
+        let record_name = match CString::new(name.as_bytes()) {
             Ok(s) => s,
             Err(_) => return Err(DaemonError::InvalidCstr),
         };
 
         unsafe {
             let raw_grp = getgrnam(record_name.as_ptr());
-            return check_group_record(raw_grp);
-        };
+            check_group_record(raw_grp)
+        }
     }
 
     pub fn lookup_record_by_id(gid: u32) -> Result<GroupRecord> {
@@ -152,43 +231,59 @@ impl GroupRecord {
 
         unsafe {
             let raw_grp = getgrgid(record_id);
-            return check_group_record(raw_grp);
-        };
+            check_group_record(raw_grp)
+        }
     }
 }
 
 impl PasswdRecord {
-    pub fn lookup_record_by_name(name: &str) -> Result<PasswdRecord> {
-        let record_name = match CString::new(name) {
+    pub fn lookup_record_by_name(name: &OsStr) -> Result<PasswdRecord> {
+        let record_name = match CString::new(name.as_bytes()) {
             Ok(s) => s,
             Err(_) => return Err(DaemonError::InvalidCstr),
         };
 
-        unsafe {
-            let raw_passwd = getpwnam(record_name.as_ptr());
-            return check_passwd_record(raw_passwd);
-        };
+        lookup_passwd(|pwd, buf, buflen, result| unsafe {
+            getpwnam_r(record_name.as_ptr(), pwd, buf, buflen, result)
+        })
     }
 
     pub fn lookup_record_by_id(uid: u32) -> Result<PasswdRecord> {
         let record_id = uid as libc::uid_t;
 
-        unsafe {
-            let raw_passwd = getpwuid(record_id);
-            return check_passwd_record(raw_passwd);
-        };
+        lookup_passwd(|pwd, buf, buflen, result| unsafe { getpwuid_r(record_id, pwd, buf, buflen, result) })
     }
 }
 
+/// The longest name prctl(PR_SET_NAME) keeps, the kernel buffer holds 16 bytes including the NUL
 #[cfg(target_os = "linux")]
-/// Safe wrapper to the prctl(2) call
-pub fn set_proc_name(name: &OsStr) -> Result<()> {
-    let name_truncated = match CString::new(OsString::from(name).as_bytes()) {
-        Ok(procname) => procname,
-        Err(_) => return Err(InvalidProcName),
-    };
+const PROC_NAME_MAX: usize = 15;
+
+/// The name as handed to the OS, on Linux a name the kernel would cut is refused unless `truncate` is set,
+/// in which case it is cut to the first 15 bytes up front
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub fn proc_name_cstring(name: &OsStr, truncate: bool) -> Result<CString> {
+    #[cfg_attr(not(target_os = "linux"), allow(unused_mut))]
+    let mut bytes = name.as_bytes().to_vec();
+    #[cfg(target_os = "linux")]
+    if bytes.len() > PROC_NAME_MAX {
+        if !truncate {
+            return Err(InvalidProcName);
+        }
+        bytes.truncate(PROC_NAME_MAX);
+    }
+    match CString::new(bytes) {
+        Ok(procname) => Ok(procname),
+        Err(_) => Err(InvalidProcName),
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Safe wrapper to the prctl(2) call, see [`proc_name_cstring`] for names longer than 15 bytes
+pub fn set_proc_name(name: &OsStr, truncate: bool) -> Result<()> {
+    let name = proc_name_cstring(name, truncate)?;
     unsafe {
-        if prctl(PR_SET_NAME, name_truncated.as_bytes_with_nul()) < 0 {
+        if prctl(PR_SET_NAME, name.as_ptr()) < 0 {
             Err(SetProcName)
         } else {
             Ok(())
@@ -196,12 +291,375 @@ pub fn set_proc_name(name: &OsStr) -> Result<()> {
     }
 }
 
-// TODO: Implement this for non linux targets
+#[cfg(target_os = "macos")]
+/// Safe wrapper to pthread_setname_np(3), macOS only names the calling thread, up to 63 bytes,
+/// which is what Activity Monitor and the debuggers show
+pub fn set_proc_name(name: &OsStr, truncate: bool) -> Result<()> {
+    let name = proc_name_cstring(name, truncate)?;
+    if unsafe { libc::pthread_setname_np(name.as_ptr()) } != 0 {
+        Err(SetProcName)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "freebsd", target_os = "dragonfly", target_os = "netbsd", target_os = "openbsd"))]
+/// Safe wrapper to setproctitle(3), unlike on Linux the title shown by ps(1) isn't cut at 16 bytes
+/// and replaces the whole command line, the program name prefix is left out
+pub fn set_proc_name(name: &OsStr, truncate: bool) -> Result<()> {
+    let name = proc_name_cstring(name, truncate)?;
+    // the name is passed as an argument so a % in it isn't taken for a format directive
+    unsafe { libc::setproctitle(b"-%s\0".as_ptr() as *const libc::c_char, name.as_ptr()) };
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+pub fn set_proc_name(_name: &OsStr, _truncate: bool) -> Result<()> {
+    Err(UnsupportedOnOS)
+}
+
+#[cfg(target_os = "linux")]
+/// Overwrites argv[0] in place with its basename, so ps(1) shows `mydaemon` instead of `/usr/sbin/mydaemon`.
+/// The argument strings sit in one region of the stack right before the environment strings and it can't
+/// grow, only the bytes of argv[0] are touched: the basename is written at its start and the bytes left
+/// over are set to NUL, they show up as empty arguments between the name and the other arguments.
+/// The region is located through /proc/self/stat and checked to hold argv[0] before anything is written
+pub fn set_argv0_basename() -> Result<()> {
+    let current = match std::env::args_os().next() {
+        Some(arg) => arg,
+        None => return Err(DaemonError::SetArgv0),
+    };
+    let current = current.as_bytes();
+    let basename = match Path::new(OsStr::from_bytes(current)).file_name() {
+        Some(name) => name.as_bytes(),
+        None => return Err(DaemonError::SetArgv0),
+    };
+    let (arg_start, arg_end) = args_region()?;
+    if arg_end <= arg_start || arg_end - arg_start <= current.len() {
+        return Err(DaemonError::SetArgv0);
+    }
+    let region = arg_start as *mut u8;
+    // argv[0] is the first string of the region, anything else means the region was moved eg. by PR_SET_MM
+    let in_place = unsafe { std::slice::from_raw_parts(region, current.len() + 1) };
+    if in_place[..current.len()] != *current || in_place[current.len()] != 0 {
+        return Err(DaemonError::SetArgv0);
+    }
+    unsafe {
+        std::ptr::copy(basename.as_ptr(), region, basename.len());
+        std::ptr::write_bytes(region.add(basename.len()), 0, current.len() - basename.len());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+/// The arg_start and arg_end fields of /proc/self/stat, the name field before them may hold spaces and parentheses
+fn args_region() -> Result<(usize, usize)> {
+    let stat = match std::fs::read_to_string("/proc/self/stat") {
+        Ok(stat) => stat,
+        Err(_) => return Err(DaemonError::SetArgv0),
+    };
+    let after_name = match stat.rfind(')') {
+        Some(end) => &stat[end + 1..],
+        None => return Err(DaemonError::SetArgv0),
+    };
+    // the fields after the name start with the third one, arg_start is the 48th and arg_end the 49th
+    let mut fields = after_name.split_whitespace().skip(45);
+    match (fields.next().and_then(|f| f.parse().ok()), fields.next().and_then(|f| f.parse().ok())) {
+        (Some(start), Some(end)) => Ok((start, end)),
+        _ => Err(DaemonError::SetArgv0),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_argv0_basename() -> Result<()> {
+    Err(UnsupportedOnOS)
+}
+
+#[cfg(target_os = "freebsd")]
+/// Safe wrapper to setusercontext(3), applies the resource limits, priority and umask of a login class
+pub fn set_login_class(class: &str) -> Result<()> {
+    let class_cstr = match CString::new(class) {
+        Ok(cstr) => cstr,
+        Err(_) => return Err(DaemonError::InvalidCstr),
+    };
+    unsafe {
+        let lc = login_getclass(class_cstr.as_ptr());
+        if lc.is_null() {
+            return Err(DaemonError::LoginClass);
+        }
+        let flags = LOGIN_SETRESOURCES | LOGIN_SETPRIORITY | LOGIN_SETUMASK;
+        let result = setusercontext(lc, std::ptr::null(), libc::getuid(), flags);
+        login_close(lc);
+        if result < 0 {
+            Err(DaemonError::LoginClass)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_os = "freebsd"))]
+pub fn set_login_class(_class: &str) -> Result<()> {
+    Err(UnsupportedOnOS)
+}
+
+#[cfg(target_os = "linux")]
+/// How many threads the process has, from the entries of /proc/self/task
+pub fn thread_count() -> Option<usize> {
+    std::fs::read_dir("/proc/self/task").ok().map(|entries| entries.count())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn thread_count() -> Option<usize> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+/// Safe wrapper to prctl(PR_SET_CHILD_SUBREAPER), orphaned descendants are reparented to this process
+pub fn set_child_subreaper() -> Result<()> {
+    if unsafe { prctl(libc::PR_SET_CHILD_SUBREAPER, 1 as libc::c_ulong) } < 0 {
+        Err(DaemonError::SetChildSubreaper(std::io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_child_subreaper() -> Result<()> {
+    Err(UnsupportedOnOS)
+}
+
+#[cfg(target_os = "linux")]
+/// Safe wrapper to prctl(PR_SET_NO_NEW_PRIVS), execve can no longer grant privileges through setuid bits or file capabilities
+pub fn set_no_new_privs() -> Result<()> {
+    if unsafe { prctl(libc::PR_SET_NO_NEW_PRIVS, 1 as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong) } < 0 {
+        Err(DaemonError::SetNoNewPrivs(std::io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_no_new_privs() -> Result<()> {
+    Err(UnsupportedOnOS)
+}
+
+#[cfg(target_os = "linux")]
+/// Does what aa_change_profile(2) of libapparmor does, through the kernel interface so nothing is linked:
+/// the request is written to the attr file of the calling thread, the one of the AppArmor LSM when
+/// the kernel has per LSM attr files and the shared one otherwise. The file is read back as a kernel
+/// without AppArmor may accept the write and keep the thread in its current context
+pub fn change_apparmor_profile(profile: &str) -> Result<()> {
+    let attr = match Path::new("/proc/thread-self/attr/apparmor/current") {
+        lsm_attr if lsm_attr.exists() => lsm_attr,
+        _ => Path::new("/proc/thread-self/attr/current"),
+    };
+    let applied = std::fs::write(attr, format!("changeprofile {}", profile)).and_then(|_| {
+        let current = std::fs::read_to_string(attr)?;
+        // the context reads as `{profile} ({mode})`
+        if current.trim_end_matches('\0').trim_end().split(" (").next() == Some(profile) {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(std::io::ErrorKind::Other, format!("the thread stayed in {}", current.trim())))
+        }
+    });
+    match applied {
+        Ok(()) => Ok(()),
+        Err(source) => Err(DaemonError::AppArmor { profile: profile.to_owned(), source }),
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Moves the process into a mount namespace of its own and mounts each of `mounts` in it, given as
+/// `(source, target, fstype, flags)` the way mount(2) takes them. The existing mounts are made private
+/// first so neither the new ones nor later unmounts propagate back to the host
+pub fn mount_private(mounts: &[(String, PathBuf, String, libc::c_ulong)]) -> Result<()> {
+    use nix::mount::{mount, MsFlags};
+    use nix::sched::{unshare, CloneFlags};
+
+    if let Err(errno) = unshare(CloneFlags::CLONE_NEWNS) {
+        return Err(DaemonError::MountNamespace(errno));
+    }
+    if let Err(errno) = mount::<str, str, str, str>(None, "/", None, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None) {
+        return Err(DaemonError::Mount { target: PathBuf::from("/"), source: errno });
+    }
+    for (source, target, fstype, flags) in mounts {
+        let flags = MsFlags::from_bits_truncate(*flags);
+        if let Err(errno) = mount::<str, Path, str, str>(Some(source.as_str()), target, Some(fstype.as_str()), flags, None) {
+            return Err(DaemonError::Mount { target: target.clone(), source: errno });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn mount_private(_mounts: &[(String, PathBuf, String, libc::c_ulong)]) -> Result<()> {
+    Err(UnsupportedOnOS)
+}
+
+#[cfg(target_os = "linux")]
+/// Safe wrapper to prctl(PR_SET_KEEPCAPS), when set the permitted capabilities survive a setuid away from root
+pub fn set_keep_capabilities(keep: bool) -> Result<()> {
+    if unsafe { prctl(libc::PR_SET_KEEPCAPS, keep as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong) } < 0 {
+        Err(DaemonError::KeepCapabilities(std::io::Error::last_os_error()))
+    } else {
+        Ok(())
+    }
+}
+
 #[cfg(not(target_os = "linux"))]
-pub fn set_proc_name(name: &OsStr) -> Result<()> {
+pub fn set_keep_capabilities(_keep: bool) -> Result<()> {
     Err(UnsupportedOnOS)
 }
 
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct FFICapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FFICapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+#[cfg(target_os = "linux")]
+/// Narrows the permitted, effective and inheritable sets of the calling thread to `capabilities` with capset(2)
+/// and raises each of them in the ambient set, so they also survive an execve as the unprivileged user.
+/// Every capability must still be permitted, that is the uid changed with [`set_keep_capabilities`] on
+pub fn restrict_capabilities(capabilities: &[crate::Capability]) -> Result<()> {
+    // _LINUX_CAPABILITY_VERSION_3, the sets are split in two 32 bit words
+    let mut header = FFICapHeader { version: 0x2008_0522, pid: 0 };
+    let mut data = [FFICapData::default(); 2];
+    for &capability in capabilities {
+        let (word, bit) = (capability as usize / 32, 1u32 << (capability as u32 % 32));
+        data[word].effective |= bit;
+        data[word].permitted |= bit;
+        data[word].inheritable |= bit;
+    }
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header as *mut FFICapHeader, data.as_ptr()) } < 0 {
+        return Err(DaemonError::KeepCapabilities(std::io::Error::last_os_error()));
+    }
+    for &capability in capabilities {
+        let raised = unsafe {
+            prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong, capability as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong)
+        };
+        if raised < 0 {
+            return Err(DaemonError::KeepCapabilities(std::io::Error::last_os_error()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restrict_capabilities(_capabilities: &[crate::Capability]) -> Result<()> {
+    Err(UnsupportedOnOS)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn change_apparmor_profile(_profile: &str) -> Result<()> {
+    Err(UnsupportedOnOS)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+/// Safe wrapper to setvbuf(3) for the libc stdout and stderr streams
+pub fn set_stdio_buffering(mode: libc::c_int) -> Result<()> {
+    unsafe {
+        let streams = [stdout, stderr];
+        for stream in streams {
+            if libc::setvbuf(stream, std::ptr::null_mut(), mode, 0) != 0 {
+                return Err(DaemonError::SetBuffering(std::io::Error::last_os_error()));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+pub fn set_stdio_buffering(_mode: libc::c_int) -> Result<()> {
+    Err(UnsupportedOnOS)
+}
+
+/// A pipe that isn't leaked to programs executed later on
+pub(crate) fn cloexec_pipe() -> std::io::Result<(RawFd, RawFd)> {
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+    use nix::unistd::{close, pipe};
+
+    let (read_end, write_end) = pipe()?;
+    for fd in [read_end, write_end] {
+        if let Err(errno) = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+            let _ = close(read_end);
+            let _ = close(write_end);
+            return Err(errno.into());
+        }
+    }
+    Ok((read_end, write_end))
+}
+
+/// A single write(2) of `msg` to `fd`, returning how many bytes were written.
+/// Unlike `println!` it takes no lock and allocates nothing, so it is async-signal-safe: this is the way
+/// to emit diagnostics from the post fork hooks, where stdio may not be set up yet, and from signal handlers.
+/// A short write is not retried, keep messages below `PIPE_BUF` for them to be written whole
+pub fn raw_log(fd: std::os::unix::io::RawFd, msg: &[u8]) -> std::result::Result<usize, Errno> {
+    let written = unsafe { libc::write(fd, msg.as_ptr() as *const libc::c_void, msg.len()) };
+    if written < 0 {
+        Err(Errno::last())
+    } else {
+        Ok(written as usize)
+    }
+}
+
+/// Closes every fd from `lowest` up except the ones in `keep`, with close_range(2) where the kernel has it,
+/// else the fds listed in `/proc/self/fd` and without `/proc` every fd below `sysconf(_SC_OPEN_MAX)`
+pub fn close_fds_from(lowest: RawFd, keep: &[RawFd]) {
+    let mut keep: Vec<RawFd> = keep.iter().copied().filter(|&fd| fd >= lowest).collect();
+    keep.sort_unstable();
+    keep.dedup();
+    #[cfg(target_os = "linux")]
+    if close_range_except(lowest, &keep) {
+        return;
+    }
+    let open_fds: Vec<RawFd> = match std::fs::read_dir("/proc/self/fd") {
+        // the directory fd is in the listing as well, it is closed already once collected
+        Ok(entries) => entries.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok()).collect(),
+        Err(_) => {
+            let open_max = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+            let open_max = if open_max > 0 { open_max.min(RawFd::MAX as libc::c_long) as RawFd } else { 1024 };
+            (lowest..open_max).collect()
+        }
+    };
+    for fd in open_fds.into_iter().filter(|fd| *fd >= lowest && keep.binary_search(fd).is_err()) {
+        let _ = nix::unistd::close(fd);
+    }
+}
+
+/// Closes the ranges between the kept fds, false when close_range(2) isn't available (before Linux 5.9)
+#[cfg(target_os = "linux")]
+fn close_range_except(lowest: RawFd, keep: &[RawFd]) -> bool {
+    let mut first = lowest as libc::c_uint;
+    for &kept in keep {
+        let kept = kept as libc::c_uint;
+        if kept > first && unsafe { libc::syscall(libc::SYS_close_range, first, kept - 1, 0) } < 0 {
+            return false;
+        }
+        first = kept + 1;
+    }
+    let result = unsafe { libc::syscall(libc::SYS_close_range, first, libc::c_uint::MAX, 0) };
+    result == 0
+}
+
 #[cfg(test)]
 mod tests {
     // TODO: Improve testing because of unsafe code
@@ -210,7 +668,7 @@ mod tests {
     #[test]
     /// Asserts if the uid returned for the uname "root" is 0
     fn test_passwd_by_name() {
-        let root = PasswdRecord::lookup_record_by_name("root").unwrap();
+        let root = PasswdRecord::lookup_record_by_name(OsStr::new("root")).unwrap();
         assert_eq!(root.pw_uid, 0)
     }
 
@@ -221,10 +679,174 @@ mod tests {
         assert_eq!(root.pw_name, "root")
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Asserts that the subreaper flag is set, in a forked child so the test process keeps its own
+    fn test_set_child_subreaper() {
+        use nix::sys::wait::{waitpid, WaitStatus};
+        use nix::unistd::{fork, ForkResult};
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+            ForkResult::Child => {
+                let mut flag: libc::c_int = 0;
+                let set = set_child_subreaper().is_ok()
+                    && unsafe { prctl(libc::PR_GET_CHILD_SUBREAPER, &mut flag as *mut libc::c_int) } == 0
+                    && flag == 1;
+                unsafe { libc::_exit(if set { 0 } else { 1 }) }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Asserts that no_new_privs is set, in a forked child since it can't be cleared again
+    fn test_set_no_new_privs() {
+        use nix::sys::wait::{waitpid, WaitStatus};
+        use nix::unistd::{fork, ForkResult};
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+            ForkResult::Child => {
+                let set = set_no_new_privs().is_ok()
+                    && unsafe { prctl(libc::PR_GET_NO_NEW_PRIVS, 0 as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong) } == 1;
+                unsafe { libc::_exit(if set { 0 } else { 1 }) }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Asserts that changing to a profile that isn't loaded fails and names the profile
+    fn test_change_apparmor_profile() {
+        match change_apparmor_profile("daemonize-me-test-missing") {
+            Err(DaemonError::AppArmor { profile, .. }) => assert_eq!(profile, "daemonize-me-test-missing"),
+            other => panic!("expected AppArmor, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Asserts that the mounts are only made in the namespace of the forked child and a failed one names its target
+    fn test_mount_private() {
+        use nix::sys::wait::{waitpid, WaitStatus};
+        use nix::unistd::{fork, getuid, ForkResult};
+
+        if !getuid().is_root() {
+            return;
+        }
+        let target = std::env::temp_dir().join(format!("daemonize-me-mount-{}", std::process::id()));
+        std::fs::create_dir_all(&target).unwrap();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+            ForkResult::Child => {
+                let mounts = [("tmpfs".to_owned(), target.clone(), "tmpfs".to_owned(), libc::MS_NOSUID)];
+                let mounted = mount_private(&mounts).is_ok() && std::fs::write(target.join("private"), "x").is_ok();
+                let missing = [("tmpfs".to_owned(), target.join("missing"), "tmpfs".to_owned(), 0)];
+                let named = matches!(mount_private(&missing), Err(DaemonError::Mount { target, .. }) if target.ends_with("missing"));
+                unsafe { libc::_exit(if mounted && named { 0 } else { 1 }) }
+            }
+        }
+        // the file went to the tmpfs of the child, not to the directory
+        assert!(!target.join("private").exists());
+        std::fs::remove_dir(&target).unwrap();
+    }
+
+    #[test]
+    /// Asserts that every fd from the lowest one up is closed except the kept one, in a forked child
+    /// so the test process keeps its own fds
+    fn test_close_fds_from() {
+        use std::os::unix::io::IntoRawFd;
+        use nix::fcntl::{fcntl, FcntlArg};
+        use nix::sys::wait::{waitpid, WaitStatus};
+        use nix::unistd::{fork, ForkResult};
+
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+            ForkResult::Child => {
+                let fds: Vec<RawFd> = (0..3).filter_map(|_| std::fs::File::open("/dev/null").ok()).map(IntoRawFd::into_raw_fd).collect();
+                close_fds_from(fds[0], &[fds[1]]);
+                let open: Vec<bool> = fds.iter().map(|&fd| fcntl(fd, FcntlArg::F_GETFD).is_ok()).collect();
+                let stdio_open = (0..3).all(|fd| fcntl(fd, FcntlArg::F_GETFD).is_ok());
+                unsafe { libc::_exit(if open == [false, true, false] && stdio_open { 0 } else { 1 }) }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Asserts that argv[0] is cut down to its basename in place, the other arguments staying where they are,
+    /// in a forked child so the test process keeps its command line
+    fn test_set_argv0_basename() {
+        use nix::sys::wait::{waitpid, WaitStatus};
+        use nix::unistd::{fork, ForkResult};
+
+        let argv0 = std::env::args_os().next().unwrap();
+        let basename = Path::new(&argv0).file_name().unwrap().to_owned();
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+            ForkResult::Child => {
+                let args_before: Vec<_> = std::env::args_os().skip(1).collect();
+                let renamed = set_argv0_basename().is_ok();
+                let cmdline = std::fs::read("/proc/self/cmdline").unwrap_or_default();
+                let mut expected = basename.as_bytes().to_vec();
+                expected.resize(argv0.len() + 1, 0);
+                let shown = cmdline.starts_with(&expected);
+                let args_after: Vec<_> = std::env::args_os().skip(1).collect();
+                let same_args = args_before == args_after;
+                let read_back = std::env::args_os().next() == Some(basename.clone());
+                unsafe { libc::_exit(if renamed && shown && same_args && read_back { 0 } else { 1 }) }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Asserts that names the kernel would cut are refused unless truncating was asked for
+    fn test_proc_name_cstring() {
+        let long = OsStr::new("a-rather-long-daemon");
+        assert!(matches!(proc_name_cstring(long, false), Err(InvalidProcName)));
+        assert_eq!(proc_name_cstring(long, true).unwrap().as_bytes(), b"a-rather-long-d");
+        assert_eq!(proc_name_cstring(OsStr::new("exactly-15-byte"), false).unwrap().as_bytes(), b"exactly-15-byte");
+        assert!(matches!(proc_name_cstring(OsStr::new("nul\0"), true), Err(InvalidProcName)));
+    }
+
+    #[test]
+    /// Asserts that the message is written whole and a bad fd is reported
+    fn test_raw_log() {
+        let (read_end, write_end) = nix::unistd::pipe().unwrap();
+        assert_eq!(raw_log(write_end, b"child up\n"), Ok(9));
+        let mut buf = [0u8; 16];
+        let read = nix::unistd::read(read_end, &mut buf).unwrap();
+        assert_eq!(&buf[..read], b"child up\n");
+        nix::unistd::close(read_end).unwrap();
+        nix::unistd::close(write_end).unwrap();
+        assert_eq!(raw_log(-1, b"lost"), Err(Errno::EBADF));
+    }
+
+    #[test]
+    /// Asserts that a user that doesn't exist is told apart from a failed lookup
+    fn test_passwd_not_found() {
+        match PasswdRecord::lookup_record_by_name(OsStr::new("daemonize-me-no-such-user")) {
+            Err(DaemonError::UserNotFound) => (),
+            other => panic!("expected UserNotFound, got {:?}", other),
+        }
+    }
+
     #[test]
     /// Asserts if the uid returned for the uname "root" is 0
     fn test_gr_by_name() {
-        let root = GroupRecord::lookup_record_by_name("root").unwrap();
+        let root = GroupRecord::lookup_record_by_name(OsStr::new("root")).unwrap();
         assert_eq!(root.gr_gid, 0)
     }
 
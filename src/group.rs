@@ -1,27 +1,60 @@
 pub use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
 
 use crate::{DaemonError, Result};
-use crate::ffi::GroupRecord;
+use crate::resolver::{RecordResolver, SystemResolver};
 
 /// Expects: either the group name or a gid
-/// if the name is provided it will be resolved to an id
+/// if the name is provided it will be resolved to an id, a name made of digits only that doesn't exist is taken as a gid
 #[derive(Debug, Ord, PartialOrd, PartialEq, Eq, Clone)]
 pub struct Group {
     pub id: u32,
     pub name: String
 }
 
-impl<'uname> TryFrom<&'uname str> for Group {
-    type Error = DaemonError;
-
-    fn try_from(gname: &'uname str) -> Result<Group> {
-        match GroupRecord::lookup_record_by_name(gname) {
+impl Group {
+    pub(crate) fn resolve_name<N: AsRef<OsStr>, R: RecordResolver>(gname: N, resolver: &R) -> Result<Group> {
+        let gname = gname.as_ref();
+        match resolver.group_by_name(gname) {
             Ok(record) => Ok(Group {
                 id: record.gr_gid,
                 name: record.gr_name
             }),
-            Err(_) => Err(DaemonError::InvalidGroup),
+            Err(e) => {
+                let invalid = |source| DaemonError::InvalidGroup { names: vec![gname.to_string_lossy().into_owned()], source: Some(Box::new(source)) };
+                match gname.to_str().and_then(|gname| gname.parse::<u32>().ok()) {
+                    Some(gid) => Group::resolve_id(gid, resolver).map_err(invalid),
+                    None => Err(invalid(e)),
+                }
+            }
+        }
+    }
+
+    /// Resolves the first of `names` that exists
+    pub(crate) fn resolve_first_available<R: RecordResolver>(names: &[&str], resolver: &R) -> Result<Group> {
+        for name in names {
+            match Group::resolve_name(*name, resolver) {
+                Err(DaemonError::InvalidGroup { .. }) => (),
+                other => return other,
+            }
         }
+        Err(DaemonError::InvalidGroup { names: names.iter().map(|name| (*name).to_owned()).collect(), source: None })
+    }
+
+    pub(crate) fn resolve_id<R: RecordResolver>(gid: u32, resolver: &R) -> Result<Group> {
+        let record = resolver.group_by_id(gid)?;
+        Ok(Group {
+            id: record.gr_gid,
+            name: record.gr_name
+        })
+    }
+}
+
+impl<'uname> TryFrom<&'uname str> for Group {
+    type Error = DaemonError;
+
+    fn try_from(gname: &'uname str) -> Result<Group> {
+        Group::resolve_name(gname, &SystemResolver)
     }
 }
 
@@ -29,13 +62,25 @@ impl TryFrom<&String> for Group {
     type Error = DaemonError;
 
     fn try_from(gname: &String) -> Result<Group> {
-        match GroupRecord::lookup_record_by_name(gname.as_str()) {
-            Ok(record) => Ok(Group {
-                id: record.gr_gid,
-                name: record.gr_name
-            }),
-            Err(_) => Err(DaemonError::InvalidGroup),
-        }
+        Group::try_from(gname.as_str())
+    }
+}
+
+/// The raw bytes of the name are looked up, so names that aren't valid UTF-8 resolve as well,
+/// the `name` of the group found then holds them with the invalid sequences replaced
+impl<'gname> TryFrom<&'gname OsStr> for Group {
+    type Error = DaemonError;
+
+    fn try_from(gname: &'gname OsStr) -> Result<Group> {
+        Group::resolve_name(gname, &SystemResolver)
+    }
+}
+
+impl TryFrom<&OsString> for Group {
+    type Error = DaemonError;
+
+    fn try_from(gname: &OsString) -> Result<Group> {
+        Group::try_from(gname.as_os_str())
     }
 }
 
@@ -43,11 +88,60 @@ impl TryFrom<u32> for Group {
     type Error = DaemonError;
 
     fn try_from(gid: u32) -> Result<Group> {
-        let record = GroupRecord::lookup_record_by_id(gid)?;
-        Ok(Group {
-            id: record.gr_gid,
-            name: record.gr_name
-        })
+        Group::resolve_id(gid, &SystemResolver)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::resolver::fake::FakeResolver;
+
+    use super::*;
+
+    fn fixtures() -> FakeResolver {
+        FakeResolver::default().with_group("staff", 50).with_group("100", 60)
+    }
+
+    #[test]
+    /// Asserts that names and gids resolve and missing groups are reported
+    fn test_resolve_group() {
+        let staff = Group::resolve_name("staff", &fixtures()).unwrap();
+        assert_eq!(staff, Group { id: 50, name: "staff".to_owned() });
+        assert_eq!(Group::resolve_id(50, &fixtures()).unwrap(), staff);
+        match Group::resolve_name("wheel", &fixtures()) {
+            Err(e @ DaemonError::InvalidGroup { .. }) => {
+                assert_eq!(e.to_string(), "Invalid or nonexistent group wheel");
+                assert!(std::error::Error::source(&e).is_some());
+            }
+            other => panic!("expected InvalidGroup, got {:?}", other),
+        }
+        assert!(matches!(Group::resolve_id(70, &fixtures()), Err(DaemonError::GetGrRecord)));
+    }
+
+    #[test]
+    /// Asserts that a numeric name is only taken as a gid when no group has that name
+    fn test_resolve_numeric_group_name() {
+        assert_eq!(Group::resolve_name("50", &fixtures()).unwrap().name, "staff");
+        assert_eq!(Group::resolve_name("100", &fixtures()).unwrap().id, 60);
+    }
+
+    #[test]
+    /// Asserts that names given as raw bytes are looked up as they are, valid UTF-8 or not
+    fn test_resolve_os_str_group_name() {
+        use std::os::unix::ffi::OsStrExt;
+
+        assert_eq!(Group::resolve_name(OsStr::new("staff"), &fixtures()).unwrap().id, 50);
+        let not_utf8 = OsStr::from_bytes(b"st\xffff");
+        assert!(matches!(Group::resolve_name(not_utf8, &fixtures()), Err(DaemonError::InvalidGroup { .. })));
+    }
+
+    #[test]
+    /// Asserts that the first existing group is picked and the attempted names are reported when none exists
+    fn test_resolve_first_available_group() {
+        assert_eq!(Group::resolve_first_available(&["nogroup", "staff"], &fixtures()).unwrap().id, 50);
+        match Group::resolve_first_available(&["nogroup", "wheel"], &fixtures()) {
+            Err(DaemonError::InvalidGroup { names, .. }) => assert_eq!(names, ["nogroup", "wheel"]),
+            other => panic!("expected InvalidGroup, got {:?}", other),
+        }
+    }
+}
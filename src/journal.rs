@@ -0,0 +1,170 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use nix::sys::stat::fstat;
+use nix::unistd::close;
+
+use crate::{DaemonError, Result};
+use crate::ffi::cloexec_pipe;
+
+/// The socket journald reads native protocol datagrams from, the one `sd_journal_print` sends to
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Sends every line written to a standard stream to the journal as one entry with the priority
+/// and fields configured, speaking the native protocol of journald like `sd_journal_print` does
+#[derive(Debug)]
+pub(crate) struct Journal {
+    priority: libc::c_int,
+    fields: Vec<(String, String)>,
+    socket: PathBuf,
+}
+
+impl Journal {
+    pub(crate) fn new(priority: libc::c_int, fields: &[(&str, &str)]) -> Result<Self> {
+        Journal::with_socket(priority, fields, Path::new(JOURNAL_SOCKET))
+    }
+
+    pub(crate) fn with_socket(priority: libc::c_int, fields: &[(&str, &str)], socket: &Path) -> Result<Self> {
+        if !(libc::LOG_EMERG..=libc::LOG_DEBUG).contains(&priority) {
+            return Err(DaemonError::InvalidJournalPriority { priority });
+        }
+        if let Some((name, _)) = fields.iter().find(|(name, _)| !valid_field_name(name)) {
+            return Err(DaemonError::InvalidJournalField { name: (*name).to_owned() });
+        }
+        Ok(Journal {
+            priority,
+            fields: fields.iter().map(|(name, value)| ((*name).to_owned(), (*value).to_owned())).collect(),
+            socket: socket.to_owned(),
+        })
+    }
+
+    /// Returns the fd `stream_fd` should write to and whether it is owned, the write end of a pipe read by
+    /// a thread forwarding its lines. When `stream_fd` already is the journal stream systemd set up
+    /// it is returned as it is, the entries then don't get the priority and fields set here
+    /// but nothing is logged twice
+    pub(crate) fn attach(&self, stream_fd: RawFd) -> Result<(RawFd, bool)> {
+        if connected_to_journal(stream_fd) {
+            return Ok((stream_fd, false));
+        }
+        let socket = match UnixDatagram::unbound().and_then(|socket| socket.connect(&self.socket).map(|_| socket)) {
+            Ok(socket) => socket,
+            Err(e) => return Err(DaemonError::Journal(e)),
+        };
+        let (read_end, write_end) = cloexec_pipe().map_err(DaemonError::Journal)?;
+        let header = self.header();
+        let reader = BufReader::new(unsafe { File::from_raw_fd(read_end) });
+        let spawned = thread::Builder::new().name("stdio-journal".into()).spawn(move || {
+            for line in reader.split(b'\n') {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => return,
+                };
+                let mut entry = header.clone();
+                append_field(&mut entry, "MESSAGE", &line);
+                // the journal may be restarting, the line is lost but the stream keeps flowing
+                let _ = socket.send(&entry);
+            }
+        });
+        if spawned.is_err() {
+            let _ = close(write_end);
+            return Err(DaemonError::Journal(std::io::Error::new(std::io::ErrorKind::Other, "failed to start the journal thread")));
+        }
+        Ok((write_end, true))
+    }
+
+    /// The fields every entry starts with
+    fn header(&self) -> Vec<u8> {
+        let mut header = Vec::new();
+        append_field(&mut header, "PRIORITY", self.priority.to_string().as_bytes());
+        for (name, value) in &self.fields {
+            append_field(&mut header, name, value.as_bytes());
+        }
+        header
+    }
+}
+
+/// Journal field names are upper case letters, digits and underscores not starting with an underscore,
+/// those starting with one are trusted fields only journald may set
+fn valid_field_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('_')
+        && name.bytes().all(|byte| byte.is_ascii_uppercase() || byte.is_ascii_digit() || byte == b'_')
+}
+
+/// Appends `NAME=value`, values holding a newline are framed by their length as the protocol wants
+fn append_field(entry: &mut Vec<u8>, name: &str, value: &[u8]) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains(&b'\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value);
+    entry.push(b'\n');
+}
+
+/// Whether `fd` is the stream systemd connected to the journal, named by `JOURNAL_STREAM` as `device:inode`
+fn connected_to_journal(fd: RawFd) -> bool {
+    let stream = match std::env::var("JOURNAL_STREAM") {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    let (dev, ino) = match stream.split_once(':') {
+        Some((dev, ino)) => (dev.parse::<libc::dev_t>(), ino.parse::<libc::ino_t>()),
+        None => return false,
+    };
+    match (fstat(fd), dev, ino) {
+        (Ok(st), Ok(dev), Ok(ino)) => st.st_dev == dev && st.st_ino == ino,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    /// Asserts that entries hold the priority, the fields and the message, framed when multi line
+    fn test_entry_encoding() {
+        let journal = Journal::with_socket(libc::LOG_INFO, &[("SYSLOG_IDENTIFIER", "svc")], Path::new("/nonexistent")).unwrap();
+        let mut entry = journal.header();
+        append_field(&mut entry, "MESSAGE", b"started");
+        assert_eq!(entry, b"PRIORITY=6\nSYSLOG_IDENTIFIER=svc\nMESSAGE=started\n");
+        let mut framed = Vec::new();
+        append_field(&mut framed, "MESSAGE", b"a\nb");
+        assert_eq!(framed, b"MESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n");
+
+        let bad_name = Journal::with_socket(libc::LOG_INFO, &[("_PID", "1")], Path::new("/nonexistent"));
+        assert!(matches!(bad_name, Err(DaemonError::InvalidJournalField { name }) if name == "_PID"));
+        assert!(matches!(Journal::new(8, &[]), Err(DaemonError::InvalidJournalPriority { priority: 8 })));
+    }
+
+    #[test]
+    /// Asserts that each line written to the attached fd arrives as one datagram on the journal socket
+    fn test_attach_forwards_lines() {
+        let path = std::env::temp_dir().join(format!("daemonize-me-journal-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let journald = UnixDatagram::bind(&path).unwrap();
+        journald.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let journal = Journal::with_socket(libc::LOG_ERR, &[("UNIT_TEST", "1")], &path).unwrap();
+        let (write_end, owned) = journal.attach(libc::STDOUT_FILENO).unwrap();
+        assert!(owned);
+        nix::unistd::write(write_end, b"first\nsecond\n").unwrap();
+        close(write_end).unwrap();
+        let mut buf = [0u8; 256];
+        for message in ["first", "second"] {
+            let n = journald.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..n], format!("PRIORITY=3\nUNIT_TEST=1\nMESSAGE={}\n", message).as_bytes());
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(journal.attach(libc::STDOUT_FILENO), Err(DaemonError::Journal(_))));
+    }
+}
@@ -14,62 +14,225 @@ use thiserror::Error;
 
 mod stdio;
 
+mod capability;
+mod credential;
+
 mod group;
 mod user;
 mod daemon;
 mod ffi;
+#[cfg(feature = "journal")]
+mod journal;
+mod pid_file;
+mod plan;
+mod resolver;
+mod privileges;
+mod profile;
+mod ring_buffer;
+mod rlimit;
+mod signal;
+mod status;
+mod takeover;
 
+pub use crate::capability::Capability;
+pub use crate::credential::Credential;
 pub use crate::group::Group;
-pub use crate::user::User;
-pub use crate::daemon::Daemon;
+pub use crate::user::{PasswdDetails, User};
+pub use crate::daemon::{daemonize, Daemon, DaemonOutcome, ForkStrategy, ParentBehavior, PidFileTiming, PreparedDaemon};
+pub use crate::ffi::raw_log;
+pub use crate::pid_file::{is_running, read_pid_file};
+pub use crate::plan::DaemonStep;
+pub use crate::privileges::{PrivilegeMode, with_privileges};
+pub use crate::profile::SecurityProfile;
+pub use crate::ring_buffer::{dump_ring_buffers, flush_stdio};
+pub use crate::rlimit::RlimitPhase;
+pub use crate::signal::SignalPipe;
+pub use crate::status::DaemonStatus;
+pub use crate::takeover::Takeover;
+pub use crate::stdio::{BufferMode, LogHandle, StdStream, Stdio};
 
 
 #[derive(Error, Debug)]
 pub enum DaemonError {
     #[error("This feature is unavailable, or not implemented for your target os")]
     UnsupportedOnOS,
+    #[error("The daemon was already started in this process")]
+    AlreadyDaemonized,
+    #[error("{count} threads are running, all but the one forking would be lost in the daemon")]
+    ThreadsRunning { count: usize },
     #[error("Unable to fork")]
-    Fork,
+    Fork(#[source] std::io::Error),
+    #[error("Failed to create the pipe used to wait for the parent to exit")]
+    ParentExitPipe(#[source] std::io::Error),
     #[error("Failed to chdir")]
-    ChDir,
+    ChDir(#[source] nix::errno::Errno),
+    #[error("Failed to regain root privileges, the saved uid is not root")]
+    RegainPrivileges,
+    #[error("Privileges are already held by an enclosing scope")]
+    ReentrantPrivileges,
+    #[error("Root could be regained after dropping privileges")]
+    PrivilegeDropFailed,
+    #[error("Failed to chroot")]
+    Chroot(#[source] nix::errno::Errno),
+    #[error("The work dir does not resolve to the same directory inside the chroot")]
+    ChrootWorkDir,
     #[error("Failed to open dev null")]
-    OpenDevNull,
-    #[error("Failed to close the file pointer of a stdio stream")]
-    CloseFp,
-    #[error("Invalid or nonexistent user")]
-    InvalidUser,
-    #[error("Invalid or nonexistent group")]
-    InvalidGroup,
-    #[error("Either group or user was specified but not the other")]
+    OpenDevNull(#[source] nix::errno::Errno),
+    #[error("/dev/null is not the null character device")]
+    DevNullInvalid,
+    #[error("Failed to close the file pointer of {stream}")]
+    CloseFp { stream: StdStream },
+    #[error("Invalid or nonexistent user{}", listed_names(.names))]
+    InvalidUser { names: Vec<String>, #[source] source: Option<Box<DaemonError>> },
+    #[error("The user account is locked or has a nologin shell")]
+    AccountLocked,
+    #[error("The daemon must be launched by {expected}")]
+    WrongLaunchUser { expected: String },
+    #[error("Invalid or nonexistent group{}", listed_names(.names))]
+    InvalidGroup { names: Vec<String>, #[source] source: Option<Box<DaemonError>> },
+    #[error("chown of the pid file needs both a user and a group; call .user(...) and .group(...)")]
     InvalidUserGroupPair,
+    #[error("user set but group missing; call .group(...) or .group_copy_user()")]
+    UserWithoutGroup,
+    #[error("group set but user missing; call .user(...)")]
+    GroupWithoutUser,
+    #[error("supplementary groups set but user missing; call .user(...)")]
+    SupplementaryGroupsWithoutUser,
+    #[error("capabilities to keep set but user missing; call .user(...)")]
+    CapabilitiesWithoutUser,
+    #[error("Failed to keep the capabilities across the privilege drop")]
+    KeepCapabilities(#[source] std::io::Error),
     #[error("The specified cstr is invalid")]
     InvalidCstr,
     #[error("Failed to execute initgroups")]
-    InitGroups,
-    #[error("Failed to set uid")]
-    SetUid,
-    #[error("Failed to set gid")]
-    SetGid,
-    #[error("Failed to chown the pid file")]
-    ChownPid,
+    InitGroups(#[source] std::io::Error),
+    #[error("Failed to set the supplementary groups{}", eperm_hint(*.0))]
+    SetGroups(#[source] nix::errno::Errno),
+    #[error("The supplementary groups were not applied as expected after dropping privileges")]
+    GroupVerificationFailed,
+    #[error("Failed to apply the login class")]
+    LoginClass,
+    #[error("Failed to set uid{}", eperm_hint(*.0))]
+    SetUid(#[source] nix::errno::Errno),
+    #[error("Failed to set gid{}", eperm_hint(*.0))]
+    SetGid(#[source] nix::errno::Errno),
+    #[error("Failed to chown the pid file{}", eperm_hint(*.0))]
+    ChownPid(#[source] nix::errno::Errno),
     #[error("Failed to create the pid file")]
-    OpenPid,
+    OpenPid(#[source] std::io::Error),
     #[error("Failed to write to the pid file")]
-    WritePid,
-    #[error("Failed to redirect the standard streams")]
-    RedirectStream,
+    WritePid(#[source] std::io::Error),
+    #[error("Failed to create or lock the lock file")]
+    OpenLockFile(#[source] std::io::Error),
+    #[error("Another instance is already running")]
+    AlreadyRunning,
+    #[error("Failed to read the pid file")]
+    ReadPid(#[source] std::io::Error),
+    #[error("The pid file does not contain a valid pid")]
+    InvalidPid,
+    #[error("Failed to create the ready file")]
+    CreateReadyFile(#[source] std::io::Error),
+    #[error("Failed to open the file to redirect a standard stream to")]
+    OpenStdioFile(#[source] std::io::Error),
+    #[error("Failed to create the pipe shared by the standard streams")]
+    SharedPipe(#[source] std::io::Error),
+    #[error("The environment variable {var} doesn't hold a file descriptor number")]
+    EnvFdParse { var: String },
+    #[error("File descriptor {fd} passed in {var} is not open")]
+    EnvFdClosed { var: String, fd: std::os::unix::io::RawFd },
+    #[error("File descriptor {fd} to keep open in the daemon is not open")]
+    KeepFd { fd: std::os::unix::io::RawFd },
+    #[error("File descriptor {fd} is a standard stream, redirect it instead of keeping it")]
+    KeepStdioFd { fd: std::os::unix::io::RawFd },
+    #[error("Failed to forward the stream to the journal")]
+    Journal(#[source] std::io::Error),
+    #[error("Journal priority {priority} is not a syslog level")]
+    InvalidJournalPriority { priority: libc::c_int },
+    #[error("Journal field name {name} is not upper case letters, digits and underscores")]
+    InvalidJournalField { name: String },
+    #[error("Failed to set up the stdio ring buffer")]
+    RingBuffer(#[source] std::io::Error),
+    #[error("Failed to dump the stdio ring buffer")]
+    DumpRingBuffer,
+    #[error("Timed out waiting for the stdio pump threads to flush")]
+    StdioFlushTimeout,
+    #[error("The credential command failed")]
+    CredentialCommand,
+    #[error("Failed to get the directory the daemon was launched from")]
+    LaunchDir(#[source] std::io::Error),
+    #[error("No pid file is set; call .pid_file(...) first")]
+    NoPidFile,
+    #[error("The pid doesn't fit in the zero padded width of the pid file")]
+    PidTooWide,
+    #[error("Failed to start the pid file heartbeat thread")]
+    PidFileHeartbeat(#[source] std::io::Error),
+    #[error("Failed to write the status file")]
+    WriteStatusFile(#[source] std::io::Error),
+    #[error("Failed to set the buffering mode of the standard streams")]
+    SetBuffering(#[source] std::io::Error),
+    #[error("Failed to duplicate a file descriptor")]
+    DupFd(#[source] std::io::Error),
+    #[error("Failed to redirect {stream}")]
+    RedirectStream { stream: StdStream, #[source] source: std::io::Error },
     #[error("Umask bits are invalid")]
     InvalidUmaskBits,
     #[error("Failed to set sid")]
-    SetSid,
+    SetSid(#[source] nix::errno::Errno),
     #[error("Failed to get groups record")]
     GetGrRecord,
     #[error("Failed to get passwd record")]
     GetPasswdRecord,
+    #[error("No such user in the passwd database")]
+    UserNotFound,
+    #[error("The passwd lookup failed")]
+    PasswdLookupFailed(#[source] nix::errno::Errno),
     #[error("Failed to set proc name")]
     SetProcName,
-    #[error("Failed to set proc name")]
+    #[error("Failed to rewrite argv[0] in place")]
+    SetArgv0,
+    #[error("Failed to set the {resource:?} resource limit")]
+    SetRlimit { resource: nix::sys::resource::Resource, #[source] source: std::io::Error },
+    #[error("The soft {resource:?} resource limit is above the hard one")]
+    RlimitSoftAboveHard { resource: nix::sys::resource::Resource },
+    #[error("Failed to set no_new_privs")]
+    SetNoNewPrivs(#[source] std::io::Error),
+    #[error("Failed to set up the admin socket {}", path.display())]
+    AdminSocket { path: std::path::PathBuf, #[source] source: std::io::Error },
+    #[error("Failed to unshare the mount namespace")]
+    MountNamespace(#[source] nix::errno::Errno),
+    #[error("Failed to mount {}", target.display())]
+    Mount { target: std::path::PathBuf, #[source] source: nix::errno::Errno },
+    #[error("Failed to change to the AppArmor profile {profile}")]
+    AppArmor { profile: String, #[source] source: std::io::Error },
+    #[error("Failed to make the daemon a child subreaper")]
+    SetChildSubreaper(#[source] std::io::Error),
+    #[error("Failed to set up the signal pipe")]
+    SignalPipe(#[source] std::io::Error),
+    #[error("Failed to signal the old instance")]
+    SignalOldInstance(#[source] nix::errno::Errno),
+    #[error("The old instance is still running after SIGKILL")]
+    OldInstanceStuck,
+    #[error("The proc name is longer than the OS keeps or contains a NUL byte")]
     InvalidProcName,
 }
 
+/// The names an account was looked up by, for the messages of `InvalidUser` and `InvalidGroup`
+fn listed_names(names: &[String]) -> String {
+    match names {
+        [] => String::new(),
+        [name] => format!(" {}", name),
+        names => format!(", tried {}", names.join(", ")),
+    }
+}
+
+/// Privilege operations failing with EPERM almost always mean the program wasn't started as root
+fn eperm_hint(errno: nix::errno::Errno) -> &'static str {
+    match errno {
+        nix::errno::Errno::EPERM => {
+            " (insufficient privileges, daemonization usually requires starting as root to drop to another user)"
+        }
+        _ => "",
+    }
+}
+
 pub type Result<T> = std::result::Result<T, DaemonError>;
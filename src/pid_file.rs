@@ -0,0 +1,366 @@
+use std::fs::{File, OpenOptions};
+use std::ffi::OsString;
+use std::io::prelude::*;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, flock, FlockArg};
+use nix::sys::signal::kill;
+use nix::unistd::{fchown, Gid, Pid, Uid};
+
+use crate::{DaemonError, Result};
+
+/// Formats the pid without any whitespace, zero padded to `zero_pad` digits when set
+pub(crate) fn format_pid(pid: Pid, zero_pad: Option<usize>) -> Result<String> {
+    match zero_pad {
+        Some(width) => {
+            let digits = format!("{:0width$}", pid.as_raw(), width = width);
+            if digits.len() > width {
+                return Err(DaemonError::PidTooWide);
+            }
+            Ok(digits)
+        }
+        None => Ok(pid.to_string()),
+    }
+}
+
+/// Writes the pid file, either replacing it with one containing only the pid or appending a
+/// `{timestamp} {pid}` line to the run history kept in it, `newline` ends a lone pid with `\n` as history lines are.
+/// A replaced pid file is written to `{path}.tmp` and renamed over `path`, so readers see either the previous
+/// pid or the complete new one, never an empty file. `in_place` truncates and rewrites the file itself instead,
+/// this is needed when the pid file is the one locked since the lock belongs to the inode.
+/// With an `owner` the open file is handed over before anything is written, the pid is then synced to disk,
+/// so a failed chown never leaves a written root owned pid file behind
+pub(crate) fn write_pid_file(
+    path: &Path,
+    pid: Pid,
+    append_history: bool,
+    in_place: bool,
+    zero_pad: Option<usize>,
+    newline: bool,
+    owner: Option<(Uid, Gid)>,
+) -> Result<()> {
+    let pid = format_pid(pid, zero_pad)?;
+    let replace = !append_history && !in_place;
+    let tmp_path = temp_sibling(path);
+    let fp = if append_history {
+        OpenOptions::new().create(true).append(true).open(path)
+    } else if in_place {
+        File::create(path)
+    } else {
+        File::create(&tmp_path)
+    };
+    let contents = if append_history {
+        let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs(),
+            Err(_) => 0,
+        };
+        format!("{} {}\n", timestamp, pid)
+    } else if newline {
+        format!("{}\n", pid)
+    } else {
+        pid
+    };
+    let mut fp = match fp {
+        Ok(fp) => fp,
+        Err(e) => return Err(DaemonError::OpenPid(e)),
+    };
+    let mut written = match owner {
+        Some((uid, gid)) => fchown(fp.as_raw_fd(), Some(uid), Some(gid)).map_err(DaemonError::ChownPid),
+        None => Ok(()),
+    };
+    if written.is_ok() {
+        written = fp.write_all(contents.as_bytes()).and_then(|_| fp.sync_all()).map_err(DaemonError::WritePid);
+    }
+    if written.is_ok() && replace {
+        written = std::fs::rename(&tmp_path, path).map_err(DaemonError::WritePid);
+    }
+    if written.is_err() && replace {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    written
+}
+
+/// The temporary file a file is written to before it is renamed over it, `{path}.tmp`
+fn temp_sibling(path: &Path) -> PathBuf {
+    let mut tmp_path = OsString::from(path.as_os_str());
+    tmp_path.push(".tmp");
+    PathBuf::from(tmp_path)
+}
+
+/// Writes `contents` to a temporary sibling of `path` and renames it over `path`,
+/// readers see either the previous contents or the complete new ones, never a partial write
+#[cfg(feature = "json")]
+pub(crate) fn write_file_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let tmp_path = temp_sibling(path);
+    let mut fp = File::create(&tmp_path)?;
+    fp.write_all(contents)?;
+    fp.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Touches the mtime of the pid file every `interval` from a dedicated thread for as long as the daemon runs,
+/// the file is opened right away so the path doesn't need to stay reachable
+pub(crate) fn start_heartbeat(path: &Path, interval: Duration) -> Result<()> {
+    let fp = match File::open(path) {
+        Ok(fp) => fp,
+        Err(e) => return Err(DaemonError::OpenPid(e)),
+    };
+    let spawned = thread::Builder::new().name("pid-file-heartbeat".into()).spawn(move || loop {
+        thread::sleep(interval);
+        // null times means both are set to the current time, this only needs write access
+        unsafe { libc::futimens(fp.as_raw_fd(), std::ptr::null()) };
+    });
+    match spawned {
+        Ok(_) => Ok(()),
+        Err(e) => Err(DaemonError::PidFileHeartbeat(e)),
+    }
+}
+
+/// Reads the pid of the current or last instance from a pid file in either format, `None` when there is
+/// no pid file. A pid of 0 or below fails with `InvalidPid`. Combined with [`is_running`] this is enough
+/// for a `--status` command
+pub fn read_pid_file<T: AsRef<Path>>(path: T) -> Result<Option<Pid>> {
+    let path = path.as_ref();
+    match std::fs::metadata(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        _ => read_current_pid(path).map(Some),
+    }
+}
+
+/// Whether a process with this pid exists, tested with signal 0 so nothing is delivered.
+/// A process owned by another user counts as running, a zombie waiting to be reaped has exited already.
+/// A pid of 0 or below never is, signal 0 would test a process group instead
+pub fn is_running(pid: Pid) -> bool {
+    if pid.as_raw() <= 0 {
+        return false;
+    }
+    // EPERM means the process exists but belongs to someone else
+    match kill(pid, None) {
+        Ok(_) | Err(Errno::EPERM) => !is_zombie(pid),
+        Err(_) => false,
+    }
+}
+
+/// The state field of `/proc/[pid]/stat`, only known where `/proc` is available
+fn is_zombie(pid: Pid) -> bool {
+    let stat = match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat,
+        Err(_) => return false,
+    };
+    // the command name may contain spaces and parentheses, the state follows the last ')'
+    match stat.rfind(')') {
+        Some(end) => stat[end + 1..].split_whitespace().next() == Some("Z"),
+        None => false,
+    }
+}
+
+/// Reads the current pid from a pid file, that is the last pid written to it,
+/// this works for both the plain and the run history formats
+pub(crate) fn read_current_pid(path: &Path) -> Result<Pid> {
+    read_current_entry(path).map(|(_, pid)| pid)
+}
+
+/// Reads the last entry of a pid file, the start timestamp is only known in the run history format
+pub(crate) fn read_current_entry(path: &Path) -> Result<(Option<u64>, Pid)> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut fp) => {
+            if let Err(e) = fp.read_to_string(&mut contents) {
+                return Err(DaemonError::ReadPid(e));
+            }
+        }
+        Err(e) => return Err(DaemonError::ReadPid(e)),
+    };
+    let last_line = contents.lines().rev().find(|line| !line.trim().is_empty());
+    let fields: Vec<&str> = last_line.map(|line| line.split_whitespace().collect()).unwrap_or_default();
+    let (timestamp, pid) = match fields.as_slice() {
+        [pid] => (None, pid),
+        [.., timestamp, pid] => (timestamp.parse::<u64>().ok(), pid),
+        [] => return Err(DaemonError::InvalidPid),
+    };
+    // 0 and negative pids address process groups or every process when signalled
+    match pid.parse::<i32>() {
+        Ok(pid) if pid > 0 => Ok((timestamp, Pid::from_raw(pid))),
+        _ => Err(DaemonError::InvalidPid),
+    }
+}
+
+/// Path of the lock file sibling of a pid file, `{pid_file}.lock`
+pub(crate) fn lock_file_path(pid_file: &Path) -> PathBuf {
+    let mut path = OsString::from(pid_file.as_os_str());
+    path.push(".lock");
+    PathBuf::from(path)
+}
+
+/// Creates the file if needed and takes an exclusive `flock` on it, the lock lives as long as the
+/// returned file, or any fd duplicated from it for instance by forking, stays open
+pub(crate) fn lock_file(path: &Path) -> Result<File> {
+    let mut fp = match OpenOptions::new().create(true).write(true).truncate(false).open(path) {
+        Ok(fp) => fp,
+        Err(e) => return Err(DaemonError::OpenLockFile(e)),
+    };
+    // with a standard stream closed the lock could land on it and be lost when the streams are redirected
+    if fp.as_raw_fd() <= libc::STDERR_FILENO {
+        fp = match fcntl(fp.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(libc::STDERR_FILENO + 1)) {
+            Ok(high_fd) => unsafe { File::from_raw_fd(high_fd) },
+            Err(errno) => return Err(DaemonError::OpenLockFile(errno.into())),
+        };
+    }
+    match flock(fp.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(_) => Ok(fp),
+        Err(Errno::EWOULDBLOCK) => Err(DaemonError::AlreadyRunning),
+        Err(errno) => Err(DaemonError::OpenLockFile(errno.into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::remove_file;
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn temp_pid_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("daemonize-me-{}-{}.pid", name, std::process::id()))
+    }
+
+    #[test]
+    /// Asserts that pids are zero padded to the width and refused when they don't fit
+    fn test_format_pid() {
+        assert_eq!(format_pid(Pid::from_raw(42), None).unwrap(), "42");
+        assert_eq!(format_pid(Pid::from_raw(42), Some(5)).unwrap(), "00042");
+        assert_eq!(format_pid(Pid::from_raw(12345), Some(5)).unwrap(), "12345");
+        assert!(matches!(format_pid(Pid::from_raw(123456), Some(5)), Err(DaemonError::PidTooWide)));
+    }
+
+    #[test]
+    /// Asserts that the plain format holds only the last pid written
+    fn test_plain_pid_file() {
+        let path = temp_pid_path("plain");
+        write_pid_file(&path, Pid::from_raw(41), false, false, None, false, None).unwrap();
+        write_pid_file(&path, Pid::from_raw(42), false, false, None, false, None).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "42");
+        assert_eq!(read_current_pid(&path).unwrap(), Pid::from_raw(42));
+        write_pid_file(&path, Pid::from_raw(43), false, false, None, true, None).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "43\n");
+        assert_eq!(read_current_pid(&path).unwrap(), Pid::from_raw(43));
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    /// Asserts that a replaced pid file is swapped in whole while a locked one keeps its inode
+    fn test_pid_file_replaced_atomically() {
+        use std::os::unix::fs::MetadataExt;
+
+        let path = temp_pid_path("replaced");
+        write_pid_file(&path, Pid::from_raw(41), false, false, None, false, None).unwrap();
+        let mut reader = File::open(&path).unwrap();
+        write_pid_file(&path, Pid::from_raw(42), false, false, None, false, None).unwrap();
+        // a reader that opened the previous file still sees all of it
+        let mut previous = String::new();
+        reader.read_to_string(&mut previous).unwrap();
+        assert_eq!(previous, "41");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "42");
+        assert!(!temp_sibling(&path).exists());
+
+        let inode = std::fs::metadata(&path).unwrap().ino();
+        write_pid_file(&path, Pid::from_raw(43), false, true, None, false, None).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().ino(), inode);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "43");
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the history format keeps every start and the current pid is the last line
+    fn test_history_pid_file() {
+        let path = temp_pid_path("history");
+        let _ = remove_file(&path);
+        write_pid_file(&path, Pid::from_raw(41), true, false, None, false, None).unwrap();
+        write_pid_file(&path, Pid::from_raw(42), true, false, None, false, None).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 2);
+        assert_eq!(read_current_pid(&path).unwrap(), Pid::from_raw(42));
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the pid file already belongs to the daemon user once written, as after dropping to nobody
+    fn test_pid_file_owner() {
+        use std::os::unix::fs::MetadataExt;
+
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let path = temp_pid_path("owner");
+        let _ = remove_file(&path);
+        let owner = (Uid::from_raw(65534), Gid::from_raw(65534));
+        write_pid_file(&path, Pid::from_raw(42), false, false, None, false, Some(owner)).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!((metadata.uid(), metadata.gid()), (65534, 65534));
+        assert_eq!(read_current_pid(&path).unwrap(), Pid::from_raw(42));
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "json")]
+    /// Asserts that an atomic write replaces the contents and leaves no temporary file behind
+    fn test_write_file_atomically() {
+        let path = temp_pid_path("atomic");
+        write_file_atomically(&path, b"41").unwrap();
+        write_file_atomically(&path, b"42").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "42");
+        assert!(!path.with_extension("pid.tmp").exists());
+        remove_file(&path).unwrap();
+    }
+
+    #[test]
+    /// Asserts that a missing pid file is no pid and that liveness follows the process
+    fn test_read_pid_file_is_running() {
+        let path = temp_pid_path("status");
+        let _ = remove_file(&path);
+        assert_eq!(read_pid_file(&path).unwrap(), None);
+        write_pid_file(&path, nix::unistd::getpid(), false, false, None, false, None).unwrap();
+        let pid = read_pid_file(&path).unwrap().unwrap();
+        assert!(is_running(pid));
+        std::fs::write(&path, "not a pid").unwrap();
+        assert!(read_pid_file(&path).is_err());
+        for contents in ["0", "-1", "1700000000 0\n"] {
+            std::fs::write(&path, contents).unwrap();
+            assert!(matches!(read_pid_file(&path), Err(DaemonError::InvalidPid)));
+        }
+        assert!(!is_running(Pid::from_raw(0)));
+        assert!(!is_running(Pid::from_raw(-1)));
+        remove_file(&path).unwrap();
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = Pid::from_raw(child.id() as i32);
+        child.wait().unwrap();
+        assert!(!is_running(pid));
+    }
+
+    #[test]
+    /// Asserts that the io error behind a failure is reachable through the source chain
+    fn test_error_source() {
+        use std::error::Error;
+
+        let err = read_current_pid(&temp_pid_path("no-such-pid-file")).unwrap_err();
+        let source = err.source().and_then(|source| source.downcast_ref::<std::io::Error>());
+        assert_eq!(source.map(std::io::Error::kind), Some(std::io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    /// Asserts that a lock file can't be locked twice while the first lock is held
+    fn test_lock_file() {
+        let path = lock_file_path(&temp_pid_path("lock"));
+        assert!(path.to_string_lossy().ends_with(".pid.lock"));
+        let held = lock_file(&path).unwrap();
+        assert!(matches!(lock_file(&path), Err(DaemonError::AlreadyRunning)));
+        drop(held);
+        assert!(lock_file(&path).is_ok());
+        remove_file(&path).unwrap();
+    }
+}
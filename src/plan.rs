@@ -0,0 +1,112 @@
+use std::ffi::OsString;
+use std::fmt::{self, Display};
+use std::path::PathBuf;
+
+use nix::sys::resource::Resource;
+
+use crate::capability::Capability;
+
+/// One operation `start` performs, in the order returned by [`crate::Daemon::plan`].
+/// Only the operations with an effect outside of the process memory are listed, hooks are not.
+/// The display form reads like the call made, eg. `setgid(120)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DaemonStep {
+    /// Take the lock next to the pid file
+    LockPidFile(PathBuf),
+    /// Bind the admin socket and set its mode and owner
+    BindAdminSocket(PathBuf),
+    /// Run the credential command
+    RunCredentialCommand(OsString),
+    /// Fork the daemon process
+    Fork,
+    /// Execute the program again as the daemon process, see [`crate::ForkStrategy::Reexec`]
+    Reexec,
+    /// Close the inherited fds above stderr except the ones listed
+    CloseFds { keep: Vec<i32> },
+    /// Redirect the standard streams
+    RedirectStdio,
+    SetProcName(OsString),
+    /// Cut argv[0] down to its basename
+    SetArgv0Basename,
+    Umask(u16),
+    Setsid,
+    SetChildSubreaper,
+    Chdir(PathBuf),
+    WritePidFile(PathBuf),
+    SetLoginClass(String),
+    SetRlimit { resource: Resource, soft: Option<libc::rlim_t>, hard: Option<libc::rlim_t> },
+    ChownPidFile { path: PathBuf, uid: u32, gid: u32 },
+    Setgid(u32),
+    Setegid(u32),
+    Setresgid([u32; 3]),
+    Initgroups { user: String, gid: u32 },
+    Setgroups(Vec<u32>),
+    /// Move into a mount namespace of its own
+    UnshareMountNamespace,
+    Mount { source: String, target: PathBuf, fstype: String },
+    Chroot(PathBuf),
+    Setuid(u32),
+    Seteuid(u32),
+    Setresuid([u32; 3]),
+    /// Narrow the capabilities down to these and raise them in the ambient set
+    KeepCapabilities(Vec<Capability>),
+    ChangeApparmorProfile(String),
+    SetNoNewPrivs,
+    CreateReadyFile(PathBuf),
+}
+
+/// Formats a limit the way `ulimit` does
+pub(crate) struct Limit(pub(crate) Option<libc::rlim_t>);
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(limit) => write!(f, "{}", limit),
+            None => f.write_str("unlimited"),
+        }
+    }
+}
+
+impl Display for DaemonStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DaemonStep::LockPidFile(path) => write!(f, "lock({})", path.display()),
+            DaemonStep::BindAdminSocket(path) => write!(f, "bind({})", path.display()),
+            DaemonStep::RunCredentialCommand(program) => write!(f, "run({})", program.to_string_lossy()),
+            DaemonStep::Fork => f.write_str("fork()"),
+            DaemonStep::Reexec => f.write_str("reexec()"),
+            DaemonStep::CloseFds { keep } => write!(f, "close_fds(3.., keep {:?})", keep),
+            DaemonStep::RedirectStdio => f.write_str("redirect_stdio()"),
+            DaemonStep::SetProcName(name) => write!(f, "set_proc_name({})", name.to_string_lossy()),
+            DaemonStep::SetArgv0Basename => f.write_str("set_argv0_basename()"),
+            DaemonStep::Umask(mask) => write!(f, "umask({:#o})", mask),
+            DaemonStep::Setsid => f.write_str("setsid()"),
+            DaemonStep::SetChildSubreaper => f.write_str("set_child_subreaper()"),
+            DaemonStep::Chdir(path) => write!(f, "chdir({})", path.display()),
+            DaemonStep::WritePidFile(path) => write!(f, "write_pid_file({})", path.display()),
+            DaemonStep::SetLoginClass(class) => write!(f, "setusercontext({})", class),
+            DaemonStep::SetRlimit { resource, soft, hard } => {
+                write!(f, "setrlimit({:?}, {}, {})", resource, Limit(*soft), Limit(*hard))
+            }
+            DaemonStep::ChownPidFile { path, uid, gid } => write!(f, "chown({}, {}, {})", path.display(), uid, gid),
+            DaemonStep::Setgid(gid) => write!(f, "setgid({})", gid),
+            DaemonStep::Setegid(gid) => write!(f, "setegid({})", gid),
+            DaemonStep::Setresgid([real, effective, saved]) => write!(f, "setresgid({}, {}, {})", real, effective, saved),
+            DaemonStep::Initgroups { user, gid } => write!(f, "initgroups({}, {})", user, gid),
+            DaemonStep::Setgroups(gids) => write!(f, "setgroups({:?})", gids),
+            DaemonStep::UnshareMountNamespace => f.write_str("unshare(CLONE_NEWNS)"),
+            DaemonStep::Mount { source, target, fstype } => write!(f, "mount({}, {}, {})", source, target.display(), fstype),
+            DaemonStep::Chroot(path) => write!(f, "chroot({})", path.display()),
+            DaemonStep::Setuid(uid) => write!(f, "setuid({})", uid),
+            DaemonStep::Seteuid(uid) => write!(f, "seteuid({})", uid),
+            DaemonStep::Setresuid([real, effective, saved]) => write!(f, "setresuid({}, {}, {})", real, effective, saved),
+            DaemonStep::KeepCapabilities(capabilities) => {
+                let names: Vec<&str> = capabilities.iter().map(|capability| capability.as_str()).collect();
+                write!(f, "capset({})", names.join(", "))
+            }
+            DaemonStep::ChangeApparmorProfile(profile) => write!(f, "aa_change_profile({})", profile),
+            DaemonStep::SetNoNewPrivs => f.write_str("set_no_new_privs()"),
+            DaemonStep::CreateReadyFile(path) => write!(f, "create({})", path.display()),
+        }
+    }
+}
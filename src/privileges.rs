@@ -0,0 +1,168 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(any(target_os = "android", target_os = "freebsd", target_os = "linux", target_os = "openbsd"))]
+use nix::unistd::{setresgid, setresuid, Gid};
+use nix::unistd::{geteuid, seteuid, setuid, Uid};
+
+use crate::{DaemonError, Result};
+
+/// Set while a [`with_privileges`] scope is running, nested scopes are refused
+static PRIVILEGED_SCOPE: AtomicBool = AtomicBool::new(false);
+
+/// How privileges are dropped when a user and group are set, see [`crate::Daemon::privilege_mode`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrivilegeMode {
+    /// `setgid`/`setuid`, root can never be regained, this is the default
+    Permanent,
+    /// `setegid`/`seteuid`, only the effective ids change and the saved uid stays root so
+    /// privileges can be regained for short scoped operations with [`with_privileges`]
+    Temporary,
+}
+
+/// Puts the effective uid back when the privileged scope ends, even by unwinding
+struct PrivilegeGuard {
+    euid: Uid,
+    restored: bool,
+}
+
+impl PrivilegeGuard {
+    fn restore(&mut self) -> Result<()> {
+        self.restored = true;
+        let result = seteuid(self.euid);
+        PRIVILEGED_SCOPE.store(false, Ordering::SeqCst);
+        match result {
+            Ok(_) => Ok(()),
+            Err(errno) => Err(DaemonError::SetUid(errno)),
+        }
+    }
+}
+
+impl Drop for PrivilegeGuard {
+    fn drop(&mut self) {
+        if !self.restored {
+            // Carrying on as root after a panic would be worse than not carrying on at all
+            if seteuid(self.euid).is_err() {
+                std::process::abort();
+            }
+            PRIVILEGED_SCOPE.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Run `f` as root and drop back to the service effective uid afterwards, even if `f` panics.
+/// This only works for daemons started in [`PrivilegeMode::Temporary`] where the saved uid is still root,
+/// the typical use is a periodic privileged task such as renewing a certificate owned by root.
+/// Scopes can't be nested, a call from inside `f` fails with [`DaemonError::ReentrantPrivileges`]
+pub fn with_privileges<R, F: FnOnce() -> R>(f: F) -> Result<R> {
+    if PRIVILEGED_SCOPE.swap(true, Ordering::SeqCst) {
+        return Err(DaemonError::ReentrantPrivileges);
+    }
+    let mut guard = PrivilegeGuard { euid: geteuid(), restored: true };
+    if seteuid(Uid::from_raw(0)).is_err() {
+        PRIVILEGED_SCOPE.store(false, Ordering::SeqCst);
+        return Err(DaemonError::RegainPrivileges);
+    }
+    guard.restored = false;
+    let result = f();
+    guard.restore()?;
+    Ok(result)
+}
+
+/// Checks that a permanent drop from root sticks: setuid(0) has to fail now. If it succeeds the real or
+/// saved uid was still root, the process is root again and the daemon must not carry on
+pub(crate) fn verify_privilege_drop() -> Result<()> {
+    match setuid(Uid::from_raw(0)) {
+        Ok(_) => Err(DaemonError::PrivilegeDropFailed),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "freebsd", target_os = "linux", target_os = "openbsd"))]
+/// Sets the real, effective and saved gids with setresgid(2)
+pub(crate) fn set_resgid([real, effective, saved]: [u32; 3]) -> Result<()> {
+    match setresgid(Gid::from_raw(real), Gid::from_raw(effective), Gid::from_raw(saved)) {
+        Ok(_) => Ok(()),
+        Err(errno) => Err(DaemonError::SetGid(errno)),
+    }
+}
+
+#[cfg(not(any(target_os = "android", target_os = "freebsd", target_os = "linux", target_os = "openbsd")))]
+pub(crate) fn set_resgid(_ids: [u32; 3]) -> Result<()> {
+    Err(DaemonError::UnsupportedOnOS)
+}
+
+#[cfg(any(target_os = "android", target_os = "freebsd", target_os = "linux", target_os = "openbsd"))]
+/// Sets the real, effective and saved uids with setresuid(2)
+pub(crate) fn set_resuid([real, effective, saved]: [u32; 3]) -> Result<()> {
+    match setresuid(Uid::from_raw(real), Uid::from_raw(effective), Uid::from_raw(saved)) {
+        Ok(_) => Ok(()),
+        Err(errno) => Err(DaemonError::SetUid(errno)),
+    }
+}
+
+#[cfg(not(any(target_os = "android", target_os = "freebsd", target_os = "linux", target_os = "openbsd")))]
+pub(crate) fn set_resuid(_ids: [u32; 3]) -> Result<()> {
+    Err(DaemonError::UnsupportedOnOS)
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    use super::*;
+
+    /// Runs `test` in a forked child with the effective uid dropped to nobody and returns its exit code
+    fn as_nobody(test: fn() -> bool) -> i32 {
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => match waitpid(child, None).unwrap() {
+                WaitStatus::Exited(_, code) => code,
+                _ => -1,
+            },
+            ForkResult::Child => {
+                let ok = seteuid(Uid::from_raw(65534)).is_ok() && test();
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+        }
+    }
+
+    #[test]
+    /// Asserts that root is held inside the scope only
+    fn test_with_privileges() {
+        if !geteuid().is_root() {
+            return;
+        }
+        let code = as_nobody(|| {
+            matches!(with_privileges(geteuid), Ok(uid) if uid.is_root()) && geteuid().as_raw() == 65534
+        });
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    /// Asserts that a drop leaving the saved uid at root is caught and a full setuid drop passes
+    fn test_verify_privilege_drop() {
+        if !geteuid().is_root() {
+            return;
+        }
+        assert_eq!(as_nobody(|| matches!(verify_privilege_drop(), Err(DaemonError::PrivilegeDropFailed))), 0);
+        assert_eq!(as_nobody(|| seteuid(Uid::from_raw(0)).is_ok() && setuid(Uid::from_raw(65534)).is_ok()
+            && verify_privilege_drop().is_ok()), 0);
+    }
+
+    #[test]
+    /// Asserts that nested scopes are refused and a panicking scope still drops back
+    fn test_with_privileges_reentrancy_and_panic() {
+        if !geteuid().is_root() {
+            return;
+        }
+        let code = as_nobody(|| {
+            let nested = with_privileges(|| with_privileges(|| ()));
+            if !matches!(nested, Ok(Err(DaemonError::ReentrantPrivileges))) {
+                return false;
+            }
+            let unwound = std::panic::catch_unwind(|| with_privileges(|| panic!("privileged task failed")));
+            unwound.is_err() && geteuid().as_raw() == 65534 && with_privileges(|| ()).is_ok()
+        });
+        assert_eq!(code, 0);
+    }
+}
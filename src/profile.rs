@@ -0,0 +1,146 @@
+use nix::sys::resource::Resource;
+use nix::sys::stat::Mode;
+
+use crate::capability::Capability;
+use crate::group::Group;
+use crate::rlimit::{Rlimit, RlimitPhase};
+use crate::user::User;
+use crate::{DaemonError, Result};
+
+/// The identity and hardening options of a daemon bundled for reuse, so several daemons can share one
+/// definition: build it once, check it with [`SecurityProfile::validate_profile`] and hand a clone
+/// to [`crate::Daemon::apply_profile`] for each of them.
+/// Each option behaves like the `Daemon` setter of the same name
+#[derive(Debug, Clone, Default)]
+pub struct SecurityProfile {
+    pub(crate) user: Option<User>,
+    pub(crate) group: Option<Group>,
+    pub(crate) supplementary_groups: Vec<Group>,
+    pub(crate) capabilities: Vec<Capability>,
+    pub(crate) umask: Option<u16>,
+    pub(crate) rlimits: Vec<Rlimit>,
+    pub(crate) no_new_privs: Option<bool>,
+}
+
+impl SecurityProfile {
+    pub fn new() -> Self {
+        SecurityProfile::default()
+    }
+
+    pub fn user<T: Into<User>>(mut self, user: T) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    pub fn group<T: Into<Group>>(mut self, group: T) -> Self {
+        self.group = Some(group.into());
+        self
+    }
+
+    pub fn supplementary_groups(mut self, groups: Vec<Group>) -> Self {
+        self.supplementary_groups = groups;
+        self
+    }
+
+    pub fn keep_capabilities(mut self, capabilities: Vec<Capability>) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    pub fn umask(mut self, mask: u16) -> Self {
+        self.umask = Some(mask);
+        self
+    }
+
+    pub fn rlimit(mut self, resource: Resource, soft: Option<libc::rlim_t>, hard: Option<libc::rlim_t>, when: RlimitPhase) -> Self {
+        self.rlimits.retain(|limit| limit.resource != resource);
+        self.rlimits.push(Rlimit { resource, soft, hard, when });
+        self
+    }
+
+    pub fn no_new_privs(mut self, enable: bool) -> Self {
+        self.no_new_privs = Some(enable);
+        self
+    }
+
+    /// Checks the whole profile at once, before any daemon uses it: the user and group come in pairs,
+    /// supplementary groups and capabilities come with a user, the umask bits are valid, no soft limit is above its hard limit and every option is supported here
+    pub fn validate_profile(&self) -> Result<()> {
+        match (&self.user, &self.group) {
+            (Some(_), None) => return Err(DaemonError::UserWithoutGroup),
+            (None, Some(_)) => return Err(DaemonError::GroupWithoutUser),
+            (None, None) if !self.supplementary_groups.is_empty() => return Err(DaemonError::SupplementaryGroupsWithoutUser),
+            (None, None) if !self.capabilities.is_empty() => return Err(DaemonError::CapabilitiesWithoutUser),
+            _ => (),
+        }
+        if let Some(mask) = self.umask {
+            if Mode::from_bits(mask as _).is_none() {
+                return Err(DaemonError::InvalidUmaskBits);
+            }
+        }
+        for limit in &self.rlimits {
+            if let (Some(soft), Some(hard)) = (limit.soft, limit.hard) {
+                if soft > hard {
+                    return Err(DaemonError::RlimitSoftAboveHard { resource: limit.resource });
+                }
+            } else if limit.soft.is_none() && limit.hard.is_some() {
+                return Err(DaemonError::RlimitSoftAboveHard { resource: limit.resource });
+            }
+        }
+        if cfg!(target_os = "macos") && !self.supplementary_groups.is_empty() {
+            return Err(DaemonError::UnsupportedOnOS);
+        }
+        if cfg!(not(target_os = "linux")) && (self.no_new_privs == Some(true) || !self.capabilities.is_empty()) {
+            return Err(DaemonError::UnsupportedOnOS);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Daemon;
+
+    use super::*;
+
+    fn svc_profile() -> SecurityProfile {
+        SecurityProfile::new()
+            .user(User { id: 113, name: "svc".to_owned() })
+            .group(Group { id: 120, name: "svc".to_owned() })
+            .umask(0o027)
+            .rlimit(Resource::RLIMIT_NOFILE, Some(1024), Some(4096), RlimitPhase::BeforePrivilegeDrop)
+    }
+
+    #[test]
+    /// Asserts that inconsistent profiles are refused as a whole
+    fn test_validate_profile() {
+        assert!(svc_profile().validate_profile().is_ok());
+        let no_group = SecurityProfile { group: None, ..svc_profile() };
+        assert!(matches!(no_group.validate_profile(), Err(DaemonError::UserWithoutGroup)));
+        let groups_only = SecurityProfile::new().supplementary_groups(vec![Group { id: 4242, name: "extra".to_owned() }]);
+        assert!(matches!(groups_only.validate_profile(), Err(DaemonError::SupplementaryGroupsWithoutUser)));
+        let capabilities_only = SecurityProfile::new().keep_capabilities(vec![Capability::NetBindService]);
+        assert!(matches!(capabilities_only.validate_profile(), Err(DaemonError::CapabilitiesWithoutUser)));
+        assert!(matches!(svc_profile().umask(0o10000).validate_profile(), Err(DaemonError::InvalidUmaskBits)));
+        let inverted = svc_profile().rlimit(Resource::RLIMIT_NOFILE, Some(8192), Some(4096), RlimitPhase::BeforePrivilegeDrop);
+        assert!(matches!(inverted.validate_profile(), Err(DaemonError::RlimitSoftAboveHard { .. })));
+        let unlimited_soft = svc_profile().rlimit(Resource::RLIMIT_NOFILE, None, Some(4096), RlimitPhase::BeforePrivilegeDrop);
+        assert!(matches!(unlimited_soft.validate_profile(), Err(DaemonError::RlimitSoftAboveHard { .. })));
+    }
+
+    #[test]
+    /// Asserts that applying a profile sets every option it holds on the daemon
+    fn test_apply_profile() {
+        let profile = svc_profile().keep_capabilities(vec![Capability::NetBindService]).no_new_privs(true);
+        let daemon = Daemon::new().umask(0o077).apply_profile(profile);
+        assert_eq!(daemon.user.as_ref().map(|user| user.id), Some(113));
+        assert_eq!(daemon.group.as_ref().map(|group| group.id), Some(120));
+        assert_eq!(daemon.umask, 0o027);
+        assert_eq!(daemon.rlimits.len(), 1);
+        assert_eq!(daemon.capabilities, vec![Capability::NetBindService]);
+        assert!(daemon.no_new_privs);
+        // options the profile leaves unset are kept, including the hardening ones
+        assert!(daemon.no_new_privs(true).apply_profile(svc_profile()).no_new_privs);
+        assert!(!Daemon::new().no_new_privs(true).apply_profile(svc_profile().no_new_privs(false)).no_new_privs);
+    }
+}
@@ -0,0 +1,86 @@
+use std::ffi::OsStr;
+
+use crate::Result;
+use crate::ffi::{GroupRecord, PasswdRecord};
+
+/// Source of the passwd and group records `User` and `Group` are resolved from,
+/// the system databases in the library and controlled fixtures in the tests
+pub(crate) trait RecordResolver {
+    fn passwd_by_name(&self, name: &OsStr) -> Result<PasswdRecord>;
+    fn passwd_by_id(&self, uid: u32) -> Result<PasswdRecord>;
+    fn group_by_name(&self, name: &OsStr) -> Result<GroupRecord>;
+    fn group_by_id(&self, gid: u32) -> Result<GroupRecord>;
+}
+
+/// Resolves through libc and whatever NSS backends the system is configured with
+pub(crate) struct SystemResolver;
+
+impl RecordResolver for SystemResolver {
+    fn passwd_by_name(&self, name: &OsStr) -> Result<PasswdRecord> {
+        PasswdRecord::lookup_record_by_name(name)
+    }
+
+    fn passwd_by_id(&self, uid: u32) -> Result<PasswdRecord> {
+        PasswdRecord::lookup_record_by_id(uid)
+    }
+
+    fn group_by_name(&self, name: &OsStr) -> Result<GroupRecord> {
+        GroupRecord::lookup_record_by_name(name)
+    }
+
+    fn group_by_id(&self, gid: u32) -> Result<GroupRecord> {
+        GroupRecord::lookup_record_by_id(gid)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod fake {
+    use crate::DaemonError;
+
+    use super::*;
+
+    /// In memory records so resolution can be tested without the host user database
+    #[derive(Default)]
+    pub(crate) struct FakeResolver {
+        users: Vec<PasswdRecord>,
+        groups: Vec<GroupRecord>,
+    }
+
+    impl FakeResolver {
+        pub(crate) fn with_user<N: AsRef<OsStr>>(mut self, name: N, uid: u32, gid: u32) -> Self {
+            self.users.push(PasswdRecord {
+                pw_name: name.as_ref().to_owned(),
+                pw_passwd: "x".to_owned(),
+                pw_uid: uid,
+                pw_gid: gid,
+                pw_gecos: String::new(),
+                pw_dir: "/nonexistent".to_owned(),
+                pw_shell: "/usr/sbin/nologin".to_owned(),
+            });
+            self
+        }
+
+        pub(crate) fn with_group(mut self, name: &str, gid: u32) -> Self {
+            self.groups.push(GroupRecord { gr_name: name.to_owned(), gr_passwd: "x".to_owned(), gr_gid: gid });
+            self
+        }
+    }
+
+    impl RecordResolver for FakeResolver {
+        fn passwd_by_name(&self, name: &OsStr) -> Result<PasswdRecord> {
+            self.users.iter().find(|user| user.pw_name == name).cloned().ok_or(DaemonError::UserNotFound)
+        }
+
+        fn passwd_by_id(&self, uid: u32) -> Result<PasswdRecord> {
+            self.users.iter().find(|user| user.pw_uid == uid).cloned().ok_or(DaemonError::UserNotFound)
+        }
+
+        fn group_by_name(&self, name: &OsStr) -> Result<GroupRecord> {
+            self.groups.iter().find(|group| OsStr::new(&group.gr_name) == name).cloned().ok_or(DaemonError::GetGrRecord)
+        }
+
+        fn group_by_id(&self, gid: u32) -> Result<GroupRecord> {
+            self.groups.iter().find(|group| group.gr_gid == gid).cloned().ok_or(DaemonError::GetGrRecord)
+        }
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::VecDeque;
+use std::fmt::{self, Debug};
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Once};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, open, FcntlArg, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{raise, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::stat::{fstat, Mode};
+use nix::unistd::{close, dup2, fsync, read};
+
+use crate::{DaemonError, Result};
+use crate::ffi::cloexec_pipe;
+use crate::signal::errno_location;
+
+/// Every ring buffer attached to a standard stream, dumped together on panic or on the dump signal
+static RING_BUFFERS: Mutex<Vec<Arc<RingBuffer>>> = Mutex::new(Vec::new());
+/// Write end of the pipe waking the dump thread, -1 while no dump signal is installed
+static DUMP_TRIGGER_FD: AtomicI32 = AtomicI32::new(-1);
+/// Write end of the pipe waking the flush thread, -1 while no flush signal is installed
+static FLUSH_TRIGGER_FD: AtomicI32 = AtomicI32::new(-1);
+static PANIC_HOOK: Once = Once::new();
+
+/// Keeps the last `capacity` bytes written to the standard streams it is attached to
+pub(crate) struct RingBuffer {
+    capacity: usize,
+    dump_path: PathBuf,
+    state: Mutex<RingState>,
+    /// The pump thread, joined by [`flush_stdio`]
+    pump: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct RingState {
+    data: VecDeque<u8>,
+    /// Read end of the pump pipe, the pump thread and dumps drain it under the state lock
+    read_end: Option<RawFd>,
+    /// Write end of the pump pipe, the standard streams are duplicated from it
+    write_end: Option<RawFd>,
+}
+
+impl Debug for RingBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RingBuffer")
+            .field("capacity", &self.capacity)
+            .field("dump_path", &self.dump_path)
+            .finish()
+    }
+}
+
+impl RingBuffer {
+    pub(crate) fn new(capacity: usize, dump_path: &Path) -> Self {
+        Self {
+            capacity,
+            dump_path: dump_path.to_owned(),
+            state: Mutex::new(RingState { data: VecDeque::with_capacity(capacity), read_end: None, write_end: None }),
+            pump: Mutex::new(None),
+        }
+    }
+
+    /// Returns the fd the standard streams should write to, the first call creates the pipe and
+    /// starts the pump thread so it must happen in the process that keeps running as the daemon
+    pub(crate) fn attach(self: &Arc<Self>) -> Result<RawFd> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return Err(poisoned()),
+        };
+        if let Some(write_end) = state.write_end {
+            return Ok(write_end);
+        }
+        let (read_end, write_end) = cloexec_pipe().map_err(DaemonError::RingBuffer)?;
+        if let Err(errno) = fcntl(read_end, FcntlArg::F_SETFL(OFlag::O_NONBLOCK)) {
+            let _ = close(read_end);
+            let _ = close(write_end);
+            return Err(DaemonError::RingBuffer(errno.into()));
+        }
+        state.read_end = Some(read_end);
+        state.write_end = Some(write_end);
+        drop(state);
+
+        let ring = Arc::clone(self);
+        let pump = match thread::Builder::new().name("stdio-ring-buffer".into()).spawn(move || ring.pump(read_end)) {
+            Ok(pump) => pump,
+            Err(e) => return Err(DaemonError::RingBuffer(e)),
+        };
+        if let Ok(mut slot) = self.pump.lock() {
+            *slot = Some(pump);
+        }
+        match RING_BUFFERS.lock() {
+            Ok(mut rings) => rings.push(Arc::clone(self)),
+            Err(_) => return Err(poisoned()),
+        }
+        install_panic_hook();
+        Ok(write_end)
+    }
+
+    /// Waits for output without holding the lock and drains it with the lock held,
+    /// this keeps the bytes in order when a dump drains the pipe at the same time
+    fn pump(&self, read_end: RawFd) {
+        let mut fds = [PollFd::new(read_end, PollFlags::POLLIN)];
+        loop {
+            match poll(&mut fds, -1) {
+                Ok(_) | Err(Errno::EINTR) => (),
+                Err(_) => return,
+            }
+            let open = match self.state.lock() {
+                Ok(mut state) => state.drain(self.capacity),
+                Err(_) => false,
+            };
+            if !open {
+                return;
+            }
+        }
+    }
+
+    /// Points the standard streams writing into this buffer at `devnull_fd` and closes the write end,
+    /// the pump thread then drains what is left and stops on EOF
+    fn detach(&self, devnull_fd: RawFd) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let write_end = match state.write_end.take() {
+            Some(fd) => fd,
+            None => return,
+        };
+        if let Ok(pipe) = fstat(write_end) {
+            for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+                match fstat(fd) {
+                    Ok(st) if st.st_dev == pipe.st_dev && st.st_ino == pipe.st_ino => {
+                        let _ = dup2(devnull_fd, fd);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        let _ = close(write_end);
+    }
+
+    /// Writes the retained output to the dump path, replacing a previous dump
+    fn dump(&self) -> std::io::Result<()> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        // pick up whatever was written but not pumped yet, eg. the panic message
+        state.drain(self.capacity);
+        let mut file = File::create(&self.dump_path)?;
+        let (front, back) = state.data.as_slices();
+        file.write_all(front)?;
+        file.write_all(back)
+    }
+}
+
+impl RingState {
+    /// Moves everything readable from the pipe into the buffer, returns false once the pipe is closed
+    fn drain(&mut self, capacity: usize) -> bool {
+        let read_end = match self.read_end {
+            Some(fd) => fd,
+            None => return false,
+        };
+        let mut buf = [0u8; 4096];
+        loop {
+            match read(read_end, &mut buf) {
+                Ok(0) => return false,
+                Ok(n) => push_bounded(&mut self.data, &buf[..n], capacity),
+                Err(Errno::EAGAIN) => return true,
+                Err(Errno::EINTR) => continue,
+                Err(_) => return false,
+            }
+        }
+    }
+}
+
+/// Appends `bytes` dropping the oldest ones so at most `capacity` are kept
+fn push_bounded(data: &mut VecDeque<u8>, bytes: &[u8], capacity: usize) {
+    let bytes = &bytes[bytes.len().saturating_sub(capacity)..];
+    let overflow = (data.len() + bytes.len()).saturating_sub(capacity);
+    data.drain(..overflow);
+    data.extend(bytes);
+}
+
+/// A poisoned lock means a pump thread panicked holding it
+fn poisoned() -> DaemonError {
+    DaemonError::RingBuffer(std::io::Error::new(std::io::ErrorKind::Other, "the ring buffer state is poisoned"))
+}
+
+/// Chains onto the current panic hook so the panic message makes it into the dump
+fn install_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        let previous = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            previous(info);
+            let _ = dump_ring_buffers();
+        }));
+    });
+}
+
+/// Write the output retained by every standard stream ring buffer to its dump path,
+/// see [`crate::Stdio::ring_buffer`]. This is done automatically on panic and on the signal set
+/// with [`crate::Daemon::ring_buffer_dump_signal`], call it yourself for any other "last words" moment
+pub fn dump_ring_buffers() -> Result<()> {
+    let rings = match RING_BUFFERS.lock() {
+        Ok(rings) => rings.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    let mut result = Ok(());
+    for ring in rings {
+        if ring.dump().is_err() {
+            result = Err(DaemonError::DumpRingBuffer);
+        }
+    }
+    result
+}
+
+/// Flush everything written to the standard streams so far and stop the threads pumping them,
+/// call it on the shutdown path so the last lines explaining why the daemon stopped aren't lost.
+/// The Rust and libc buffers are flushed, the pump threads of the ring buffers drain their pipe and are
+/// joined, waiting at most `timeout` for them, and file backed streams are synced to disk.
+/// Streams that were pumped write to /dev/null afterwards. Fails with `StdioFlushTimeout` if a pump
+/// doesn't finish in time, eg. because a child process still holds the pipe open
+pub fn flush_stdio(timeout: Duration) -> Result<()> {
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+    unsafe { libc::fflush(std::ptr::null_mut()) };
+
+    let rings = match RING_BUFFERS.lock() {
+        Ok(rings) => rings.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    };
+    if !rings.is_empty() {
+        let devnull_fd = match open(Path::new("/dev/null"), OFlag::O_WRONLY | OFlag::O_CLOEXEC, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(errno) => return Err(DaemonError::OpenDevNull(errno)),
+        };
+        for ring in &rings {
+            ring.detach(devnull_fd);
+        }
+        let _ = close(devnull_fd);
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut result = Ok(());
+    for ring in &rings {
+        let pump = match ring.pump.lock() {
+            Ok(mut slot) => slot.take(),
+            Err(poisoned) => poisoned.into_inner().take(),
+        };
+        if let Some(pump) = pump {
+            while !pump.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(5));
+            }
+            if pump.is_finished() {
+                let _ = pump.join();
+            } else {
+                result = Err(DaemonError::StdioFlushTimeout);
+            }
+        }
+    }
+
+    // pipes and terminals can't be synced, only files matter here
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        let _ = fsync(fd);
+    }
+    result
+}
+
+/// Writes to the trigger pipe stored in `trigger`, only an atomic load and a write(2), both
+/// async-signal-safe, errno is saved and restored so the interrupted code never observes a change
+fn wake(trigger: &AtomicI32) {
+    let fd = trigger.load(Ordering::Relaxed);
+    if fd < 0 {
+        return;
+    }
+    let byte = 0u8;
+    unsafe {
+        let saved_errno = *errno_location();
+        libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+        *errno_location() = saved_errno;
+    }
+}
+
+/// The handler only wakes the dump thread, dumping itself isn't async-signal-safe
+extern "C" fn dump_signal_handler(_: libc::c_int) {
+    wake(&DUMP_TRIGGER_FD);
+}
+
+extern "C" fn flush_signal_handler(_: libc::c_int) {
+    wake(&FLUSH_TRIGGER_FD);
+}
+
+/// Runs `action` on a dedicated thread every time `signal` is delivered, the handler wakes it through a pipe
+fn on_signal<F: Fn() + Send + 'static>(
+    signal: Signal,
+    trigger: &'static AtomicI32,
+    handler: extern "C" fn(libc::c_int),
+    name: &str,
+    action: F,
+) -> Result<()> {
+    let (read_end, write_end) = cloexec_pipe().map_err(DaemonError::RingBuffer)?;
+    if trigger.compare_exchange(-1, write_end, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        let _ = close(read_end);
+        let _ = close(write_end);
+        let source = std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{} is already handled", signal));
+        return Err(DaemonError::RingBuffer(source));
+    }
+    let spawned = thread::Builder::new().name(name.into()).spawn(move || {
+        let mut byte = [0u8; 1];
+        loop {
+            match read(read_end, &mut byte) {
+                Ok(0) => break,
+                Ok(_) => action(),
+                Err(Errno::EINTR) => (),
+                Err(_) => break,
+            }
+        }
+        let _ = close(read_end);
+    });
+    let thread = match spawned {
+        Ok(thread) => thread,
+        Err(e) => {
+            trigger.store(-1, Ordering::SeqCst);
+            let _ = close(read_end);
+            let _ = close(write_end);
+            return Err(DaemonError::RingBuffer(e));
+        }
+    };
+    let action = SigAction::new(SigHandler::Handler(handler), SaFlags::SA_RESTART, SigSet::empty());
+    match unsafe { sigaction(signal, &action) } {
+        Ok(_) => Ok(()),
+        Err(errno) => {
+            // free the slot for a later call, the thread sees the end of the pipe and closes the read end
+            trigger.store(-1, Ordering::SeqCst);
+            let _ = close(write_end);
+            let _ = thread.join();
+            Err(DaemonError::RingBuffer(errno.into()))
+        }
+    }
+}
+
+/// Dump the ring buffers from a dedicated thread every time `signal` is delivered
+pub(crate) fn dump_on_signal(signal: Signal) -> Result<()> {
+    on_signal(signal, &DUMP_TRIGGER_FD, dump_signal_handler, "stdio-ring-dump", || {
+        let _ = dump_ring_buffers();
+    })
+}
+
+/// On `signal` flush the standard streams with [`flush_stdio`] then die from the signal as if it wasn't handled
+pub(crate) fn flush_on_signal(signal: Signal, timeout: Duration) -> Result<()> {
+    on_signal(signal, &FLUSH_TRIGGER_FD, flush_signal_handler, "stdio-flush", move || {
+        let _ = flush_stdio(timeout);
+        let default = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+        if unsafe { sigaction(signal, &default) }.is_ok() {
+            let _ = raise(signal);
+        }
+        // the signal is ignored by default or blocked, exit the way it would have
+        std::process::exit(128 + signal as i32);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Asserts that a handler that can't be installed leaves the trigger free instead of reporting the signal as handled
+    fn test_on_signal_failure_releases_trigger() {
+        // SIGKILL can't be caught
+        for _ in 0..2 {
+            match dump_on_signal(Signal::SIGKILL) {
+                Err(DaemonError::RingBuffer(source)) => assert_ne!(source.kind(), std::io::ErrorKind::AlreadyExists),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        assert_eq!(DUMP_TRIGGER_FD.load(Ordering::SeqCst), -1);
+    }
+
+    #[test]
+    /// Asserts that only the newest bytes are kept
+    fn test_push_bounded() {
+        let mut data = VecDeque::new();
+        push_bounded(&mut data, b"hello", 8);
+        push_bounded(&mut data, b" world", 8);
+        assert_eq!(data.iter().copied().collect::<Vec<u8>>(), b"lo world");
+        push_bounded(&mut data, b"0123456789", 8);
+        assert_eq!(data.iter().copied().collect::<Vec<u8>>(), b"23456789");
+    }
+}
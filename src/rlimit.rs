@@ -0,0 +1,69 @@
+use nix::sys::resource::{setrlimit, Resource};
+
+use crate::{DaemonError, Result};
+
+/// When a resource limit is applied relative to dropping privileges, see [`crate::Daemon::rlimit`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RlimitPhase {
+    /// While still root, needed to raise a hard limit
+    BeforePrivilegeDrop,
+    /// Once running as the daemon user, for caps meant for that user
+    AfterPrivilegeDrop,
+}
+
+/// One resource limit to apply, `None` means unlimited
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Rlimit {
+    pub(crate) resource: Resource,
+    pub(crate) soft: Option<libc::rlim_t>,
+    pub(crate) hard: Option<libc::rlim_t>,
+    pub(crate) when: RlimitPhase,
+}
+
+/// Applies every limit meant for `phase` in the order they were set
+pub(crate) fn apply_rlimits(limits: &[Rlimit], phase: RlimitPhase) -> Result<()> {
+    for limit in limits.iter().filter(|limit| limit.when == phase) {
+        if let Err(errno) = setrlimit(limit.resource, limit.soft, limit.hard) {
+            return Err(DaemonError::SetRlimit { resource: limit.resource, source: errno.into() });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::sys::resource::getrlimit;
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    use super::*;
+
+    #[test]
+    /// Asserts that only the limits of the phase are applied and read back as set, a soft limit
+    /// above the hard one is refused with `SetRlimit`
+    fn test_apply_rlimits() {
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            }
+            ForkResult::Child => {
+                let checked = (|| {
+                    let (_, hard) = getrlimit(Resource::RLIMIT_NOFILE).ok()?;
+                    let (core_soft, core_hard) = getrlimit(Resource::RLIMIT_CORE).ok()?;
+                    let limits = [
+                        Rlimit { resource: Resource::RLIMIT_NOFILE, soft: Some(256), hard, when: RlimitPhase::BeforePrivilegeDrop },
+                        Rlimit { resource: Resource::RLIMIT_CORE, soft: Some(0), hard: Some(0), when: RlimitPhase::AfterPrivilegeDrop },
+                    ];
+                    apply_rlimits(&limits, RlimitPhase::BeforePrivilegeDrop).ok()?;
+                    let applied = getrlimit(Resource::RLIMIT_NOFILE).ok()? == (Some(256), hard)
+                        && getrlimit(Resource::RLIMIT_CORE).ok()? == (core_soft, core_hard);
+                    let above_hard = [Rlimit { resource: Resource::RLIMIT_NOFILE, soft: Some(512), hard: Some(128), when: RlimitPhase::AfterPrivilegeDrop }];
+                    let refused = matches!(apply_rlimits(&above_hard, RlimitPhase::AfterPrivilegeDrop),
+                        Err(DaemonError::SetRlimit { resource: Resource::RLIMIT_NOFILE, .. }));
+                    Some(applied && refused)
+                })();
+                unsafe { libc::_exit(if checked == Some(true) { 0 } else { 1 }) }
+            }
+        }
+    }
+}
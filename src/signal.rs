@@ -0,0 +1,160 @@
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::{close, pipe};
+
+use crate::{DaemonError, Result};
+
+/// Write end of the signal pipe, -1 while no pipe is installed
+static SIGNAL_PIPE_FD: AtomicI32 = AtomicI32::new(-1);
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno_location()
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd", target_os = "dragonfly"))]
+pub(crate) unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__error()
+}
+
+#[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+pub(crate) unsafe fn errno_location() -> *mut libc::c_int {
+    libc::__errno()
+}
+
+/// The handler only does an atomic load and a single write(2), both async-signal-safe,
+/// errno is saved and restored so the interrupted code never observes a change
+extern "C" fn signal_pipe_handler(signum: libc::c_int) {
+    let fd = SIGNAL_PIPE_FD.load(Ordering::Relaxed);
+    if fd < 0 {
+        return;
+    }
+    let byte = signum as u8;
+    unsafe {
+        let saved_errno = *errno_location();
+        // a full pipe means a wakeup is already pending so a failed write is fine to drop
+        libc::write(fd, &byte as *const u8 as *const libc::c_void, 1);
+        *errno_location() = saved_errno;
+    }
+}
+
+fn set_pipe_flags(fd: RawFd) -> Result<()> {
+    match fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).and_then(|_| fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))) {
+        Ok(_) => Ok(()),
+        Err(errno) => Err(DaemonError::SignalPipe(errno.into())),
+    }
+}
+
+/// Read end of a self-pipe that receives one byte per delivered signal,
+/// poll its fd from your event loop and call [`SignalPipe::read_signal`] when it is readable
+#[derive(Debug)]
+pub struct SignalPipe {
+    file: File,
+}
+
+impl SignalPipe {
+    pub(crate) fn install(signals: &[Signal]) -> Result<SignalPipe> {
+        let (read_fd, write_fd) = match pipe() {
+            Ok(fds) => fds,
+            Err(errno) => return Err(DaemonError::SignalPipe(errno.into())),
+        };
+        // owning the read end right away makes sure it is closed on every error path
+        let file = unsafe { File::from_raw_fd(read_fd) };
+        if let Err(e) = set_pipe_flags(read_fd).and_then(|_| set_pipe_flags(write_fd)) {
+            let _ = close(write_fd);
+            return Err(e);
+        }
+        // only one pipe per process, the handler has no way to safely switch pipes
+        if SIGNAL_PIPE_FD
+            .compare_exchange(-1, write_fd, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            let _ = close(write_fd);
+            let source = std::io::Error::new(ErrorKind::AlreadyExists, "a signal pipe is already installed");
+            return Err(DaemonError::SignalPipe(source));
+        }
+        let action = SigAction::new(
+            SigHandler::Handler(signal_pipe_handler),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        let mut previous = Vec::with_capacity(signals.len());
+        for signal in signals {
+            match unsafe { sigaction(*signal, &action) } {
+                Ok(old) => previous.push((*signal, old)),
+                Err(errno) => {
+                    // put back the handlers installed so far, then free the slot so a later install can retry
+                    for (signal, old) in previous.iter().rev() {
+                        let _ = unsafe { sigaction(*signal, old) };
+                    }
+                    SIGNAL_PIPE_FD.store(-1, Ordering::SeqCst);
+                    let _ = close(write_fd);
+                    return Err(DaemonError::SignalPipe(errno.into()));
+                }
+            }
+        }
+        Ok(SignalPipe { file })
+    }
+
+    /// Reads the next pending signal without blocking, `None` means no signal is pending
+    pub fn read_signal(&mut self) -> Result<Option<Signal>> {
+        let mut byte = [0u8; 1];
+        match self.file.read(&mut byte) {
+            Ok(1) => match Signal::try_from(byte[0] as libc::c_int) {
+                Ok(signal) => Ok(Some(signal)),
+                Err(errno) => Err(DaemonError::SignalPipe(errno.into())),
+            },
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(DaemonError::SignalPipe(e)),
+        }
+    }
+
+    /// Consumes the pipe and returns the underlying read end
+    pub fn into_file(self) -> File {
+        self.file
+    }
+}
+
+impl AsRawFd for SignalPipe {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl IntoRawFd for SignalPipe {
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::sys::signal::raise;
+
+    use super::*;
+
+    #[test]
+    /// Asserts that a raised signal shows up on the pipe once, that an empty pipe doesn't block
+    /// and that a failed install leaves room for the next one
+    fn test_signal_pipe() {
+        // SIGKILL can't be caught, the failed install must not keep the pipe slot nor the SIGUSR2 handler
+        assert!(SignalPipe::install(&[Signal::SIGUSR2, Signal::SIGKILL]).is_err());
+        let default = SigAction::new(SigHandler::SigDfl, SaFlags::empty(), SigSet::empty());
+        let restored = unsafe { sigaction(Signal::SIGUSR2, &default) }.unwrap();
+        assert_eq!(restored.handler(), SigHandler::SigDfl);
+        let mut pipe = SignalPipe::install(&[Signal::SIGUSR1]).unwrap();
+        assert!(pipe.read_signal().unwrap().is_none());
+        raise(Signal::SIGUSR1).unwrap();
+        assert_eq!(pipe.read_signal().unwrap(), Some(Signal::SIGUSR1));
+        assert!(pipe.read_signal().unwrap().is_none());
+        // a second pipe can't be installed
+        assert!(SignalPipe::install(&[Signal::SIGUSR2]).is_err());
+    }
+}
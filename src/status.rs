@@ -0,0 +1,119 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use nix::unistd::Pid;
+
+use crate::Result;
+use crate::ffi::PasswdRecord;
+use crate::pid_file::{is_running, read_current_entry};
+
+/// What is known about a daemon from its pid file, see [`crate::Daemon::status`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DaemonStatus {
+    /// The current pid in the pid file
+    pub pid: Pid,
+    /// Whether a process with that pid exists and isn't a zombie, a stale pid file may name a recycled pid
+    pub alive: bool,
+    /// How long the daemon has been running, from the run history timestamp or `/proc/[pid]/stat`
+    pub uptime: Option<Duration>,
+    /// The real uid the process runs as, only known where `/proc` is available
+    pub uid: Option<u32>,
+    /// The name of that user
+    pub user: Option<String>,
+}
+
+pub(crate) fn read_status(pid_file: &Path) -> Result<DaemonStatus> {
+    let (started_at, pid) = read_current_entry(pid_file)?;
+    let alive = is_running(pid);
+    if !alive {
+        return Ok(DaemonStatus { pid, alive, uptime: None, uid: None, user: None });
+    }
+    let uptime = match started_at {
+        Some(started_at) => SystemTime::now()
+            .duration_since(UNIX_EPOCH + Duration::from_secs(started_at))
+            .ok(),
+        None => proc_uptime(pid),
+    };
+    let uid = proc_uid(pid);
+    let user = uid
+        .and_then(|uid| PasswdRecord::lookup_record_by_id(uid).ok())
+        .map(|record| record.pw_name.to_string_lossy().into_owned());
+    Ok(DaemonStatus { pid, alive, uptime, uid, user })
+}
+
+/// Real uid from the `Uid:` line of `/proc/[pid]/status`
+fn proc_uid(pid: Pid) -> Option<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let line = status.lines().find(|line| line.starts_with("Uid:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// System uptime minus the start time of the process, both from `/proc`
+fn proc_uptime(pid: Pid) -> Option<Duration> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name may contain spaces and parentheses, fields are counted from the last ')'
+    let fields: Vec<&str> = stat.get(stat.rfind(')')? + 1..)?.split_whitespace().collect();
+    // starttime is field 22 of the whole line, the 20th after the command name
+    let start_ticks: u64 = fields.get(19)?.parse().ok()?;
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_second <= 0 {
+        return None;
+    }
+    let uptime = std::fs::read_to_string("/proc/uptime").ok()?;
+    let system_uptime: f64 = uptime.split_whitespace().next()?.parse().ok()?;
+    let started = start_ticks as f64 / ticks_per_second as f64;
+    Some(Duration::from_secs_f64((system_uptime - started).max(0.0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::unistd::{getpid, getuid};
+
+    use super::*;
+
+    #[test]
+    /// Asserts that the status of the test process itself is read back
+    fn test_read_status() {
+        let path = std::env::temp_dir().join(format!("daemonize-me-status-{}.pid", std::process::id()));
+        std::fs::write(&path, getpid().to_string()).unwrap();
+        let status = read_status(&path).unwrap();
+        assert_eq!(status.pid, getpid());
+        assert!(status.alive);
+        if cfg!(target_os = "linux") {
+            assert_eq!(status.uid, Some(getuid().as_raw()));
+            assert!(status.uptime.is_some());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    /// Asserts that a pid file naming a process that is gone is reported as not alive
+    fn test_read_status_stale() {
+        let path = std::env::temp_dir().join(format!("daemonize-me-stale-{}.pid", std::process::id()));
+        std::fs::write(&path, format!("{} {}\n", 1000, i32::MAX)).unwrap();
+        let status = read_status(&path).unwrap();
+        assert!(!status.alive);
+        assert_eq!(status.uptime, None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Asserts that a zombie counts as gone, the same as for `is_running`
+    fn test_read_status_zombie() {
+        let path = std::env::temp_dir().join(format!("daemonize-me-zombie-{}.pid", std::process::id()));
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        let pid = Pid::from_raw(child.id() as i32);
+        std::fs::write(&path, pid.to_string()).unwrap();
+        // not reaped yet, the child stays a zombie until waited for
+        for _ in 0..100 {
+            if !read_status(&path).unwrap().alive {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(!read_status(&path).unwrap().alive);
+        child.wait().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
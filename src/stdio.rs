@@ -1,10 +1,11 @@
-use std::fmt::Debug;
-use std::fs::File;
-use std::os::unix::io::AsRawFd;
-use std::path::Path;
+use std::fmt::{self, Debug, Display};
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use nix::fcntl::{OFlag, open};
-use nix::sys::stat::Mode;
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag, open};
+use nix::sys::stat::{fstat, Mode};
 #[cfg(not(target_os = "macos"))]
 use nix::unistd::{
     close, dup2,
@@ -15,67 +16,817 @@ use nix::unistd::{
 };
 
 use crate::{DaemonError, Result};
+#[cfg(feature = "journal")]
+use crate::journal::Journal;
+use crate::ring_buffer::RingBuffer;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum StdioImp {
     Devnull,
-    RedirectToFile(File),
+    DevnullReadOnly,
+    Close,
+    /// The path is only known, and the file can only be reopened, when it was opened by this crate
+    RedirectToFile(Arc<File>, Option<PathBuf>),
+    RingBuffer(Arc<RingBuffer>),
+    #[cfg(feature = "journal")]
+    Journal(Arc<Journal>),
+}
+
+/// Buffering mode of the libc stdout and stderr streams, this only affects output written through
+/// libc stdio (`printf`, `fputs`, ... for instance from C code called over FFI), Rust's `println!`
+/// and `eprintln!` don't go through libc stdio and aren't affected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferMode {
+    /// Flush on every newline, this is what you want for log files
+    Line,
+    /// Flush when the buffer is full, the libc default for files
+    Block,
+    /// Write everything right away
+    Unbuffered,
+}
+
+impl BufferMode {
+    pub(crate) fn as_raw(self) -> libc::c_int {
+        match self {
+            BufferMode::Line => libc::_IOLBF,
+            BufferMode::Block => libc::_IOFBF,
+            BufferMode::Unbuffered => libc::_IONBF,
+        }
+    }
+}
+
+/// Identifies one of the three standard streams
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StdStream {
+    Stdin,
+    Stdout,
+    Stderr,
+}
+
+impl StdStream {
+    fn fd(self) -> RawFd {
+        match self {
+            StdStream::Stdin => libc::STDIN_FILENO,
+            StdStream::Stdout => libc::STDOUT_FILENO,
+            StdStream::Stderr => libc::STDERR_FILENO,
+        }
+    }
+}
+
+impl Display for StdStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StdStream::Stdin => f.write_str("stdin"),
+            StdStream::Stdout => f.write_str("stdout"),
+            StdStream::Stderr => f.write_str("stderr"),
+        }
+    }
 }
 
 /// describes what to do with a standard io stream for a child process.
-#[derive(Debug)]
+/// Clones redirect to the same open file
+#[derive(Debug, Clone)]
 pub struct Stdio {
     inner: StdioImp,
 }
 
 impl Stdio {
+    /// How the stream is shown in [`crate::Daemon::to_args`], files not opened from a path can't be named
+    pub(crate) fn to_arg(&self) -> String {
+        match &self.inner {
+            StdioImp::Devnull => String::from("null"),
+            StdioImp::DevnullReadOnly => String::from("null-readonly"),
+            StdioImp::Close => String::from("close"),
+            StdioImp::RedirectToFile(_, Some(path)) => path.display().to_string(),
+            StdioImp::RedirectToFile(_, None) => String::from("fd"),
+            StdioImp::RingBuffer(_) => String::from("ring-buffer"),
+            #[cfg(feature = "journal")]
+            StdioImp::Journal(_) => String::from("journal"),
+        }
+    }
+
     pub(crate) fn devnull() -> Self {
         Self {
             inner: StdioImp::Devnull,
         }
     }
+
+    pub(crate) fn devnull_readonly() -> Self {
+        Self {
+            inner: StdioImp::DevnullReadOnly,
+        }
+    }
+
+    /// Close the stream instead of redirecting it, any accidental use then fails loudly with `EBADF`
+    /// rather than vanishing into /dev/null.
+    /// Beware that the next file opened by the process gets the lowest free fd, so a closed stream
+    /// can be reused by an unrelated file later on
+    pub fn close() -> Self {
+        Self {
+            inner: StdioImp::Close,
+        }
+    }
+
+    /// Keep the last `capacity` bytes written to the stream in memory and write them to `dump_path`
+    /// on panic, on the signal set with [`crate::Daemon::ring_buffer_dump_signal`] or when
+    /// [`crate::dump_ring_buffers`] is called, a "last words" log without constant disk writes.
+    /// A thread started in the daemon pumps the stream into the buffer, give stdout and stderr clones
+    /// of the same value to share one buffer. Only meant for stdout and stderr, as stdin it fails.
+    /// Relative dump paths are resolved against the working directory at the time of the dump
+    pub fn ring_buffer<T: AsRef<Path>>(capacity: usize, dump_path: T) -> Self {
+        Self {
+            inner: StdioImp::RingBuffer(Arc::new(RingBuffer::new(capacity, dump_path.as_ref()))),
+        }
+    }
+
+    /// Send every line written to the stream to the systemd journal as an entry of its own, with
+    /// `priority` (`libc::LOG_ERR`, `libc::LOG_INFO`, ...) and the extra `fields` eg. `("SYSLOG_IDENTIFIER", "svc")`,
+    /// which piping the stream into the journal can't carry. A thread started in the daemon forwards the lines,
+    /// so only stdout and stderr can be sent, as stdin it fails. When systemd already connected the stream to
+    /// the journal, as told by `JOURNAL_STREAM`, it is left as it is instead so nothing is logged twice.
+    /// Fails with `InvalidJournalPriority` or `InvalidJournalField` for a name journald would drop
+    #[cfg(feature = "journal")]
+    pub fn journal(priority: libc::c_int, fields: &[(&str, &str)]) -> Result<Self> {
+        Ok(Self {
+            inner: StdioImp::Journal(Arc::new(Journal::new(priority, fields)?)),
+        })
+    }
+
+    /// The fd of the file the stream is redirected to, it must stay open until the redirection
+    pub(crate) fn raw_fd(&self) -> Option<RawFd> {
+        match &self.inner {
+            StdioImp::RedirectToFile(file, _) => Some(file.as_raw_fd()),
+            _ => None,
+        }
+    }
+
+    /// The path of the file the stream is redirected to, if it was opened from a path
+    pub(crate) fn path(&self) -> Option<&Path> {
+        match &self.inner {
+            StdioImp::RedirectToFile(_, path) => path.as_deref(),
+            _ => None,
+        }
+    }
 }
 
+impl Stdio {
+    /// Redirect to the file at `path`, created if needed and truncated.
+    /// The file is opened with `O_APPEND` so writes from other processes sharing it, more instances
+    /// or a log rotator, don't clobber each other, each write up to `PIPE_BUF` lands whole at the end.
+    /// The path is kept, made absolute, so the file can be reopened with [`LogHandle::reopen`]
+    pub fn from_path<T: AsRef<Path>>(path: T) -> Result<Self> {
+        Self::open_appending(path.as_ref(), true)
+    }
+
+    /// Like [`Stdio::from_path`] but keeps the current contents of the file, the one-liner for logs that
+    /// survive restarts. Whatever the file offset, every write goes to the end of the file
+    pub fn append<T: AsRef<Path>>(path: T) -> Result<Self> {
+        Self::open_appending(path.as_ref(), false)
+    }
+
+    /// Start the file at `path` over, the counterpart of [`Stdio::append`], this is [`Stdio::from_path`]
+    pub fn truncate<T: AsRef<Path>>(path: T) -> Result<Self> {
+        Self::open_appending(path.as_ref(), true)
+    }
+
+    fn open_appending(path: &Path, truncate: bool) -> Result<Self> {
+        // std refuses to combine append and truncate, so truncate once the file is open
+        let file = match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => file,
+            Err(e) => return Err(DaemonError::OpenStdioFile(e)),
+        };
+        if truncate {
+            if let Err(e) = file.set_len(0) {
+                return Err(DaemonError::OpenStdioFile(e));
+            }
+        }
+        // the daemon changes its working directory, a relative path would reopen another file
+        let path = match std::env::current_dir() {
+            Ok(cwd) => cwd.join(path),
+            Err(e) => return Err(DaemonError::OpenStdioFile(e)),
+        };
+        Ok(Self {
+            inner: StdioImp::RedirectToFile(Arc::new(file), Some(path)),
+        })
+    }
+}
+
+impl Stdio {
+    /// Redirect to the fd whose number is in the environment variable `var`, eg. `LOG_FD=7` set by
+    /// a launcher that opened the log file or socket for the daemon. The fd is duplicated right away
+    /// and the inherited one left as it is, so one variable can serve both stdout and stderr.
+    /// Fails with `EnvFdParse` when the variable is unset or not a number and `EnvFdClosed` when the fd isn't open
+    pub fn from_env_fd(var: &str) -> Result<Self> {
+        let fd = match std::env::var(var).ok().and_then(|value| value.trim().parse::<RawFd>().ok()) {
+            Some(fd) if fd >= 0 => fd,
+            _ => return Err(DaemonError::EnvFdParse { var: var.to_owned() }),
+        };
+        let owned_fd = match fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(libc::STDERR_FILENO + 1)) {
+            Ok(owned_fd) => owned_fd,
+            Err(_) => return Err(DaemonError::EnvFdClosed { var: var.to_owned(), fd }),
+        };
+        Ok(Self::from(unsafe { File::from_raw_fd(owned_fd) }))
+    }
+}
+
+impl Stdio {
+    /// Send all three streams into one pipe, as read by a log collecting sidecar, and return the three
+    /// handles for [`crate::Daemon::stdin`], [`crate::Daemon::stdout`] and [`crate::Daemon::stderr`] along
+    /// with the read end for the collector. Stdin then is the write end as well, reading it fails with `EBADF`.
+    /// Both ends are close on exec, the read end is inherited by the daemon through the fork though,
+    /// drop it there (eg. from the post fork child hook) or the collector never sees EOF
+    pub fn shared_pipe() -> Result<(Stdio, Stdio, Stdio, File)> {
+        let (read_end, write_end) = match nix::unistd::pipe() {
+            Ok(fds) => fds,
+            Err(errno) => return Err(DaemonError::SharedPipe(errno.into())),
+        };
+        let (read_end, write_end) = unsafe { (File::from_raw_fd(read_end), File::from_raw_fd(write_end)) };
+        for fd in [read_end.as_raw_fd(), write_end.as_raw_fd()] {
+            if let Err(errno) = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+                return Err(DaemonError::SharedPipe(errno.into()));
+            }
+        }
+        let stdio = Stdio::from(write_end);
+        Ok((stdio.clone(), stdio.clone(), stdio, read_end))
+    }
+}
+
+/// Files passed in are used as they are, open them with `O_APPEND` when they are shared with other writers
 impl From<File> for Stdio {
     fn from(file: File) -> Self {
         Self {
-            inner: StdioImp::RedirectToFile(file),
+            inner: StdioImp::RedirectToFile(Arc::new(file), None),
         }
     }
 }
 
-pub(crate) fn redirect_stdio(stdin: &Stdio, stdout: &Stdio, stderr: &Stdio) -> Result<()> {
-    let devnull_fd = match open(
-        Path::new("/dev/null"),
-        OFlag::O_APPEND,
-        Mode::from_bits(OFlag::O_RDWR.bits() as _).unwrap(),
-    ) {
+/// Reopens the log files stdout and stderr are redirected to, returned by `start` in the daemon.
+/// Only streams redirected with [`Stdio::from_path`] or [`Stdio::append`] can be reopened
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogHandle {
+    stdout: Option<PathBuf>,
+    stderr: Option<PathBuf>,
+}
+
+impl LogHandle {
+    pub(crate) fn new(stdout: &Stdio, stderr: &Stdio) -> Self {
+        LogHandle {
+            stdout: stdout.path().map(Path::to_path_buf),
+            stderr: stderr.path().map(Path::to_path_buf),
+        }
+    }
+
+    /// Point stdout and stderr at whatever file is at their path now, eg. from the admin command
+    /// of a daemon after its logs were rotated. The file is created if the rotator moved it away and
+    /// is appended to otherwise, streams sharing a path keep sharing one file.
+    /// After a chroot the paths are looked up inside the new root
+    pub fn reopen(&self) -> Result<()> {
+        let mut opened: Option<(&Path, File)> = None;
+        for (stream, path) in [(StdStream::Stdout, &self.stdout), (StdStream::Stderr, &self.stderr)] {
+            let path = match path {
+                Some(path) => path.as_path(),
+                None => continue,
+            };
+            let reuse = matches!(&opened, Some((last, _)) if *last == path);
+            if !reuse {
+                let file = match OpenOptions::new().create(true).append(true).open(path) {
+                    Ok(file) => file,
+                    Err(e) => return Err(DaemonError::OpenStdioFile(e)),
+                };
+                opened = Some((path, file));
+            }
+            if let Some((_, file)) = &opened {
+                if let Err(errno) = dup2(file.as_raw_fd(), stream.fd()) {
+                    return Err(DaemonError::RedirectStream { stream, source: errno.into() });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open_devnull(flags: OFlag, verify: bool) -> Result<RawFd> {
+    let fd = match open(Path::new("/dev/null"), flags, Mode::empty()) {
         Ok(fd) => fd,
-        Err(_) => return Err(DaemonError::OpenDevNull),
+        Err(errno) => return Err(DaemonError::OpenDevNull(errno)),
+    };
+    if verify {
+        if let Err(e) = check_devnull(fd) {
+            let _ = close(fd);
+            return Err(e);
+        }
+    }
+    Ok(fd)
+}
+
+/// Checks that `fd` is the null character device and not, eg. a regular file planted in its place
+fn check_devnull(fd: RawFd) -> Result<()> {
+    let st = match fstat(fd) {
+        Ok(st) => st,
+        Err(_) => return Err(DaemonError::DevNullInvalid),
+    };
+    if st.st_mode & libc::S_IFMT != libc::S_IFCHR {
+        return Err(DaemonError::DevNullInvalid);
+    }
+    // the device numbers of the null device are only fixed on Linux
+    #[cfg(target_os = "linux")]
+    if libc::major(st.st_rdev) != 1 || libc::minor(st.st_rdev) != 3 {
+        return Err(DaemonError::DevNullInvalid);
+    }
+    Ok(())
+}
+
+/// Stdin can't read from a source that only takes output
+fn write_only(stream: StdStream, source: &str) -> DaemonError {
+    let source = std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("a {} can't be read from", source));
+    DaemonError::RedirectStream { stream, source }
+}
+
+/// Duplicates `fd` to the lowest free fd above the standard streams
+fn dup_above_stdio(fd: RawFd) -> Result<RawFd> {
+    match fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(libc::STDERR_FILENO + 1)) {
+        Ok(high_fd) => Ok(high_fd),
+        Err(errno) => Err(DaemonError::DupFd(errno.into())),
+    }
+}
+
+/// Points every standard stream that is closed at /dev/null, so no fd opened later can land on one
+/// and be clobbered or clobber something when the streams are redirected
+fn fill_closed_stdio(devnull_fd: RawFd) -> Result<()> {
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if let Err(nix::errno::Errno::EBADF) = fcntl(fd, FcntlArg::F_GETFD) {
+            if let Err(errno) = dup2(devnull_fd, fd) {
+                return Err(DaemonError::DupFd(errno.into()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Redirects the three standard streams, with `fallback_to_null` a stream that can't be redirected
+/// is pointed at /dev/null instead and a warning is written to stderr once all of them are done.
+/// With `verify_devnull` every /dev/null opened is checked to be the null device
+pub(crate) fn redirect_stdio(
+    stdin: &Stdio,
+    stdout: &Stdio,
+    stderr: &Stdio,
+    fallback_to_null: bool,
+    verify_devnull: bool,
+) -> Result<()> {
+    let mut devnull_fd = open_devnull(OFlag::O_RDWR, verify_devnull)?;
+    // /dev/null lands on a standard stream when one was closed, it would be clobbered by the redirection
+    if devnull_fd <= libc::STDERR_FILENO {
+        let high_fd = dup_above_stdio(devnull_fd)?;
+        let _ = close(devnull_fd);
+        devnull_fd = high_fd;
+    }
+    fill_closed_stdio(devnull_fd)?;
+    // resolve every source before closing any stream so a newly opened fd can't land on one
+    let resolve = |stream: StdStream, stdio: &Stdio| -> Result<Option<(RawFd, bool)>> {
+        let (source_fd, owned) = match &stdio.inner {
+            StdioImp::Close => return Ok(None),
+            StdioImp::Devnull => (devnull_fd, false),
+            StdioImp::DevnullReadOnly => (open_devnull(OFlag::O_RDONLY, verify_devnull)?, true),
+            StdioImp::RedirectToFile(file, _) => (file.as_raw_fd(), false),
+            StdioImp::RingBuffer(_) if stream == StdStream::Stdin => return Err(write_only(stream, "ring buffer")),
+            StdioImp::RingBuffer(ring) => (ring.attach()?, false),
+            #[cfg(feature = "journal")]
+            StdioImp::Journal(_) if stream == StdStream::Stdin => return Err(write_only(stream, "journal")),
+            #[cfg(feature = "journal")]
+            StdioImp::Journal(journal) => journal.attach(stream.fd())?,
+        };
+        // a source that is itself one of the standard streams (eg. a file opened while stdin was closed)
+        // would be overwritten by the redirection of that stream, work from a copy above them instead
+        if source_fd <= libc::STDERR_FILENO {
+            let high_fd = dup_above_stdio(source_fd)?;
+            if owned {
+                let _ = close(source_fd);
+            }
+            return Ok(Some((high_fd, true)));
+        }
+        Ok(Some((source_fd, owned)))
     };
-    let proc_stream = |fd, stdio: &Stdio| {
-        match close(fd) {
-            Ok(_) => (),
-            Err(_) => return Err(DaemonError::CloseFp),
+    let proc_stream = |stream: StdStream, source: Result<Option<(RawFd, bool)>>| {
+        let fd = stream.fd();
+        let (source_fd, owned) = match source? {
+            Some(source) => source,
+            // an already closed stream is just as good
+            None => {
+                return match close(fd) {
+                    Ok(_) | Err(nix::errno::Errno::EBADF) => Ok(()),
+                    Err(_) => Err(DaemonError::CloseFp { stream }),
+                }
+            }
         };
-        return match &stdio.inner {
-            StdioImp::Devnull => match dup2(devnull_fd, fd) {
+        let result = match close(fd) {
+            Ok(_) => match dup2(source_fd, fd) {
                 Ok(_) => Ok(()),
-                Err(_) => Err(DaemonError::RedirectStream),
+                Err(errno) => Err(DaemonError::RedirectStream { stream, source: errno.into() }),
             },
-            StdioImp::RedirectToFile(file) => {
-                let raw_fd = file.as_raw_fd();
-                match dup2(raw_fd, fd) {
-                    Ok(_) => Ok(()),
-                    Err(_) => Err(DaemonError::RedirectStream),
-                }
-            }
+            Err(_) => Err(DaemonError::CloseFp { stream }),
         };
+        if owned {
+            let _ = close(source_fd);
+        }
+        result
     };
 
-    proc_stream(libc::STDIN_FILENO, stdin)?;
-    proc_stream(libc::STDOUT_FILENO, stdout)?;
-    proc_stream(libc::STDERR_FILENO, stderr)?;
+    let streams = [(StdStream::Stdin, stdin), (StdStream::Stdout, stdout), (StdStream::Stderr, stderr)];
+    let sources: Vec<_> = streams.iter().map(|(stream, stdio)| (*stream, resolve(*stream, stdio))).collect();
+    let mut fell_back = Vec::new();
+    for (stream, source) in sources {
+        match proc_stream(stream, source) {
+            Ok(()) => (),
+            Err(_) if fallback_to_null && dup2(devnull_fd, stream.fd()).is_ok() => fell_back.push(stream),
+            Err(e) => return Err(e),
+        }
+    }
+    for stream in fell_back {
+        let warning = format!("daemonize-me: failed to redirect {}, falling back to /dev/null\n", stream);
+        write_stderr(warning.as_bytes());
+    }
+
+    // the standard streams hold their own copy now
+    let _ = close(devnull_fd);
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Writes `bytes` to fd 2 directly, bypassing the std stderr handle and its lock
+fn write_stderr(mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        match nix::unistd::write(libc::STDERR_FILENO, bytes) {
+            Ok(0) => return,
+            Ok(n) => bytes = &bytes[n..],
+            Err(nix::errno::Errno::EINTR) => (),
+            Err(_) => return,
+        }
+    }
+}
+
+/// Replaces the panic hook with one writing the panic, the thread name and a backtrace to fd 2
+pub(crate) fn install_stderr_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let thread = std::thread::current();
+        let report = format!(
+            "thread '{}' {}\nstack backtrace:\n{}\n",
+            thread.name().unwrap_or("<unnamed>"),
+            info,
+            std::backtrace::Backtrace::force_capture()
+        );
+        write_stderr(report.as_bytes());
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult, read};
+
+    use super::*;
+
+    /// Runs `test` in a forked child so the test process keeps its own standard streams,
+    /// the child exit code is returned
+    fn in_child(test: fn() -> bool) -> i32 {
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => match waitpid(child, None).unwrap() {
+                WaitStatus::Exited(_, code) => code,
+                _ => -1,
+            },
+            ForkResult::Child => unsafe { libc::_exit(if test() { 0 } else { 1 }) },
+        }
+    }
+
+    #[test]
+    /// Asserts that reading from a read only null stdin returns EOF immediately
+    fn test_stdin_null_readonly() {
+        let code = in_child(|| {
+            if redirect_stdio(&Stdio::devnull_readonly(), &Stdio::devnull(), &Stdio::devnull(), false, false).is_err() {
+                return false;
+            }
+            let mut buf = [0u8; 8];
+            matches!(read(libc::STDIN_FILENO, &mut buf), Ok(0))
+        });
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    /// Asserts that a failed redirection names the stream that failed
+    fn test_redirect_error_names_stream() {
+        use std::os::unix::io::FromRawFd;
+
+        let code = in_child(|| {
+            // an fd that is not open can't be duplicated
+            let bad = Stdio::from(unsafe { File::from_raw_fd(4095) });
+            let ok = match redirect_stdio(&Stdio::devnull(), &Stdio::devnull(), &bad, false, false) {
+                Err(e @ DaemonError::RedirectStream { stream: StdStream::Stderr, .. }) => {
+                    e.to_string() == "Failed to redirect stderr"
+                }
+                _ => false,
+            };
+            // closing an fd that was never open aborts in debug builds
+            std::mem::forget(bad);
+            ok
+        });
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    /// Asserts that the real null device passes the check and a regular file doesn't
+    fn test_check_devnull() {
+        let devnull = File::open("/dev/null").unwrap();
+        assert!(check_devnull(devnull.as_raw_fd()).is_ok());
+        let path = std::env::temp_dir().join(format!("daemonize-me-fake-null-{}", std::process::id()));
+        let fake = File::create(&path).unwrap();
+        assert!(matches!(check_devnull(fake.as_raw_fd()), Err(DaemonError::DevNullInvalid)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn ring_dump_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("daemonize-me-ring-{}", nix::unistd::getppid()))
+    }
+
+    #[test]
+    /// Asserts that a ring buffer keeps the newest output of both streams and dumps it on request
+    fn test_ring_buffer_dump() {
+        let expected = std::env::temp_dir().join(format!("daemonize-me-ring-{}", std::process::id()));
+        let _ = std::fs::remove_file(&expected);
+        let code = in_child(|| {
+            let ring = Stdio::ring_buffer(8, ring_dump_path());
+            if redirect_stdio(&Stdio::devnull(), &ring, &ring.clone(), false, false).is_err() {
+                return false;
+            }
+            let _ = nix::unistd::write(libc::STDOUT_FILENO, b"hello");
+            let _ = nix::unistd::write(libc::STDERR_FILENO, b" world");
+            crate::dump_ring_buffers().is_ok()
+        });
+        assert_eq!(code, 0);
+        assert_eq!(std::fs::read_to_string(&expected).unwrap(), "lo world");
+        std::fs::remove_file(&expected).unwrap();
+    }
+
+    fn flushed_dump_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("daemonize-me-flushed-{}", nix::unistd::getppid()))
+    }
+
+    #[test]
+    /// Asserts that flushing drains buffered output into the ring buffer and stops its pump thread,
+    /// the stream keeps accepting writes afterwards
+    fn test_flush_stdio() {
+        let expected = std::env::temp_dir().join(format!("daemonize-me-flushed-{}", std::process::id()));
+        let _ = std::fs::remove_file(&expected);
+        let code = in_child(|| {
+            let ring = Stdio::ring_buffer(64, flushed_dump_path());
+            if redirect_stdio(&Stdio::devnull(), &ring, &Stdio::devnull(), false, false).is_err() {
+                return false;
+            }
+            // stdout of libc is fully buffered on a pipe, this stays there until flushed
+            unsafe { libc::printf(b"shutting down\0".as_ptr() as *const libc::c_char) };
+            if crate::flush_stdio(std::time::Duration::from_secs(5)).is_err() {
+                return false;
+            }
+            let still_writable = nix::unistd::write(libc::STDOUT_FILENO, b"after").is_ok();
+            still_writable && crate::dump_ring_buffers().is_ok()
+        });
+        assert_eq!(code, 0);
+        assert_eq!(std::fs::read_to_string(&expected).unwrap(), "shutting down");
+        std::fs::remove_file(&expected).unwrap();
+    }
+
+    #[test]
+    /// Asserts that a stream closed before the redirection is filled with /dev/null first
+    /// and the file opened afterwards doesn't end up on it
+    fn test_redirect_with_closed_stdout() {
+        let path = std::env::temp_dir().join(format!("daemonize-me-closed-stdout-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let code = in_child(|| {
+            let path = std::env::temp_dir().join(format!("daemonize-me-closed-stdout-{}", nix::unistd::getppid()));
+            let _ = close(libc::STDOUT_FILENO);
+            let stderr = match Stdio::from_path(&path) {
+                Ok(stderr) => stderr,
+                Err(_) => return false,
+            };
+            if redirect_stdio(&Stdio::devnull(), &Stdio::devnull(), &stderr, false, false).is_err() {
+                return false;
+            }
+            check_devnull(libc::STDOUT_FILENO).is_ok() && nix::unistd::write(libc::STDERR_FILENO, b"stderr").is_ok()
+        });
+        assert_eq!(code, 0);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "stderr");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    /// Asserts that a closed stream fails writes with EBADF
+    fn test_close_stream() {
+        let code = in_child(|| {
+            if redirect_stdio(&Stdio::devnull(), &Stdio::close(), &Stdio::devnull(), false, false).is_err() {
+                return false;
+            }
+            matches!(nix::unistd::write(libc::STDOUT_FILENO, b"lost"), Err(nix::errno::Errno::EBADF))
+        });
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    /// Asserts that two writers appending to the same file don't overwrite each other
+    fn test_append_shared_file() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("daemonize-me-append-{}", std::process::id()));
+        let first = Stdio::from_path(&path).unwrap();
+        let second = Stdio::append(&path).unwrap();
+        let (mut first, mut second) = match (first.inner, second.inner) {
+            (StdioImp::RedirectToFile(first, _), StdioImp::RedirectToFile(second, _)) => (first, second),
+            _ => panic!("a path should redirect to a file"),
+        };
+        for _ in 0..100 {
+            Arc::get_mut(&mut first).unwrap().write_all(b"first\n").unwrap();
+            Arc::get_mut(&mut second).unwrap().write_all(b"second\n").unwrap();
+        }
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.matches("first\n").count(), 100);
+        assert_eq!(contents.matches("second\n").count(), 100);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    /// Asserts that the fd named by an environment variable is validated and redirected to
+    fn test_from_env_fd() {
+        let code = in_child(|| {
+            let (read_end, write_end) = match nix::unistd::pipe() {
+                Ok(pipe) => pipe,
+                Err(_) => return false,
+            };
+            std::env::set_var("DAEMONIZE_ME_TEST_LOG_FD", write_end.to_string());
+            std::env::set_var("DAEMONIZE_ME_TEST_BAD_FD", "stdout");
+            std::env::set_var("DAEMONIZE_ME_TEST_CLOSED_FD", "4000");
+            let rejected = matches!(Stdio::from_env_fd("DAEMONIZE_ME_TEST_BAD_FD"), Err(DaemonError::EnvFdParse { .. }))
+                && matches!(Stdio::from_env_fd("DAEMONIZE_ME_TEST_UNSET_FD"), Err(DaemonError::EnvFdParse { .. }))
+                && matches!(
+                    Stdio::from_env_fd("DAEMONIZE_ME_TEST_CLOSED_FD"),
+                    Err(DaemonError::EnvFdClosed { fd: 4000, .. })
+                );
+            let log = match Stdio::from_env_fd("DAEMONIZE_ME_TEST_LOG_FD") {
+                Ok(log) => log,
+                Err(_) => return false,
+            };
+            let _ = close(write_end);
+            if redirect_stdio(&Stdio::devnull(), &log, &Stdio::devnull(), false, false).is_err() {
+                return false;
+            }
+            drop(log);
+            let _ = nix::unistd::write(libc::STDOUT_FILENO, b"handed down");
+            let mut buf = [0u8; 32];
+            let read_back = read(read_end, &mut buf).unwrap_or(0);
+            rejected && &buf[..read_back] == b"handed down"
+        });
+        assert_eq!(code, 0);
+    }
+
+    #[test]
+    /// Asserts that stdout and stderr both land in the shared pipe
+    fn test_shared_pipe() {
+        let code = in_child(|| {
+            let (stdin, stdout, stderr, read_end) = match Stdio::shared_pipe() {
+                Ok(pipe) => pipe,
+                Err(_) => return false,
+            };
+            if redirect_stdio(&stdin, &stdout, &stderr, false, false).is_err() {
+                return false;
+            }
+            let _ = nix::unistd::write(libc::STDOUT_FILENO, b"out ");
+            let _ = nix::unistd::write(libc::STDERR_FILENO, b"err");
+            let mut buf = [0u8; 16];
+            let read_back = read(read_end.as_raw_fd(), &mut buf).unwrap_or(0);
+            &buf[..read_back] == b"out err" && read(libc::STDIN_FILENO, &mut buf) == Err(nix::errno::Errno::EBADF)
+        });
+        assert_eq!(code, 0);
+    }
+
+    fn rotated_log_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("daemonize-me-rotated-{}", nix::unistd::getppid()))
+    }
+
+    #[test]
+    /// Asserts that reopening after a rotation sends new output to a fresh file at the same path
+    fn test_reopen_logs() {
+        let path = std::env::temp_dir().join(format!("daemonize-me-rotated-{}", std::process::id()));
+        let rotated = path.with_extension("1");
+        let code = in_child(|| {
+            let log = match Stdio::from_path(rotated_log_path()) {
+                Ok(log) => log,
+                Err(_) => return false,
+            };
+            if redirect_stdio(&Stdio::devnull(), &log, &log.clone(), false, false).is_err() {
+                return false;
+            }
+            let handle = LogHandle::new(&log, &log);
+            let _ = nix::unistd::write(libc::STDOUT_FILENO, b"before ");
+            if std::fs::rename(rotated_log_path(), rotated_log_path().with_extension("1")).is_err() {
+                return false;
+            }
+            if handle.reopen().is_err() {
+                return false;
+            }
+            let _ = nix::unistd::write(libc::STDOUT_FILENO, b"after ");
+            let _ = nix::unistd::write(libc::STDERR_FILENO, b"shared");
+            true
+        });
+        assert_eq!(code, 0);
+        assert_eq!(std::fs::read_to_string(&rotated).unwrap(), "before ");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "after shared");
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&rotated).unwrap();
+    }
+
+    #[test]
+    /// Asserts that appending keeps what a previous run wrote and truncating drops it
+    fn test_append_and_truncate() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("daemonize-me-restart-{}", std::process::id()));
+        std::fs::write(&path, "previous run\n").unwrap();
+        let write = |stdio: Stdio| match stdio.inner {
+            StdioImp::RedirectToFile(file, _) => (&*file).write_all(b"this run\n").unwrap(),
+            _ => panic!("a path should redirect to a file"),
+        };
+        write(Stdio::append(&path).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "previous run\nthis run\n");
+        write(Stdio::truncate(&path).unwrap());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "this run\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    fn panic_log_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("daemonize-me-panic-{}", nix::unistd::getppid()))
+    }
+
+    #[test]
+    /// Asserts that the panic hook writes the panic message to the redirected stderr
+    fn test_stderr_panic_hook() {
+        let log = std::env::temp_dir().join(format!("daemonize-me-panic-{}", std::process::id()));
+        let code = in_child(|| {
+            let stderr = match Stdio::from_path(panic_log_path()) {
+                Ok(stderr) => stderr,
+                Err(_) => return false,
+            };
+            if redirect_stdio(&Stdio::devnull(), &Stdio::devnull(), &stderr, false, false).is_err() {
+                return false;
+            }
+            install_stderr_panic_hook();
+            std::panic::catch_unwind(|| panic!("daemon went down")).is_err()
+        });
+        assert_eq!(code, 0);
+        let contents = std::fs::read_to_string(&log).unwrap();
+        assert!(contents.contains("panicked at"));
+        assert!(contents.contains("daemon went down"));
+        assert!(contents.contains("stack backtrace:"));
+        std::fs::remove_file(&log).unwrap();
+    }
+
+    #[test]
+    /// Asserts that a stream that can't be redirected falls back to /dev/null when asked to
+    fn test_fallback_to_null() {
+        use std::os::unix::io::FromRawFd;
+
+        let code = in_child(|| {
+            let bad = Stdio::from(unsafe { File::from_raw_fd(4095) });
+            let redirected = redirect_stdio(&Stdio::devnull(), &bad, &Stdio::devnull(), true, false).is_ok();
+            std::mem::forget(bad);
+            redirected && matches!(nix::unistd::write(libc::STDOUT_FILENO, b"lost"), Ok(4))
+        });
+        assert_eq!(code, 0);
+    }
+
+    fn aliased_log_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("daemonize-me-aliased-{}", nix::unistd::getppid()))
+    }
+
+    #[test]
+    /// Asserts that a target file living on a standard stream fd isn't clobbered by the redirection
+    fn test_redirect_aliased_source() {
+        use std::os::unix::io::FromRawFd;
+
+        let log = std::env::temp_dir().join(format!("daemonize-me-aliased-{}", std::process::id()));
+        let code = in_child(|| {
+            let file = match File::create(aliased_log_path()) {
+                Ok(file) => file,
+                Err(_) => return false,
+            };
+            // the stdout target is fd 0, redirecting stdin first used to replace it with /dev/null
+            if dup2(file.as_raw_fd(), libc::STDIN_FILENO).is_err() {
+                return false;
+            }
+            let stdout = Stdio::from(unsafe { File::from_raw_fd(libc::STDIN_FILENO) });
+            if redirect_stdio(&Stdio::devnull(), &stdout, &Stdio::devnull(), false, false).is_err() {
+                return false;
+            }
+            std::mem::forget(stdout);
+            matches!(nix::unistd::write(libc::STDOUT_FILENO, b"kept"), Ok(4))
+        });
+        assert_eq!(code, 0);
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "kept");
+        std::fs::remove_file(&log).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use nix::errno::Errno;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+use crate::{DaemonError, Result};
+use crate::pid_file::{is_running, read_current_pid};
+
+/// How long the SIGKILL of a stuck instance is given to take effect
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// The instance stopped by [`crate::Daemon::takeover_with`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Takeover {
+    /// The pid the old instance had
+    pub pid: Pid,
+    /// Whether it outlived the grace period and had to be killed with SIGKILL
+    pub escalated: bool,
+}
+
+pub(crate) fn take_over(pid_file: &Path, term_signal: Signal, grace: Duration) -> Result<Option<Takeover>> {
+    if !pid_file.exists() {
+        return Ok(None);
+    }
+    let pid = read_current_pid(pid_file)?;
+    // never signal a process group or every process, whatever ended up in the file
+    if pid.as_raw() <= 0 {
+        return Err(DaemonError::InvalidPid);
+    }
+    if !is_running(pid) {
+        return Ok(None);
+    }
+    match kill(pid, term_signal) {
+        Ok(_) => (),
+        Err(Errno::ESRCH) => return Ok(None),
+        Err(errno) => return Err(DaemonError::SignalOldInstance(errno)),
+    }
+    if wait_for_exit(pid, grace) {
+        return Ok(Some(Takeover { pid, escalated: false }));
+    }
+    match kill(pid, Signal::SIGKILL) {
+        Ok(_) | Err(Errno::ESRCH) => (),
+        Err(errno) => return Err(DaemonError::SignalOldInstance(errno)),
+    }
+    if wait_for_exit(pid, KILL_TIMEOUT) {
+        Ok(Some(Takeover { pid, escalated: true }))
+    } else {
+        Err(DaemonError::OldInstanceStuck)
+    }
+}
+
+/// Polls until `pid` is gone or `timeout` expires, returns whether it is gone
+fn wait_for_exit(pid: Pid, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if !is_running(pid) {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet};
+    use nix::sys::wait::waitpid;
+    use nix::unistd::{fork, getpid, ForkResult};
+
+    use super::*;
+
+    /// Starts a process that isn't a child of the test and writes its pid to `pid_file`,
+    /// with `ignore_term` it ignores SIGTERM like a stuck instance
+    fn spawn_old_instance(pid_file: &Path, ignore_term: bool) -> Pid {
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Parent { child } => {
+                waitpid(child, None).unwrap();
+                for _ in 0..100 {
+                    if let Ok(pid) = read_current_pid(pid_file) {
+                        return pid;
+                    }
+                    sleep(POLL_INTERVAL);
+                }
+                panic!("the old instance never wrote its pid file");
+            }
+            ForkResult::Child => {
+                if let Ok(ForkResult::Child) = unsafe { fork() } {
+                    if ignore_term {
+                        let ignore = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+                        let _ = unsafe { sigaction(Signal::SIGTERM, &ignore) };
+                    }
+                    let _ = std::fs::write(pid_file, getpid().to_string());
+                    sleep(Duration::from_secs(30));
+                }
+                unsafe { libc::_exit(0) }
+            }
+        }
+    }
+
+    #[test]
+    /// Asserts that an old instance is stopped by the term signal and a stuck one is killed after the grace period
+    fn test_take_over() {
+        let pid_file = std::env::temp_dir().join(format!("daemonize-me-takeover-{}", std::process::id()));
+
+        let old = spawn_old_instance(&pid_file, false);
+        let taken = take_over(&pid_file, Signal::SIGTERM, Duration::from_secs(5)).unwrap();
+        assert_eq!(taken, Some(Takeover { pid: old, escalated: false }));
+        std::fs::remove_file(&pid_file).unwrap();
+
+        let stuck = spawn_old_instance(&pid_file, true);
+        let started = Instant::now();
+        let taken = take_over(&pid_file, Signal::SIGTERM, Duration::from_millis(200)).unwrap();
+        assert_eq!(taken, Some(Takeover { pid: stuck, escalated: true }));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(!is_running(stuck));
+
+        // the pid file now names a dead process, there is nothing to take over
+        assert_eq!(take_over(&pid_file, Signal::SIGTERM, Duration::from_millis(200)).unwrap(), None);
+        // a pid addressing a process group or every process is refused before anything is signalled
+        for contents in ["0", "-1"] {
+            std::fs::write(&pid_file, contents).unwrap();
+            let taken = take_over(&pid_file, Signal::SIGTERM, Duration::from_millis(200));
+            assert!(matches!(taken, Err(DaemonError::InvalidPid)));
+        }
+        std::fs::remove_file(&pid_file).unwrap();
+        assert_eq!(take_over(&pid_file, Signal::SIGTERM, Duration::from_millis(200)).unwrap(), None);
+    }
+}
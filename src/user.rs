@@ -1,24 +1,90 @@
 pub use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
 
 use crate::{DaemonError, Result};
 use crate::ffi::PasswdRecord;
+use crate::resolver::{RecordResolver, SystemResolver};
 
 /// Expects: either the username or the uid
-/// if the name is provided it will be resolved to an id
+/// if the name is provided it will be resolved to an id, a name made of digits only that doesn't exist is taken as a uid.
+/// A user that doesn't exist fails with `UserNotFound` while a lookup that failed, eg. because an NSS
+/// directory server is unreachable, fails with `PasswdLookupFailed` carrying the errno so it can be retried.
+/// The rest of the passwd record is looked up on demand with [`User::details`]
 #[derive(Debug, Ord, PartialOrd, PartialEq, Eq, Clone)]
 pub struct User {
     pub id: u32,
     pub name: String,
 }
 
+/// The fields of the passwd record `User` doesn't hold, see [`User::details`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswdDetails {
+    /// The home directory, `None` when the field is empty
+    pub home_dir: Option<PathBuf>,
+    /// The login shell, `None` when the field is empty
+    pub shell: Option<PathBuf>,
+    /// The gid of the primary group
+    pub primary_gid: u32,
+}
+
+impl PasswdDetails {
+    fn from_record(record: PasswdRecord) -> PasswdDetails {
+        let path = |field: String| if field.is_empty() { None } else { Some(PathBuf::from(field)) };
+        PasswdDetails { home_dir: path(record.pw_dir), shell: path(record.pw_shell), primary_gid: record.pw_gid }
+    }
+}
+
+impl User {
+    /// Looks up the rest of the passwd record of the user by uid, eg. for
+    /// `.work_dir(user.details()?.home_dir.unwrap())`. It fails like [`User::try_from`] with a uid would
+    pub fn details(&self) -> Result<PasswdDetails> {
+        self.details_with(&SystemResolver)
+    }
+
+    pub(crate) fn details_with<R: RecordResolver>(&self, resolver: &R) -> Result<PasswdDetails> {
+        resolver.passwd_by_id(self.id).map(PasswdDetails::from_record)
+    }
+
+    fn from_record(record: &PasswdRecord) -> User {
+        User { id: record.pw_uid, name: record.pw_name.to_string_lossy().into_owned() }
+    }
+
+    pub(crate) fn resolve_name<N: AsRef<OsStr>, R: RecordResolver>(uname: N, resolver: &R) -> Result<User> {
+        let uname = uname.as_ref();
+        match resolver.passwd_by_name(uname) {
+            Ok(record) => Ok(User::from_record(&record)),
+            Err(DaemonError::UserNotFound) => match uname.to_str().and_then(|uname| uname.parse::<u32>().ok()) {
+                Some(uid) => User::resolve_id(uid, resolver),
+                None => Err(DaemonError::UserNotFound),
+            },
+            Err(e @ DaemonError::PasswdLookupFailed(_)) => Err(e),
+            Err(e) => Err(DaemonError::InvalidUser { names: vec![uname.to_string_lossy().into_owned()], source: Some(Box::new(e)) }),
+        }
+    }
+
+    /// Resolves the first of `names` that exists, names that don't exist are skipped but a failing
+    /// lookup is returned as is since the next name may not be the one intended
+    pub(crate) fn resolve_first_available<R: RecordResolver>(names: &[&str], resolver: &R) -> Result<User> {
+        for name in names {
+            match User::resolve_name(*name, resolver) {
+                Err(DaemonError::UserNotFound) | Err(DaemonError::InvalidUser { .. }) => (),
+                other => return other,
+            }
+        }
+        Err(DaemonError::InvalidUser { names: names.iter().map(|name| (*name).to_owned()).collect(), source: None })
+    }
+
+    pub(crate) fn resolve_id<R: RecordResolver>(uid: u32, resolver: &R) -> Result<User> {
+        resolver.passwd_by_id(uid).map(|record| User::from_record(&record))
+    }
+}
+
 impl<'uname> TryFrom<&'uname str> for User {
     type Error = DaemonError;
 
     fn try_from(uname: &'uname str) -> Result<User> {
-        match PasswdRecord::lookup_record_by_name(uname) {
-            Ok(record) => Ok(User { id: record.pw_uid, name: record.pw_name }),
-            Err(_) => Err(DaemonError::InvalidUser),
-        }
+        User::resolve_name(uname, &SystemResolver)
     }
 }
 
@@ -26,10 +92,25 @@ impl TryFrom<&String> for User {
     type Error = DaemonError;
 
     fn try_from(uname: &String) -> Result<User> {
-        match PasswdRecord::lookup_record_by_name(uname.as_str()) {
-            Ok(record) => Ok(User { id: record.pw_uid, name: record.pw_name }),
-            Err(_) => Err(DaemonError::InvalidUser),
-        }
+        User::try_from(uname.as_str())
+    }
+}
+
+/// The raw bytes of the name are looked up, so names that aren't valid UTF-8 resolve as well,
+/// the `name` of the user found then holds them with the invalid sequences replaced
+impl<'uname> TryFrom<&'uname OsStr> for User {
+    type Error = DaemonError;
+
+    fn try_from(uname: &'uname OsStr) -> Result<User> {
+        User::resolve_name(uname, &SystemResolver)
+    }
+}
+
+impl TryFrom<&OsString> for User {
+    type Error = DaemonError;
+
+    fn try_from(uname: &OsString) -> Result<User> {
+        User::try_from(uname.as_os_str())
     }
 }
 
@@ -37,10 +118,77 @@ impl TryFrom<u32> for User {
     type Error = DaemonError;
 
     fn try_from(uid: u32) -> Result<User> {
-        let record = PasswdRecord::lookup_record_by_id(uid)?;
-        Ok(User {
-            id: record.pw_uid,
-            name: record.pw_name,
-        })
+        User::resolve_id(uid, &SystemResolver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::resolver::fake::FakeResolver;
+
+    use super::*;
+
+    fn fixtures() -> FakeResolver {
+        FakeResolver::default().with_user("alice", 1000, 1000).with_user("4242", 1001, 1001)
+    }
+
+    #[test]
+    /// Asserts that names and ids resolve to the same user and missing users are told apart
+    fn test_resolve_user() {
+        let alice = User::resolve_name("alice", &fixtures()).unwrap();
+        assert_eq!(alice, User { id: 1000, name: "alice".to_owned() });
+        assert_eq!(User::resolve_id(1000, &fixtures()).unwrap(), alice);
+        assert!(matches!(User::resolve_name("bob", &fixtures()), Err(DaemonError::UserNotFound)));
+        assert!(matches!(User::resolve_id(2000, &fixtures()), Err(DaemonError::UserNotFound)));
+    }
+
+    #[test]
+    /// Asserts that the rest of the passwd record is looked up by uid, for a user built by hand as well
+    fn test_passwd_details() {
+        let alice = User::resolve_name("alice", &fixtures()).unwrap();
+        let details = alice.details_with(&fixtures()).unwrap();
+        assert_eq!(details.primary_gid, 1000);
+        assert_eq!(details.home_dir, Some(PathBuf::from("/nonexistent")));
+        assert_eq!(details.shell, Some(PathBuf::from("/usr/sbin/nologin")));
+        let by_hand = User { id: 1003, name: "carol".to_owned() };
+        assert!(matches!(by_hand.details_with(&fixtures()), Err(DaemonError::UserNotFound)));
+        let resolver = fixtures().with_user("carol", 1003, 1004);
+        assert_eq!(by_hand.details_with(&resolver).unwrap().primary_gid, 1004);
+    }
+
+    #[test]
+    /// Asserts that a numeric name is only taken as a uid when no user has that name
+    fn test_resolve_numeric_user_name() {
+        assert_eq!(User::resolve_name("1000", &fixtures()).unwrap().name, "alice");
+        assert_eq!(User::resolve_name("4242", &fixtures()).unwrap().id, 1001);
+        assert!(matches!(User::resolve_name("3000", &fixtures()), Err(DaemonError::UserNotFound)));
+    }
+
+    #[test]
+    /// Asserts that names given as raw bytes are looked up as they are, valid UTF-8 or not
+    fn test_resolve_os_str_user_name() {
+        use std::os::unix::ffi::OsStrExt;
+
+        assert_eq!(User::resolve_name(OsStr::new("alice"), &fixtures()).unwrap().id, 1000);
+        let not_utf8 = OsStr::from_bytes(b"al\xffce");
+        assert!(matches!(User::resolve_name(not_utf8, &fixtures()), Err(DaemonError::UserNotFound)));
+        let resolver = fixtures().with_user(not_utf8, 1002, 1002);
+        let user = User::resolve_name(not_utf8, &resolver).unwrap();
+        assert_eq!((user.id, user.name.as_str()), (1002, "al\u{fffd}ce"));
+        // the raw name is what the privilege drop hands to initgroups
+        assert_eq!(resolver.passwd_by_id(1002).unwrap().pw_name, not_utf8);
+    }
+
+    #[test]
+    /// Asserts that the first existing user is picked and the attempted names are reported when none exists
+    fn test_resolve_first_available_user() {
+        let user = User::resolve_first_available(&["daemon", "alice", "4242"], &fixtures()).unwrap();
+        assert_eq!(user.name, "alice");
+        match User::resolve_first_available(&["daemon", "nobody"], &fixtures()) {
+            Err(e @ DaemonError::InvalidUser { .. }) => {
+                assert_eq!(e.to_string(), "Invalid or nonexistent user, tried daemon, nobody")
+            }
+            other => panic!("expected InvalidUser, got {:?}", other),
+        }
     }
 }